@@ -0,0 +1,64 @@
+//! Parse a corpus of representative lines and a few very long ones, to notice when the parser
+//! gets slower
+
+use std::{hint::black_box, path::Path};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use mini_tmpfiles::parser::{parse_line, FileSpan, MAX_LINE_LENGTH};
+
+const CORPUS: &[u8] = include_bytes!("corpus.conf");
+
+fn parse_corpus(c: &mut Criterion) {
+    let file = Path::new("corpus.conf");
+    let lines = FileSpan::from_slice(CORPUS, file)
+        .lines()
+        .map(|(_, line)| line)
+        .filter(|line| line.is_data_line())
+        .collect::<Vec<_>>();
+    // A benchmark of errors would measure nothing useful
+    for line in &lines {
+        if let Err(e) = parse_line(line.clone()) {
+            panic!("{e} ({})", line.bytes().escape_ascii());
+        }
+    }
+
+    let mut group = c.benchmark_group("parse_line");
+    group.throughput(Throughput::Bytes(CORPUS.len() as u64));
+    group.bench_function("corpus", |b| {
+        b.iter(|| {
+            for line in &lines {
+                black_box(parse_line(black_box(line.clone())).ok());
+            }
+        })
+    });
+    group.finish();
+}
+
+/// Single lines at the length limit, which would show parsing that isn't linear in the length of
+/// a line
+fn parse_long_lines(c: &mut Criterion) {
+    let line_of = |prefix: &[u8], repeated: &[u8]| {
+        let count = (MAX_LINE_LENGTH - prefix.len()) / repeated.len();
+        [prefix, &repeated.repeat(count)].concat()
+    };
+    let mut group = c.benchmark_group("parse_long_line");
+    group.sample_size(10);
+    for (name, line) in [
+        ("specifiers", line_of(b"d /", b"%%")),
+        ("escapes", line_of(b"w /x - - - - ", b"\\x41")),
+    ] {
+        group.throughput(Throughput::Bytes(line.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                black_box(parse_line(FileSpan::from_slice(
+                    black_box(&line),
+                    Path::new(""),
+                )))
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse_corpus, parse_long_lines);
+criterion_main!(benches);
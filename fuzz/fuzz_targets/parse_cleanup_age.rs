@@ -0,0 +1,10 @@
+#![no_main]
+
+extern crate mini_tmpfiles;
+
+use libfuzzer_sys::fuzz_target;
+use mini_tmpfiles::parser::parse_cleanup_age;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_cleanup_age(data);
+});
@@ -0,0 +1,21 @@
+#![no_main]
+
+extern crate mini_tmpfiles;
+
+use std::path::Path;
+
+use libfuzzer_sys::fuzz_target;
+use mini_tmpfiles::parser::{parse_line, FileSpan};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(line) = parse_line(FileSpan::from_slice(
+        data.split(|&b| b == b'\n').next().unwrap(),
+        Path::new(""),
+    )) else {
+        return;
+    };
+    let emitted = line.to_string();
+    let reparsed = parse_line(FileSpan::from_slice(emitted.as_bytes(), Path::new("")))
+        .expect("emitted line should parse");
+    assert_eq!(reparsed.to_string(), emitted);
+});
@@ -0,0 +1,382 @@
+use std::{
+    collections::BTreeMap, error::Error, fmt, fs::File, io, os::unix::fs::PermissionsExt,
+    path::Path, str::FromStr,
+};
+
+use crate::{config_file::FileOwner, syscalls::Syscalls, users};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum AclTag {
+    UserObj,
+    User(FileOwner),
+    GroupObj,
+    Group(FileOwner),
+    Mask,
+    Other,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AclEntry {
+    /// Entry of the default ACL inherited by new children, which only directories have
+    pub default: bool,
+    pub tag: AclTag,
+    /// Read, write, and execute as the bits 4, 2, and 1
+    pub permissions: u8,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AclParseError {
+    MalformedEntry(Box<[u8]>),
+    UnknownTag(Box<[u8]>),
+    UnexpectedQualifier(Box<[u8]>),
+    InvalidQualifier(Box<[u8]>),
+    InvalidPermissions(Box<[u8]>),
+}
+
+impl fmt::Display for AclParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (message, bytes) = match self {
+            Self::MalformedEntry(entry) => ("malformed ACL entry", entry),
+            Self::UnknownTag(tag) => ("unknown ACL tag", tag),
+            Self::UnexpectedQualifier(entry) => ("ACL entry can't have a qualifier", entry),
+            Self::InvalidQualifier(qualifier) => ("invalid ACL qualifier", qualifier),
+            Self::InvalidPermissions(permissions) => ("invalid ACL permissions", permissions),
+        };
+        write!(f, "{message} \"{}\"", bytes.escape_ascii())
+    }
+}
+
+impl Error for AclParseError {}
+
+/// Parse an ACL argument like `u:alice:rw,d:g::r-x` into its entries
+pub fn parse_acl(input: &[u8]) -> Result<Vec<AclEntry>, AclParseError> {
+    input
+        .split(|&ch| ch == b',')
+        .filter(|entry| !entry.is_empty())
+        .map(parse_entry)
+        .collect()
+}
+
+fn parse_entry(entry: &[u8]) -> Result<AclEntry, AclParseError> {
+    let mut fields = entry.split(|&ch| ch == b':').collect::<Vec<_>>();
+    let default = matches!(fields.first(), Some(&(b"d" | b"default")));
+    if default {
+        fields.remove(0);
+    }
+    let (tag, qualifier, permissions) = match *fields.as_slice() {
+        [tag, qualifier, permissions] => (tag, qualifier, permissions),
+        // Mask and other never have a qualifier, so its field may be left out
+        [tag @ (b"m" | b"mask" | b"o" | b"other"), permissions] => (tag, &b""[..], permissions),
+        _ => Err(AclParseError::MalformedEntry(entry.into()))?,
+    };
+    let qualifier = (!qualifier.is_empty())
+        .then(|| parse_qualifier(qualifier))
+        .transpose()?;
+    let tag = match (tag, qualifier) {
+        (b"u" | b"user", None) => AclTag::UserObj,
+        (b"u" | b"user", Some(user)) => AclTag::User(user),
+        (b"g" | b"group", None) => AclTag::GroupObj,
+        (b"g" | b"group", Some(group)) => AclTag::Group(group),
+        (b"m" | b"mask", None) => AclTag::Mask,
+        (b"o" | b"other", None) => AclTag::Other,
+        (b"m" | b"mask" | b"o" | b"other", Some(_)) => {
+            Err(AclParseError::UnexpectedQualifier(entry.into()))?
+        }
+        _ => Err(AclParseError::UnknownTag(tag.into()))?,
+    };
+    Ok(AclEntry {
+        default,
+        tag,
+        permissions: parse_permissions(permissions)?,
+    })
+}
+
+fn parse_qualifier(input: &[u8]) -> Result<FileOwner, AclParseError> {
+    let Ok(s) = std::str::from_utf8(input) else {
+        Err(AclParseError::InvalidQualifier(input.into()))?
+    };
+    Ok(match u32::from_str(s) {
+        Ok(id) => FileOwner::Id(id),
+        Err(_) => FileOwner::Name(s.to_owned()),
+    })
+}
+
+fn parse_permissions(input: &[u8]) -> Result<u8, AclParseError> {
+    if let &[digit @ b'0'..=b'7'] = input {
+        return Ok(digit - b'0');
+    }
+    let mut permissions = 0;
+    for &ch in input {
+        let bit = match ch {
+            b'r' => 4,
+            b'w' => 2,
+            b'x' => 1,
+            b'-' => continue,
+            _ => Err(AclParseError::InvalidPermissions(input.into()))?,
+        };
+        if permissions & bit != 0 {
+            Err(AclParseError::InvalidPermissions(input.into()))?
+        }
+        permissions |= bit;
+    }
+    if input.is_empty() {
+        Err(AclParseError::InvalidPermissions(input.into()))?
+    }
+    Ok(permissions)
+}
+
+// Tags from linux/posix_acl.h, in the order the kernel expects them
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+const POSIX_ACL_XATTR_VERSION: u32 = 2;
+
+/// Entries with names resolved, keyed by tag and qualifier so they stay sorted and a later entry
+/// for the same tag and qualifier replaces an earlier one
+type ResolvedAcl = BTreeMap<(u16, u32), u16>;
+
+/// Add `entries` to `acl`, replacing what it has for the same tag and qualifier
+fn resolve<'a>(
+    mut acl: ResolvedAcl,
+    entries: impl IntoIterator<Item = &'a AclEntry>,
+    mode: u32,
+) -> io::Result<ResolvedAcl> {
+    for entry in entries {
+        let key = match &entry.tag {
+            AclTag::UserObj => (ACL_USER_OBJ, ACL_UNDEFINED_ID),
+            AclTag::User(user) => (ACL_USER, users::resolve_user(user)?),
+            AclTag::GroupObj => (ACL_GROUP_OBJ, ACL_UNDEFINED_ID),
+            AclTag::Group(group) => (ACL_GROUP, users::resolve_group(group)?),
+            AclTag::Mask => (ACL_MASK, ACL_UNDEFINED_ID),
+            AclTag::Other => (ACL_OTHER, ACL_UNDEFINED_ID),
+        };
+        acl.insert(key, entry.permissions.into());
+    }
+
+    // Fill in the required entries from the file mode, like setfacl does
+    let bits = |shift: u32| ((mode >> shift) & 0o7) as u16;
+    acl.entry((ACL_USER_OBJ, ACL_UNDEFINED_ID))
+        .or_insert(bits(6));
+    acl.entry((ACL_GROUP_OBJ, ACL_UNDEFINED_ID))
+        .or_insert(bits(3));
+    acl.entry((ACL_OTHER, ACL_UNDEFINED_ID)).or_insert(bits(0));
+    let named = acl
+        .keys()
+        .any(|&(tag, _)| tag == ACL_USER || tag == ACL_GROUP);
+    if named && !acl.contains_key(&(ACL_MASK, ACL_UNDEFINED_ID)) {
+        let mask = acl
+            .iter()
+            .filter(|((tag, _), _)| matches!(*tag, ACL_USER | ACL_GROUP_OBJ | ACL_GROUP))
+            .fold(0, |mask, (_, permissions)| mask | permissions);
+        acl.insert((ACL_MASK, ACL_UNDEFINED_ID), mask);
+    }
+    Ok(acl)
+}
+
+/// Encode an ACL in the format of the `system.posix_acl_*` extended attributes
+fn encode(acl: &ResolvedAcl) -> Vec<u8> {
+    let mut encoded = POSIX_ACL_XATTR_VERSION.to_le_bytes().to_vec();
+    for (&(tag, id), &permissions) in acl {
+        encoded.extend_from_slice(&tag.to_le_bytes());
+        encoded.extend_from_slice(&permissions.to_le_bytes());
+        encoded.extend_from_slice(&id.to_le_bytes());
+    }
+    encoded
+}
+
+/// Decode an ACL from the format of the `system.posix_acl_*` extended attributes
+fn decode(encoded: &[u8]) -> io::Result<ResolvedAcl> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed ACL");
+    let (version, entries) = encoded.split_first_chunk::<4>().ok_or_else(invalid)?;
+    if u32::from_le_bytes(*version) != POSIX_ACL_XATTR_VERSION || entries.len() % 8 != 0 {
+        return Err(invalid());
+    }
+    Ok(entries
+        .chunks_exact(8)
+        .map(|entry| {
+            let tag = u16::from_le_bytes([entry[0], entry[1]]);
+            let permissions = u16::from_le_bytes([entry[2], entry[3]]);
+            let id = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+            ((tag, id), permissions)
+        })
+        .collect())
+}
+
+/// Set the ACL of `file`, which is at `path`, to `entries`, or with `merge` add them to the ACL it
+/// has. Access and default entries each go to their own ACL. Default entries are skipped unless
+/// it's a directory, and symlinks are left alone since they can't have ACLs.
+pub fn set_acl(
+    syscalls: &dyn Syscalls,
+    path: &Path,
+    file: &File,
+    entries: &[AclEntry],
+    merge: bool,
+) -> io::Result<()> {
+    let meta = file.metadata()?;
+    if meta.is_symlink() {
+        return Ok(());
+    }
+    let mode = meta.permissions().mode();
+    let (defaults, access): (Vec<_>, Vec<_>) = entries.iter().partition(|entry| entry.default);
+    let existing = |name, entries: &[&AclEntry]| -> io::Result<ResolvedAcl> {
+        if !merge {
+            return Ok(ResolvedAcl::new());
+        }
+        let mut acl = match syscalls.get_xattr(path, file, name)? {
+            Some(encoded) => decode(&encoded)?,
+            None => ResolvedAcl::new(),
+        };
+        // Worked out again for the merged entries unless given, like setfacl -m does
+        if !entries.iter().any(|entry| entry.tag == AclTag::Mask) {
+            acl.remove(&(ACL_MASK, ACL_UNDEFINED_ID));
+        }
+        Ok(acl)
+    };
+    if !access.is_empty() {
+        let name = c"system.posix_acl_access";
+        let acl = resolve(existing(name, &access)?, access, mode)?;
+        syscalls.set_xattr(path, file, name, &encode(&acl))?;
+    }
+    if !defaults.is_empty() && meta.is_dir() {
+        let name = c"system.posix_acl_default";
+        let acl = resolve(existing(name, &defaults)?, defaults, mode)?;
+        syscalls.set_xattr(path, file, name, &encode(&acl))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+
+    use tempfile::TempDir;
+
+    use crate::{
+        acl::{
+            decode, parse_acl, set_acl, AclEntry, AclParseError, AclTag, ACL_GROUP_OBJ, ACL_MASK,
+            ACL_OTHER, ACL_UNDEFINED_ID, ACL_USER, ACL_USER_OBJ,
+        },
+        config_file::FileOwner,
+        syscalls::Libc,
+        xattr::get_xattr,
+    };
+
+    #[test]
+    fn test_parse_acl() {
+        assert_eq!(
+            parse_acl(b"u:alice:rw-,d:group::r-x,default:o::0,m:rwx"),
+            Ok(vec![
+                AclEntry {
+                    default: false,
+                    tag: AclTag::User(FileOwner::Name("alice".into())),
+                    permissions: 6,
+                },
+                AclEntry {
+                    default: true,
+                    tag: AclTag::GroupObj,
+                    permissions: 5,
+                },
+                AclEntry {
+                    default: true,
+                    tag: AclTag::Other,
+                    permissions: 0,
+                },
+                AclEntry {
+                    default: false,
+                    tag: AclTag::Mask,
+                    permissions: 7,
+                },
+            ])
+        );
+        assert_eq!(
+            parse_acl(b"g:100:x"),
+            Ok(vec![AclEntry {
+                default: false,
+                tag: AclTag::Group(FileOwner::Id(100)),
+                permissions: 1,
+            }])
+        );
+    }
+
+    #[test]
+    fn test_parse_acl_errors() {
+        assert_eq!(
+            parse_acl(b"u:alice"),
+            Err(AclParseError::MalformedEntry(b"u:alice".as_slice().into()))
+        );
+        assert_eq!(
+            parse_acl(b"q::r"),
+            Err(AclParseError::UnknownTag(b"q".as_slice().into()))
+        );
+        assert_eq!(
+            parse_acl(b"m:alice:r"),
+            Err(AclParseError::UnexpectedQualifier(
+                b"m:alice:r".as_slice().into()
+            ))
+        );
+        assert_eq!(
+            parse_acl(b"u::rr"),
+            Err(AclParseError::InvalidPermissions(b"rr".as_slice().into()))
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_merge_acl() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("file");
+        fs::write(&path, b"").unwrap();
+        let file = File::open(&path).unwrap();
+        let access = || {
+            let encoded = get_xattr(&path, c"system.posix_acl_access").unwrap();
+            decode(&encoded.unwrap())
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>()
+        };
+        let undefined = ACL_UNDEFINED_ID;
+
+        set_acl(
+            &Libc,
+            &path,
+            &file,
+            &parse_acl(b"u::rw,u:1:rw,g::r,o::-").unwrap(),
+            false,
+        )
+        .unwrap();
+        // Merging keeps user 1, lets the later entry for user 2 win, and works out the mask again
+        set_acl(
+            &Libc,
+            &path,
+            &file,
+            &parse_acl(b"u:2:rwx,u:2:r").unwrap(),
+            true,
+        )
+        .unwrap();
+        assert_eq!(
+            access(),
+            [
+                ((ACL_USER_OBJ, undefined), 6),
+                ((ACL_USER, 1), 6),
+                ((ACL_USER, 2), 4),
+                ((ACL_GROUP_OBJ, undefined), 4),
+                ((ACL_MASK, undefined), 6),
+                ((ACL_OTHER, undefined), 0),
+            ]
+        );
+
+        // Without merging the ACL is replaced, filling in the rest from the mode
+        set_acl(&Libc, &path, &file, &parse_acl(b"u:2:r").unwrap(), false).unwrap();
+        assert_eq!(
+            access()
+                .into_iter()
+                .filter(|((tag, _), _)| *tag == ACL_USER)
+                .collect::<Vec<_>>(),
+            [((ACL_USER, 2), 4)]
+        );
+    }
+}
@@ -0,0 +1,3849 @@
+use std::{
+    cell::RefCell,
+    ffi::{OsStr, OsString},
+    fmt,
+    fs::{self, DirBuilder, File, FileTimes, OpenOptions},
+    io::{self, Write},
+    ops::Range,
+    os::{
+        fd::{AsFd, BorrowedFd},
+        unix::{
+            ffi::{OsStrExt, OsStringExt},
+            fs::{DirBuilderExt, FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+        },
+    },
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant, SystemTime},
+};
+
+use eyre::eyre;
+
+use crate::{
+    acl, attr,
+    config_file::{
+        CleanupAge, DeviceNumbers, FileOwner, Line, LineAction, LineType, Mode, ModeBehavior,
+    },
+    diagnostic::{Diagnostic, DiagnosticFormat, Severity},
+    parser::{parse_device_numbers, parse_specifiers, parse_user, ParseOptions},
+    quota,
+    remove::{self, remove_guarded, remove_tree_guarded, walk_entries, RemoveOptions},
+    selinux::Labels,
+    specifier::{ExpandError, SpecifierContext, SpecifierSource},
+    statistics::Statistics,
+    syscalls::{self, Libc, Syscalls},
+    users::{self, UnknownOwner},
+    xattr,
+};
+
+/// Settings shared by every pass over the configuration.
+///
+/// With a root other than `/`, the path of every line is moved below it by `line_path`. Symlink
+/// targets never are, since they're written into the link as is and resolved once the tree is in
+/// use. Copy sources are moved below the root too, unless `copy_from_host` is set, see
+/// `copy_source`.
+pub struct Context {
+    /// Directory that paths in the configuration are relative to
+    pub root: PathBuf,
+    /// Read `C` sources from the running system rather than from below the root
+    pub copy_from_host: bool,
+    pub specifiers: SpecifierContext,
+    /// Per-line timings, only collected when asked for
+    pub statistics: Option<RefCell<Statistics>>,
+    /// Asked before each removal while cleaning or removing, with the path and why it qualifies
+    pub confirm: Option<Confirm>,
+    /// Whether this is the run at boot, which is the only time `w+` appends and lines marked with
+    /// `!` are applied
+    pub boot: bool,
+    /// Leave out warnings, errors are still reported
+    pub quiet: bool,
+    /// How many levels below a line's direct entries recursive actions and cleaning go. Cleaning
+    /// leaves anything deeper alone, recursive actions give up.
+    pub max_depth: usize,
+    /// Report a failing line and move on to the next instead of stopping
+    pub keep_going: bool,
+    /// Skip lines with specifiers that need a field their source doesn't set, like `%B` without
+    /// `BUILD_ID` in os-release
+    pub graceful: bool,
+    /// Let `z` and `Z` change the target of a symlink at the path of the line, rather than leave
+    /// it alone. Symlinks inside a `Z` tree are never followed.
+    pub dereference: bool,
+    /// Paths, as written in the configuration, that cleaning and removing may delete things
+    /// below. Anything outside of them is refused, to catch a specifier that expands to somewhere
+    /// unexpected. Empty allows every path.
+    pub safe_roots: Vec<PathBuf>,
+    /// Apply lines to the root directory itself, rather than refusing them. Lines that only
+    /// exclude paths from cleaning are always allowed.
+    pub allow_root_path: bool,
+    /// Makes the calls that change the filesystem
+    pub syscalls: Box<dyn Syscalls>,
+    /// Access and modification time for files and nodes we create or write, instead of the
+    /// current time, so builds are reproducible
+    pub timestamp: Option<SystemTime>,
+    /// Owner and group given to what we create when the line leaves them as `-`. Our own effective
+    /// ids, which are root's when applying for the system and the user's with `--user`. Set
+    /// explicitly, since a new node may otherwise take its group from its directory, always on
+    /// BSD and below a setgid directory on Linux.
+    pub default_owner: (u32, u32),
+    /// SELinux contexts to give what we create or adjust, only loaded with `--relabel`
+    pub labels: Option<Labels>,
+    /// How warnings and lines failing with `keep_going` are printed
+    pub diagnostics: DiagnosticFormat,
+    /// Where the service credentials that `^` lines name are, `$CREDENTIALS_DIRECTORY` when
+    /// running as a service. Without one, those lines are skipped.
+    pub credentials: Option<PathBuf>,
+}
+
+/// Deep enough for any real tree, shallow enough that a hostile one can't exhaust the stack
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
+/// Decides whether a path may be removed, given a description of why it qualifies
+pub type Confirm = Box<dyn Fn(&Path, &str) -> bool>;
+
+impl Context {
+    pub fn new(root: PathBuf, source: SpecifierSource) -> Self {
+        Self {
+            specifiers: SpecifierContext::new(&root, source),
+            root,
+            copy_from_host: false,
+            statistics: None,
+            confirm: None,
+            boot: false,
+            quiet: false,
+            max_depth: DEFAULT_MAX_DEPTH,
+            keep_going: false,
+            graceful: false,
+            dereference: false,
+            safe_roots: Vec::new(),
+            allow_root_path: false,
+            syscalls: Box::new(Libc),
+            timestamp: None,
+            default_owner: unsafe { (libc::geteuid(), libc::getegid()) },
+            labels: None,
+            diagnostics: DiagnosticFormat::Human,
+            credentials: None,
+        }
+    }
+
+    /// Whether removing `path` was confirmed, which it always is unless we're asking
+    fn confirm_removal(&self, path: &Path, reason: impl FnOnce() -> String) -> bool {
+        self.confirm
+            .as_ref()
+            .is_none_or(|confirm| confirm(path, &reason()))
+    }
+
+    /// Print a warning to stderr, about `line` if given, unless asked to be quiet
+    fn warn(&self, line: Option<&Line>, message: fmt::Arguments) {
+        if !self.quiet {
+            self.diagnostics
+                .emit(&located(Diagnostic::new(Severity::Warning, message), line));
+        }
+    }
+
+    /// Print an error about `line` that's counted rather than stopping the run, unless asked to be
+    /// quiet
+    #[allow(unused)]
+    pub(crate) fn report(&self, line: &Line, message: fmt::Arguments) {
+        if !self.quiet {
+            self.diagnostics.emit(&located(
+                Diagnostic::new(Severity::Error, message),
+                Some(line),
+            ));
+        }
+    }
+
+    /// Run one pass over one line, timing it if statistics are being collected. With `keep_going`
+    /// a failure is reported and returned as the outcome instead of as an error.
+    fn apply_line(
+        &self,
+        pass: &'static str,
+        line: &Line,
+        f: impl FnOnce() -> eyre::Result<()>,
+    ) -> eyre::Result<LineOutcome> {
+        let outcome = |outcome| LineOutcome::new(pass, line, outcome);
+        // Lines marked with ! are only for the run at boot
+        if line.line_type.data.boot && !self.boot {
+            return Ok(outcome(Outcome::Skipped("only applied at boot".to_owned())));
+        }
+        let path = self.line_path(line).ok();
+        let before = path.as_deref().and_then(change_stamp);
+        let f = || {
+            // Before anything changes, so a line with an unknown user or group is skipped whole
+            if let Some(owner @ FileOwner::Name(_)) = &line.owner.data {
+                users::resolve_user(owner)?;
+            }
+            if let Some(group @ FileOwner::Name(_)) = &line.group.data {
+                users::resolve_group(group)?;
+            }
+            // Nearly always a mistake, like a specifier that expanded to nothing, and the most
+            // costly one there is
+            let ignore = matches!(
+                line.line_type.data.action,
+                LineAction::Ignore | LineAction::IgnoreNonRecursive
+            );
+            let is_root = path
+                .as_deref()
+                .is_some_and(|path| lexically_normal(path) == lexically_normal(&self.root));
+            if is_root && !ignore && !self.allow_root_path {
+                Err(eyre!(
+                    "Refusing to apply {line} to the root directory, pass --allow-root-path if \
+                     that's intended"
+                ))?
+            }
+            f()
+        };
+        let result = match &self.statistics {
+            Some(statistics) => {
+                let start = Instant::now();
+                let result = f();
+                statistics.borrow_mut().record(pass, line, start.elapsed());
+                result
+            }
+            None => f(),
+        };
+        let error = match result {
+            Ok(()) => {
+                let after = path.as_deref().and_then(change_stamp);
+                return Ok(outcome(match (before, after) {
+                    (None, Some(_)) => Outcome::Created,
+                    (before, after) if before != after => Outcome::Modified,
+                    _ => Outcome::Unchanged,
+                }));
+            }
+            Err(error) => error,
+        };
+        let reason = format!("{error:#}");
+        match self.skip_gracefully(line, error) {
+            Ok(()) => Ok(outcome(Outcome::Skipped(reason))),
+            Err(e) if self.keep_going => {
+                let message = format!("Failed to {pass} {line}: {e:#}");
+                self.diagnostics.emit(&located(
+                    Diagnostic::new(Severity::Error, message),
+                    Some(line),
+                ));
+                Ok(outcome(Outcome::Failed(e)))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// With `graceful`, warn about and skip a line that failed for a field missing from the
+    /// source of a specifier, or for a user or group that doesn't exist, otherwise pass the error
+    /// on
+    pub fn skip_gracefully(&self, line: &Line, error: eyre::Report) -> eyre::Result<()> {
+        let missing_field = matches!(
+            error.downcast_ref(),
+            Some(ExpandError::SpecifierSourceMissingField { .. })
+        );
+        let unknown_owner = error
+            .downcast_ref::<io::Error>()
+            .and_then(|e| e.get_ref())
+            .is_some_and(|e| e.is::<UnknownOwner>());
+        if self.graceful && (missing_field || unknown_owner) {
+            self.warn(Some(line), format_args!("skipping {line}: {error:#}"));
+            return Ok(());
+        }
+        Err(error)
+    }
+
+    /// The path a line applies to, with specifiers expanded and below the root
+    pub fn line_path(&self, line: &Line) -> eyre::Result<PathBuf> {
+        let expanded = line.path.data.expand(&self.specifiers)?;
+        self.rooted(Path::new(OsStr::from_bytes(&expanded)))
+    }
+
+    /// The path of a line that cleaning or removing deletes things in, checked against
+    /// `safe_roots`. Checking is lexical, after resolving `..`, so a symlink can still lead
+    /// elsewhere.
+    fn removal_path(&self, line: &Line) -> eyre::Result<PathBuf> {
+        let path = self.line_path(line)?;
+        if self.safe_roots.is_empty() {
+            return Ok(path);
+        }
+        let normal = lexically_normal(&path);
+        for root in &self.safe_roots {
+            if normal.starts_with(lexically_normal(&self.rooted(root)?)) {
+                return Ok(path);
+            }
+        }
+        let roots = self
+            .safe_roots
+            .iter()
+            .map(|root| root.display().to_string())
+            .collect::<Vec<_>>();
+        Err(eyre!(
+            "Refusing to delete anything in {}, which is outside of the safe roots {}",
+            normal.display(),
+            roots.join(", ")
+        ))
+    }
+
+    /// Expand specifiers like `%u` in the owner and group of a line, before they're looked up
+    pub fn expand_owners(&self, line: &mut Line) -> eyre::Result<()> {
+        for owner in [&mut line.owner.data, &mut line.group.data]
+            .into_iter()
+            .flatten()
+        {
+            let FileOwner::Specifiers(string) = owner else {
+                continue;
+            };
+            let expanded = string.expand(&self.specifiers)?;
+            *owner =
+                parse_user(expanded.into()).map_err(|e| eyre!("Invalid owner {owner}: {e}"))?;
+        }
+        Ok(())
+    }
+
+    /// The path of a line in `/usr/share/factory`, as written in the configuration. It's what `C`
+    /// copies from and `L` links to without an argument.
+    fn factory_path(&self, line: &Line) -> eyre::Result<PathBuf> {
+        let expanded = line.path.data.expand(&self.specifiers)?;
+        let path = Path::new(OsStr::from_bytes(&expanded));
+        Ok(Path::new("/usr/share/factory").join(path.strip_prefix("/")?))
+    }
+
+    /// A path in the argument of a line, with specifiers expanded like in the path of the line.
+    /// `what` says what the path is for, in errors.
+    fn argument_path(&self, argument: &OsStr, what: &str) -> eyre::Result<PathBuf> {
+        let expanded = parse_specifiers(argument.as_bytes().into(), ParseOptions::default())
+            .map_err(|e| eyre!("Invalid {what}: {e:?}"))?
+            .expand(&self.specifiers)?;
+        Ok(PathBuf::from(OsStr::from_bytes(&expanded)))
+    }
+
+    /// Where a `C` line copies from, by default its `factory_path`
+    fn copy_source(&self, line: &Line) -> eyre::Result<PathBuf> {
+        let source = match &line.argument.data {
+            Some(source) => self.argument_path(source, "copy source")?,
+            None => self.factory_path(line)?,
+        };
+        if self.copy_from_host {
+            Ok(source)
+        } else {
+            self.rooted(&source)
+        }
+    }
+
+    /// Give `path` the default SELinux context for where it is once the tree is in use, when
+    /// relabeling. With `graceful` a failure is only a warning.
+    fn relabel(&self, path: &Path) -> eyre::Result<()> {
+        let Some(labels) = &self.labels else {
+            return Ok(());
+        };
+        let mode = fs::symlink_metadata(path)?.mode();
+        let key = Path::new("/").join(path.strip_prefix(&self.root).unwrap_or(path));
+        match labels.relabel(path, &key, mode) {
+            Err(e) if self.graceful => {
+                self.warn(
+                    None,
+                    format_args!("couldn't relabel {}: {e}", path.display()),
+                );
+                Ok(())
+            }
+            result => Ok(result?),
+        }
+    }
+
+    /// Options for removing a tree, which goes as deep as `max_depth`
+    fn remove_options(&self) -> RemoveOptions<'static> {
+        RemoveOptions {
+            max_depth: self.max_depth,
+            ..Default::default()
+        }
+    }
+
+    /// An absolute path from the configuration, moved below the root
+    fn rooted(&self, path: &Path) -> eyre::Result<PathBuf> {
+        let relative = path
+            .strip_prefix("/")
+            .map_err(|_| eyre!("{} is not an absolute path", path.display()))?;
+        Ok(self.root.join(relative))
+    }
+}
+
+/// `diagnostic` about where `line` starts in its configuration file, if there is a line
+fn located(diagnostic: Diagnostic, line: Option<&Line>) -> Diagnostic {
+    match line {
+        Some(line) => diagnostic.at_offset(line.path.file(), line.line_type.characters().start),
+        None => diagnostic,
+    }
+}
+
+/// `path` with `.` left out and `..` taking away the component before it, without looking at the
+/// filesystem
+fn lexically_normal(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normal.pop();
+            }
+            component => normal.push(component),
+        }
+    }
+    normal
+}
+
+/// What applying a line in one pass did, as far as the object at its path shows. Lines that work
+/// on a whole tree only report on the top of it, so a directory counts as modified when entries
+/// are added to or removed from it, but not when something further down changes.
+#[derive(Debug)]
+pub enum Outcome {
+    /// Nothing was at the path before, and something is now
+    Created,
+    /// What's at the path was changed, replaced, or removed
+    Modified,
+    Unchanged,
+    /// The line wasn't applied, for the given reason
+    Skipped(String),
+    /// Applying the line failed. Only returned with `keep_going`, otherwise the error is.
+    Failed(eyre::Report),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Created => write!(f, "created"),
+            Self::Modified => write!(f, "modified"),
+            Self::Unchanged => write!(f, "unchanged"),
+            Self::Skipped(reason) => write!(f, "skipped: {reason}"),
+            Self::Failed(error) => write!(f, "failed: {error:#}"),
+        }
+    }
+}
+
+/// The outcome of one line in one pass, with where the line came from
+#[derive(Debug)]
+pub struct LineOutcome {
+    pub pass: &'static str,
+    /// Configuration file the line was parsed from
+    pub file: PathBuf,
+    /// Byte offsets of the line within its file
+    pub characters: Range<usize>,
+    /// Type character of the line, like `d`
+    pub action: char,
+    pub outcome: Outcome,
+}
+
+impl LineOutcome {
+    fn new(pass: &'static str, line: &Line, outcome: Outcome) -> Self {
+        Self {
+            pass,
+            file: line.line_type.file().to_owned(),
+            characters: line.line_type.characters().start..line.argument.characters().end,
+            action: line.line_type.data.action.type_char(),
+            outcome,
+        }
+    }
+}
+
+/// Shows where the line came from, then what happened, like `foo.conf@0..12 create d: created`
+impl fmt::Display for LineOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}@{:?} {} {}: {}",
+            self.file.display(),
+            self.characters,
+            self.pass,
+            self.action,
+            self.outcome
+        )
+    }
+}
+
+/// What changes whenever anything about an object does, its type, contents, or metadata. Since
+/// `ctime` can't be set, a change always shows, even one that restores an old `mtime`.
+fn change_stamp(path: &Path) -> Option<(u64, i64, i64)> {
+    let meta = fs::symlink_metadata(path).ok()?;
+    Some((meta.ino(), meta.ctime(), meta.ctime_nsec()))
+}
+
+pub fn create(config: &[Line], context: &Context) -> eyre::Result<Vec<LineOutcome>> {
+    config
+        .iter()
+        .map(|line| {
+            context.apply_line("create", line, || match create_line(line, context) {
+                // With -, failing to create is only worth a warning, and doesn't fail the run
+                Err(e) if line.line_type.data.noerror => {
+                    context.warn(
+                        Some(line),
+                        format_args!("ignoring failure to create {line}: {e:#}"),
+                    );
+                    Ok(())
+                }
+                result => result,
+            })
+        })
+        .collect()
+}
+
+fn create_line(line: &Line, context: &Context) -> eyre::Result<()> {
+    let line_type = line.line_type.data;
+    if line_type.credential {
+        return match with_credential(line, context)? {
+            Some(line) => create_line(&line, context),
+            None => Ok(()),
+        };
+    }
+    match line_type.action {
+        LineAction::CreateFile => {
+            create_file(&context.line_path(line)?, line, context)?;
+        }
+        LineAction::WriteFile => {
+            write_file(&context.line_path(line)?, line, context)?;
+        }
+        LineAction::CreateAndCleanUpDirectory | LineAction::CreateAndRemoveDirectory => {
+            create_directory(&context.line_path(line)?, line, context)?;
+        }
+        LineAction::CreateSubvolume
+        | LineAction::CreateSubvolumeInheritQuota
+        | LineAction::CreateSubvolumeNewQuota => {
+            let path = context.line_path(line)?;
+            if on_btrfs(&path)? {
+                create_subvolume(&path, line, context)?;
+            } else {
+                // Elsewhere there are no subvolumes, so these are directories. A new directory
+                // joins the project of its parent by itself, which is all q needs.
+                create_directory(&path, line, context)?;
+                if line_type.action == LineAction::CreateSubvolumeNewQuota {
+                    new_project_quota(&path, line, context)?;
+                }
+            }
+        }
+        LineAction::CleanUpDirectory => {
+            // Unlike d, e never creates the directory
+            adjust_directory(&context.line_path(line)?, line, context, false)?;
+        }
+        LineAction::CreateFifo => {
+            create_fifo(&context.line_path(line)?, line, context)?;
+        }
+        LineAction::CreateSymlink => {
+            let link = &context.line_path(line)?;
+            let target = &*symlink_target(line, context)?;
+            if missing_factory_default(line, || context.rooted(target))? {
+                return Ok(());
+            }
+            match fs::symlink_metadata(link) {
+                Ok(meta) if meta.is_symlink() && fs::read_link(link)? == target => return Ok(()),
+                // With + whatever is there is replaced, with = only something that isn't a symlink
+                Ok(meta) if line_type.recreate || (line_type.force && !meta.is_symlink()) => {
+                    remove_tree_guarded(link, context.remove_options())?
+                }
+                // Otherwise an existing object is left alone
+                Ok(_) => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    create_parents(link, line_type.force)?
+                }
+                Err(e) => Err(e)?,
+            }
+            privileged(
+                context.syscalls.symlink(target, link),
+                "creating",
+                link,
+                "CAP_DAC_OVERRIDE",
+            )?;
+            context.relabel(link)?;
+        }
+        LineAction::CreateCharDevice => {
+            create_device(&context.line_path(line)?, line, context, libc::S_IFCHR)?;
+        }
+        LineAction::CreateBlockDevice => {
+            create_device(&context.line_path(line)?, line, context, libc::S_IFBLK)?;
+        }
+        LineAction::Copy => {
+            let target = context.line_path(line)?;
+            let source = context.copy_source(line)?;
+            if missing_factory_default(line, || Ok(source.clone()))? {
+                return Ok(());
+            }
+            copy(&source, &target, line_type, context)?;
+            // The copy keeps the mode and owner of the source, unless the line asks for others
+            adjust_node(&target, line, context, false)?;
+        }
+        // Only cleaning and removal look at these
+        LineAction::Ignore | LineAction::IgnoreNonRecursive => {}
+        // Removed by the remove pass, there's nothing to create
+        LineAction::Remove | LineAction::RemoveRecursive => {}
+        LineAction::SetMode => adjust_node(
+            &context.line_path(line)?,
+            line,
+            context,
+            context.dereference,
+        )?,
+        LineAction::SetModeRecursive => adjust_tree(&context.line_path(line)?, line, context)?,
+        LineAction::SetXattr | LineAction::SetXattrRecursive => {
+            set_xattrs(&context.line_path(line)?, line, context)?;
+        }
+        LineAction::SetAttr | LineAction::SetAttrRecursive => {
+            set_attrs(&context.line_path(line)?, line, context)?;
+        }
+        LineAction::SetAcl | LineAction::SetAclRecursive => {
+            set_acl(&context.line_path(line)?, line, context)?;
+        }
+    }
+    Ok(())
+}
+
+/// Correct the mode, ownership, and other metadata of what already exists, without creating or
+/// removing anything. Lines that only adjust metadata apply as usual, and the paths of `d` and `f`
+/// lines get the mode and ownership they ask for if they're already there.
+pub fn repair(config: &[Line], context: &Context) -> eyre::Result<Vec<LineOutcome>> {
+    config
+        .iter()
+        .map(|line| context.apply_line("repair", line, || repair_line(line, context)))
+        .collect()
+}
+
+fn repair_line(line: &Line, context: &Context) -> eyre::Result<()> {
+    let (what, expected): (_, fn(fs::FileType) -> bool) = match line.line_type.data.action {
+        LineAction::CreateAndCleanUpDirectory
+        | LineAction::CreateAndRemoveDirectory
+        | LineAction::CreateSubvolume
+        | LineAction::CreateSubvolumeInheritQuota
+        | LineAction::CreateSubvolumeNewQuota => ("a directory", |file_type| file_type.is_dir()),
+        LineAction::CreateFile => ("a regular file", |file_type| file_type.is_file()),
+        LineAction::SetMode
+        | LineAction::SetModeRecursive
+        | LineAction::SetXattr
+        | LineAction::SetXattrRecursive
+        | LineAction::SetAttr
+        | LineAction::SetAttrRecursive
+        | LineAction::SetAcl
+        | LineAction::SetAclRecursive => return create_line(line, context),
+        _ => return Ok(()),
+    };
+    let path = context.line_path(line)?;
+    match fs::symlink_metadata(&path) {
+        Ok(meta) if expected(meta.file_type()) => adjust_node(&path, line, context, false),
+        // Fixing that would mean removing it, which is up to --create with `+`
+        Ok(_) => {
+            context.warn(
+                Some(line),
+                format_args!("not repairing {}, which is not {what}", path.display()),
+            );
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e)?,
+    }
+}
+
+/// Clean up directories with a cleanup age. Entries are compared against `now` rather than the
+/// clock so the result is reproducible.
+pub fn clean(
+    config: &[Line],
+    context: &Context,
+    now: SystemTime,
+) -> eyre::Result<Vec<LineOutcome>> {
+    let ignored = Ignored::new(config, context)?;
+    config
+        .iter()
+        .map(|line| context.apply_line("clean", line, || clean_line(line, context, now, &ignored)))
+        .collect()
+}
+
+/// Paths that `x` and `X` lines protect from cleaning and removal. Their paths are taken
+/// literally, globs aren't matched yet.
+#[derive(Debug, Default)]
+pub(crate) struct Ignored {
+    /// From `x`, kept along with everything below them
+    subtrees: Vec<PathBuf>,
+    /// From `X`, kept themselves, while their contents are cleaned as usual
+    paths: Vec<PathBuf>,
+}
+
+impl Ignored {
+    fn new(config: &[Line], context: &Context) -> eyre::Result<Self> {
+        let mut ignored = Self::default();
+        for line in config {
+            match line.line_type.data.action {
+                LineAction::Ignore => ignored.subtrees.push(context.line_path(line)?),
+                LineAction::IgnoreNonRecursive => ignored.paths.push(context.line_path(line)?),
+                _ => {}
+            }
+        }
+        Ok(ignored)
+    }
+
+    /// Whether `path` and everything below it is left alone
+    pub(crate) fn covers(&self, path: &Path) -> bool {
+        self.subtrees
+            .iter()
+            .any(|subtree| path.starts_with(subtree))
+    }
+
+    /// Whether `path` itself is kept, though what's below it may not be
+    pub(crate) fn keeps(&self, path: &Path) -> bool {
+        self.covers(path) || self.paths.iter().any(|kept| kept == path)
+    }
+}
+
+fn clean_line(
+    line: &Line,
+    context: &Context,
+    now: SystemTime,
+    ignored: &Ignored,
+) -> eyre::Result<()> {
+    match line.line_type.data.action {
+        LineAction::CreateAndCleanUpDirectory
+        | LineAction::CleanUpDirectory
+        | LineAction::CreateSubvolume
+        | LineAction::CreateSubvolumeInheritQuota
+        | LineAction::CreateSubvolumeNewQuota => {
+            // `-` parses to CleanupAge::EMPTY, so it can't be told apart from an age of 0 and
+            // neither of them cleans anything
+            let Some(age) = line.age.data.filter(|age| *age != CleanupAge::EMPTY) else {
+                return Ok(());
+            };
+            let Some(cutoff) = now.checked_sub(age.age) else {
+                return Ok(());
+            };
+            clean_directory(context, &context.removal_path(line)?, &age, cutoff, ignored)?;
+        }
+        LineAction::CreateAndRemoveDirectory => {
+            let path = context.removal_path(line)?;
+            if context.confirm_removal(&path, || "contents of a D directory".to_owned()) {
+                empty_directory(&path, ignored, context)?;
+            }
+        }
+        // Collected up front, since they protect paths of every other line
+        LineAction::Ignore | LineAction::IgnoreNonRecursive => {}
+        _ => {}
+    }
+    Ok(())
+}
+
+pub fn remove(config: &[Line], context: &Context) -> eyre::Result<Vec<LineOutcome>> {
+    let ignored = Ignored::new(config, context)?;
+    config
+        .iter()
+        .map(|line| context.apply_line("remove", line, || remove_line(line, context, &ignored)))
+        .collect()
+}
+
+fn remove_line(line: &Line, context: &Context, ignored: &Ignored) -> eyre::Result<()> {
+    let options = RemoveOptions {
+        ignored: Some(ignored),
+        ..context.remove_options()
+    };
+    match line.line_type.data.action {
+        LineAction::CreateAndRemoveDirectory => {
+            let path = context.removal_path(line)?;
+            match fs::symlink_metadata(&path) {
+                Ok(meta) if meta.is_dir() => {
+                    if context.confirm_removal(&path, || "D directory".to_owned()) {
+                        ignore_not_found(remove_tree_guarded(&path, options))?
+                    }
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => Err(e)?,
+            }
+        }
+        // Paths are taken literally, globs aren't matched yet
+        LineAction::Remove => {
+            let path = context.removal_path(line)?;
+            if ignored.keeps(&path) {
+                return Ok(());
+            }
+            match fs::symlink_metadata(&path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+                result => result?,
+            };
+            if context.confirm_removal(&path, || "r line".to_owned()) {
+                match remove_guarded(&path) {
+                    // r only removes directories that are empty
+                    Err(e) if e.raw_os_error() == Some(libc::ENOTEMPTY) => {}
+                    result => ignore_not_found(result)?,
+                }
+            }
+        }
+        LineAction::RemoveRecursive => {
+            let path = context.removal_path(line)?;
+            match fs::symlink_metadata(&path) {
+                Ok(_) => {
+                    if context.confirm_removal(&path, || "R line".to_owned()) {
+                        ignore_not_found(remove_tree_guarded(&path, options))?
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => Err(e)?,
+            }
+        }
+        // Collected up front, since they protect paths of every other line
+        LineAction::Ignore | LineAction::IgnoreNonRecursive => {}
+        _ => {}
+    }
+    Ok(())
+}
+
+/// A `^` line with its argument replaced by the contents of the credential it names, or `None`
+/// when that credential isn't set, which skips the line like in systemd
+fn with_credential<'a>(line: &Line<'a>, context: &Context) -> eyre::Result<Option<Line<'a>>> {
+    let name = line.argument.data.as_deref().unwrap_or_default();
+    // A name, never a path that could lead out of the directory
+    if !matches!(
+        Path::new(name).components().collect::<Vec<_>>()[..],
+        [Component::Normal(_)]
+    ) || name.as_bytes().contains(&b'/')
+    {
+        Err(eyre!("Invalid credential name {name:?}"))?
+    }
+    let Some(credentials) = &context.credentials else {
+        return Ok(None);
+    };
+    let contents = match fs::read(credentials.join(name)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => Err(e)?,
+    };
+    let mut line = line.clone();
+    line.line_type.data.credential = false;
+    line.argument.data = Some(OsString::from_vec(contents));
+    Ok(Some(line))
+}
+
+/// The target an `L` line's link should point to, with specifiers expanded, by default its
+/// `factory_path`. It isn't moved below the root, since it's written into the link as is.
+pub(crate) fn symlink_target(line: &Line, context: &Context) -> eyre::Result<PathBuf> {
+    let Some(target) = line.argument.data.as_ref() else {
+        return context.factory_path(line);
+    };
+    context.argument_path(target, "symlink target")
+}
+
+/// Whether a `C` or `L` line goes without an argument and the factory default it uses instead is
+/// missing at `source`. That makes the line do nothing, since minimal images may leave out
+/// `/usr/share/factory`.
+fn missing_factory_default(
+    line: &Line,
+    source: impl FnOnce() -> eyre::Result<PathBuf>,
+) -> eyre::Result<bool> {
+    if line.argument.data.is_some() {
+        return Ok(false);
+    }
+    match fs::symlink_metadata(source()?) {
+        Ok(_) => Ok(false),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(e)?,
+    }
+}
+
+/// Permission bits an object should end up with, or `None` to leave an existing object alone.
+/// `existing` is the current mode, or `None` if we just created it.
+pub(crate) fn target_mode(mode: Option<&Mode>, default: u32, existing: Option<u32>) -> Option<u32> {
+    let Some(mode) = mode else {
+        return existing.is_none().then_some(default);
+    };
+    match (&mode.mode_behavior, existing) {
+        (ModeBehavior::KeepExisting, Some(_)) => None,
+        (ModeBehavior::Masked, Some(existing)) => {
+            // Drop read, write, or execute entirely if nobody had it before
+            let mut value = mode.value;
+            for bits in [0o444, 0o222, 0o111] {
+                if existing & bits == 0 {
+                    value &= !bits;
+                }
+            }
+            Some(value)
+        }
+        _ => Some(mode.value),
+    }
+}
+
+/// `f` and `F`: create a file, writing the argument if it's new or being truncated. `F` is parsed
+/// as `f+`, and truncates an existing file on every run, not only at boot, like systemd does.
+/// Contents are written with `replace_file`, so a reader never sees a partly written file.
+fn create_file(path: &Path, line: &Line, context: &Context) -> eyre::Result<()> {
+    let recreate = line.line_type.data.recreate;
+    let existing = match fs::symlink_metadata(path) {
+        // With = whatever else is in the way is replaced
+        Ok(meta) if !meta.is_file() && line.line_type.data.force => {
+            remove_tree_guarded(path, context.remove_options())?;
+            None
+        }
+        Ok(meta) if !meta.is_file() => {
+            Err(eyre!("{} exists and is not a regular file", path.display()))?
+        }
+        Ok(meta) => Some(meta),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => Err(e)?,
+    };
+    if let Some(contents) = line
+        .argument
+        .data
+        .as_ref()
+        .filter(|_| existing.is_none() || recreate)
+    {
+        return replace_file(path, contents.as_bytes(), |file| {
+            let mode = existing
+                .as_ref()
+                .map(|meta| preserve(context, path, file, meta))
+                .transpose()?;
+            finalize_node(context, path, file, line, 0o644, mode)?;
+            stamp(context, file)
+        });
+    }
+
+    let mut options = OpenOptions::new();
+    options
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW);
+    let (file, existing) = match options.open(path) {
+        Ok(file) => (file, None),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            let meta = fs::symlink_metadata(path)?;
+            if !meta.is_file() {
+                Err(eyre!("{} exists and is not a regular file", path.display()))?
+            }
+            let file = options.create_new(false).truncate(recreate).open(path)?;
+            if recreate {
+                stamp(context, &file)?;
+            }
+            (file, Some(meta.permissions().mode() & 0o7777))
+        }
+        Err(e) => Err(e)?,
+    };
+    finalize_node(context, path, &file, line, 0o644, existing)
+}
+
+/// Set the times of a file we just created or wrote to `context.timestamp`, if there is one
+fn stamp(context: &Context, file: &File) -> eyre::Result<()> {
+    if let Some(time) = context.timestamp {
+        match file.set_times(FileTimes::new().set_accessed(time).set_modified(time)) {
+            // Device nodes are only open with O_PATH, which futimens refuses
+            Err(e) if e.raw_os_error() == Some(libc::EBADF) => {
+                syscalls::set_times(&syscalls::fd_path(file), time)?
+            }
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+/// Give a replacement file the mode and ownership of the file it replaces, returning the mode
+fn preserve(context: &Context, path: &Path, file: &File, meta: &fs::Metadata) -> eyre::Result<u32> {
+    let mode = meta.permissions().mode() & 0o7777;
+    let syscalls = &context.syscalls;
+    privileged(
+        syscalls.chown(path, file, Some(meta.uid()), Some(meta.gid())),
+        "changing the owner of",
+        path,
+        "CAP_CHOWN",
+    )?;
+    privileged(
+        syscalls.chmod(path, file, mode),
+        "changing the mode of",
+        path,
+        "CAP_FOWNER",
+    )?;
+    Ok(mode)
+}
+
+/// Write `contents` to a temporary file next to `path`, let `prepare` set its mode and ownership,
+/// then rename it over `path`. On failure the temporary file is removed and `path` is untouched.
+fn replace_file(
+    path: &Path,
+    contents: &[u8],
+    prepare: impl FnOnce(&File) -> eyre::Result<()>,
+) -> eyre::Result<()> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        Err(eyre!("{} is not a file path", path.display()))?
+    };
+    let (temporary, mut file) = loop {
+        let mut temporary_name = OsString::from(".#");
+        temporary_name.push(name);
+        temporary_name.push(format!(
+            ".{}.{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let temporary = parent.join(temporary_name);
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&temporary)
+        {
+            Ok(file) => break (temporary, file),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => Err(eyre!(
+                "Can't create a temporary file in {} to replace {}: {e}",
+                parent.display(),
+                path.display()
+            ))?,
+        }
+    };
+    let result = (|| {
+        file.write_all(contents)?;
+        prepare(&file)?;
+        // Make sure the contents are on disk before they become visible under the real name
+        file.sync_all()?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = remove_guarded(&temporary);
+    }
+    result
+}
+
+/// `d` and `D`: create a directory and its parents, replacing anything else at the path with `=`
+fn create_directory(path: &Path, line: &Line, context: &Context) -> eyre::Result<()> {
+    create_parents(path, line.line_type.data.force)?;
+    let mkdir = || DirBuilder::new().mode(0o700).create(path);
+    let created = match mkdir() {
+        Ok(()) => true,
+        // With =, whatever else is in the way is replaced, otherwise adjust_directory refuses it
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists && line.line_type.data.force => {
+            if fs::symlink_metadata(path)?.is_dir() {
+                false
+            } else {
+                remove_tree_guarded(path, context.remove_options())?;
+                mkdir()?;
+                true
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => false,
+        Err(e) => Err(e)?,
+    };
+    adjust_directory(path, line, context, created)
+}
+
+/// Create the directories `path` goes in. With `force`, for `=`, the first one that's something
+/// other than a directory or a symlink to one is replaced, like a fifo where a directory should be.
+fn create_parents(path: &Path, force: bool) -> io::Result<()> {
+    let Some(parent) = path.parent() else {
+        return Ok(());
+    };
+    if force {
+        let ancestors: Vec<_> = parent.ancestors().collect();
+        for ancestor in ancestors.into_iter().rev() {
+            match fs::metadata(ancestor) {
+                Ok(meta) if meta.is_dir() => continue,
+                Ok(_) => remove_tree_guarded(ancestor, RemoveOptions::default())?,
+                // Nothing there, unless it's a symlink that leads nowhere
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    if fs::symlink_metadata(ancestor).is_ok() {
+                        remove_tree_guarded(ancestor, RemoveOptions::default())?
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+            break;
+        }
+    }
+    fs::create_dir_all(parent)
+}
+
+/// Whether `path`, or the directory it would be created in, is on btrfs
+fn on_btrfs(path: &Path) -> io::Result<bool> {
+    for ancestor in path.ancestors() {
+        match File::open(ancestor) {
+            Ok(file) => return quota::on_btrfs(&file),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(false)
+}
+
+/// `v`, `q`, and `Q` on btrfs: create a subvolume, which `q` and `Q` add to quota groups with
+/// `quota::join_parent_qgroups`. Where the subvolume is refused it's a plain directory instead,
+/// and where the quota groups are it goes without, with a warning either way. An existing
+/// directory is adjusted like with `d`.
+fn create_subvolume(path: &Path, line: &Line, context: &Context) -> eyre::Result<()> {
+    let LineType { action, force, .. } = line.line_type.data;
+    match fs::symlink_metadata(path) {
+        // With =, whatever else is in the way is replaced, otherwise adjust_directory refuses it
+        Ok(meta) if force && !meta.is_dir() => remove_tree_guarded(path, context.remove_options())?,
+        Ok(_) => return create_directory(path, line, context),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => Err(e)?,
+    }
+    create_parents(path, force)?;
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return create_directory(path, line, context);
+    };
+    let parent = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY)
+        .open(parent)?;
+    if let Err(e) = quota::create_subvolume(&parent, name) {
+        if e.kind() != io::ErrorKind::AlreadyExists {
+            context.warn(
+                Some(line),
+                format_args!(
+                    "creating {} as a plain directory, it can't be a subvolume: {e}",
+                    path.display()
+                ),
+            );
+        }
+        return create_directory(path, line, context);
+    }
+    if action != LineAction::CreateSubvolume {
+        let subvolume = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+            .open(path)?;
+        let intermediate = action == LineAction::CreateSubvolumeNewQuota;
+        match quota::join_parent_qgroups(&parent, &subvolume, intermediate) {
+            // Quotas aren't enabled on this filesystem, so there's nothing to join
+            Err(e) if e.raw_os_error() == Some(libc::ENOTCONN) => {}
+            Err(e) => context.warn(
+                Some(line),
+                format_args!("not setting up quota groups for {}: {e}", path.display()),
+            ),
+            Ok(()) => {}
+        }
+    }
+    adjust_directory(path, line, context, true)
+}
+
+/// `Q` outside of btrfs: give the directory a project of its own where the filesystem counts
+/// quota by project, like XFS does. Elsewhere it stays a plain directory, with a warning.
+fn new_project_quota(path: &Path, line: &Line, context: &Context) -> eyre::Result<()> {
+    let dir = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+        .open(path)?;
+    match quota::new_project(&dir) {
+        Err(e)
+            if matches!(
+                e.raw_os_error(),
+                Some(libc::ENOTTY | libc::EOPNOTSUPP | libc::EINVAL)
+            ) || matches!(
+                e.kind(),
+                io::ErrorKind::Unsupported | io::ErrorKind::InvalidInput
+            ) =>
+        {
+            context.warn(
+                Some(line),
+                format_args!("not setting up a quota for {}: {e}", path.display()),
+            );
+            Ok(())
+        }
+        result => privileged(result, "setting the project of", path, "CAP_FOWNER"),
+    }
+}
+
+/// Set the mode and ownership of a directory, if it exists. `created` is whether we just made it.
+fn adjust_directory(
+    path: &Path,
+    line: &Line,
+    context: &Context,
+    created: bool,
+) -> eyre::Result<()> {
+    // Work on the directory through a handle, so it can't be swapped for a symlink under us
+    let dir = match OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+        .open(path)
+    {
+        Ok(dir) => dir,
+        Err(e)
+            if e.raw_os_error() == Some(libc::ENOTDIR) || e.raw_os_error() == Some(libc::ELOOP) =>
+        {
+            Err(eyre!("{} exists and is not a directory", path.display()))?
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => Err(e)?,
+    };
+    let existing = (!created).then_some(dir.metadata()?.permissions().mode() & 0o7777);
+    finalize_node(context, path, &dir, line, 0o755, existing)
+}
+
+/// `p` and `p+`: create a fifo, replacing whatever is in the way with `+` or `=`
+fn create_fifo(path: &Path, line: &Line, context: &Context) -> eyre::Result<()> {
+    let LineType {
+        recreate, force, ..
+    } = line.line_type.data;
+    create_parents(path, force)?;
+    // No permissions until finalize_node sets them, so nobody can open it in the meantime
+    let mut created = true;
+    if let Err(error) = context.syscalls.mkfifo(path, 0) {
+        created = false;
+        if error.kind() != io::ErrorKind::AlreadyExists {
+            privileged(Err(error), "creating", path, "CAP_DAC_OVERRIDE")?
+        }
+        let meta = fs::symlink_metadata(path)?;
+        if !meta.file_type().is_fifo() {
+            if !(recreate || force) {
+                Err(eyre!("{} exists and is not a fifo", path.display()))?
+            }
+            remove_tree_guarded(path, context.remove_options())?;
+            privileged(
+                context.syscalls.mkfifo(path, 0),
+                "creating",
+                path,
+                "CAP_DAC_OVERRIDE",
+            )?;
+            created = true;
+        }
+    }
+    // Only a path handle, since reading needs permissions the fifo doesn't have yet, and opening
+    // it for real would wait for a writer
+    let fifo = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH | libc::O_NOFOLLOW)
+        .open(path)?;
+    let existing = (!created).then_some(fifo.metadata()?.permissions().mode() & 0o7777);
+    finalize_node(context, path, &fifo, line, 0o644, existing)
+}
+
+/// `c` and `b`: create a device node of `kind`, `S_IFCHR` or `S_IFBLK`, with the numbers in the
+/// argument. An existing node of another type is only replaced with `+` or `=`, like with `p`.
+fn create_device(path: &Path, line: &Line, context: &Context, kind: u32) -> eyre::Result<()> {
+    let Some(argument) = &line.argument.data else {
+        Err(eyre!("{} needs major:minor device numbers", path.display()))?
+    };
+    let DeviceNumbers { major, minor } = parse_device_numbers(argument.as_bytes())?;
+    let LineType {
+        recreate, force, ..
+    } = line.line_type.data;
+    create_parents(path, force)?;
+    let mknod = || {
+        privileged(
+            context.syscalls.mknod(path, kind, major, minor),
+            "creating",
+            path,
+            "CAP_MKNOD",
+        )
+    };
+    // No permissions until finalize_node sets them, like with fifos
+    let mut created = true;
+    if let Err(error) = context.syscalls.mknod(path, kind, major, minor) {
+        created = false;
+        if error.kind() != io::ErrorKind::AlreadyExists {
+            privileged(Err(error), "creating", path, "CAP_MKNOD")?
+        }
+        let meta = fs::symlink_metadata(path)?;
+        if meta.mode() & libc::S_IFMT != kind {
+            if !(recreate || force) {
+                Err(eyre!(
+                    "{} exists and is not a {} device",
+                    path.display(),
+                    if kind == libc::S_IFCHR {
+                        "character"
+                    } else {
+                        "block"
+                    }
+                ))?
+            }
+            remove_tree_guarded(path, context.remove_options())?;
+            mknod()?;
+            created = true;
+        }
+    }
+    // Only a path handle, opening the device itself would reach its driver
+    let node = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH | libc::O_NOFOLLOW)
+        .open(path)?;
+    let existing = (!created).then_some(node.metadata()?.permissions().mode() & 0o7777);
+    finalize_node(context, path, &node, line, 0o644, existing)
+}
+
+/// `z`: set the mode and ownership of whatever is at `path`, if anything. Unless we `follow` it, a
+/// symlink is left alone, since its own mode means nothing and changing its owner would surprise
+/// whoever put it there.
+fn adjust_node(path: &Path, line: &Line, context: &Context, follow: bool) -> eyre::Result<()> {
+    let no_follow = if follow { 0 } else { libc::O_NOFOLLOW };
+    // Only a path handle, like for devices, since sockets can't be opened for real, opening a
+    // device or fifo reaches whatever is behind it, and the file may not be readable at all
+    let file = match OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH | no_follow)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => Err(e)?,
+    };
+    adjust_handle(path, &file, line, context)
+}
+
+/// Like `adjust_node`, for a handle to what's at `path` we already have
+fn adjust_handle(path: &Path, file: &File, line: &Line, context: &Context) -> eyre::Result<()> {
+    let meta = file.metadata()?;
+    // With O_NOFOLLOW a symlink is opened itself
+    if meta.is_symlink() {
+        return Ok(());
+    }
+    let default = if meta.is_dir() { 0o755 } else { 0o644 };
+    finalize_node(
+        context,
+        path,
+        file,
+        line,
+        default,
+        Some(meta.mode() & 0o7777),
+    )
+}
+
+/// `Z`: like `z`, and the same for everything below a directory. Every entry gets the same mode,
+/// there's no way to ask for execute permission only on directories like chmod's `X`, though with
+/// a `~` mode entries that nobody could execute keep it that way.
+fn adjust_tree(path: &Path, line: &Line, context: &Context) -> eyre::Result<()> {
+    adjust_node(path, line, context, context.dereference)?;
+    let meta = if context.dereference {
+        fs::metadata(path)
+    } else {
+        fs::symlink_metadata(path)
+    };
+    match meta {
+        Ok(meta) if meta.is_dir() => walk_entries(
+            path,
+            context.dereference,
+            context.max_depth,
+            &mut |path, file| adjust_handle(path, file, line, context),
+        ),
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e)?,
+    }
+}
+
+/// Apply the mode and ownership a line asks for to a node we hold open, either just created with
+/// `existing` as `None`, or already there with the given mode. Shared by every type that creates
+/// something.
+fn finalize_node(
+    context: &Context,
+    path: &Path,
+    file: &File,
+    line: &Line,
+    default: u32,
+    existing: Option<u32>,
+) -> eyre::Result<()> {
+    // The mode goes first so a node handed to another owner never has looser permissions than
+    // asked for. fchmod with all 12 bits, since creating applies the umask and may drop the
+    // sticky and setgid bits.
+    let mode = target_mode(line.mode.data.as_ref(), default, existing);
+    let chmod = |mode| {
+        privileged(
+            context.syscalls.chmod(path, file, mode),
+            "changing the mode of",
+            path,
+            "CAP_FOWNER",
+        )
+    };
+    // Setting the same mode again would still change the ctime, so skip it
+    if let Some(mode) = mode.filter(|&mode| Some(mode) != existing) {
+        chmod(mode)?;
+    }
+    set_ownership(context, path, file, line, existing.is_none())?;
+    // Changing the owner clears the setuid and setgid bits, so put them back
+    if let Some(mode) = mode.filter(|mode| mode & 0o6000 != 0) {
+        if file.metadata()?.permissions().mode() & 0o7777 != mode {
+            chmod(mode)?;
+        }
+    }
+    context.relabel(path)?;
+    if existing.is_none() {
+        stamp(context, file)?;
+    }
+    Ok(())
+}
+
+/// `C`: copy `source` to `target` recursively. An existing target is left alone, unless it gets
+/// replaced, always with `+` and with `=` if it isn't the same type as the source. A directory
+/// copied onto an existing directory gets the children it's missing instead, each copied whole,
+/// so the ones already there are kept as they are and copying again changes nothing.
+fn copy(source: &Path, target: &Path, line_type: LineType, context: &Context) -> eyre::Result<()> {
+    match fs::symlink_metadata(target) {
+        Ok(meta) => {
+            let source_type = fs::symlink_metadata(source)?.file_type();
+            let mismatch = meta.file_type() != source_type;
+            if !(line_type.recreate || (line_type.force && mismatch)) {
+                if meta.is_dir() && source_type.is_dir() {
+                    copy_missing_children(source, target, context.max_depth)?;
+                }
+                return Ok(());
+            }
+            remove_tree_guarded(target, context.remove_options())?
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => Err(e)?,
+    }
+    create_parents(target, line_type.force)?;
+    copy_tree(source, target, context.max_depth)?;
+    Ok(())
+}
+
+/// Copy what's in the directory `source` and missing from `target`, going `depth_left` levels of
+/// directories deep, counting `source`
+fn copy_missing_children(source: &Path, target: &Path, depth_left: usize) -> io::Result<()> {
+    let Some(depth_left) = depth_left.checked_sub(1) else {
+        return Err(too_deep(source));
+    };
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let child = target.join(entry.file_name());
+        match fs::symlink_metadata(&child) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                copy_tree(&entry.path(), &child, depth_left)?
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Copy `source` to `target`, going `depth_left` levels of directories deep, counting `source`
+fn copy_tree(source: &Path, target: &Path, depth_left: usize) -> io::Result<()> {
+    let meta = fs::symlink_metadata(source)?;
+    if meta.is_dir() {
+        let Some(depth_left) = depth_left.checked_sub(1) else {
+            return Err(too_deep(source));
+        };
+        DirBuilder::new().mode(0o700).create(target)?;
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &target.join(entry.file_name()), depth_left)?;
+        }
+        fs::set_permissions(target, meta.permissions())?;
+    } else if meta.is_symlink() {
+        std::os::unix::fs::symlink(fs::read_link(source)?, target)?;
+    } else if meta.is_file() {
+        copy_file(source, target, &meta)?;
+    }
+    Ok(())
+}
+
+/// Copy a regular file, sharing its data with the source where the filesystem supports it, like
+/// btrfs and XFS do, and copying the bytes otherwise
+fn copy_file(source: &Path, target: &Path, meta: &fs::Metadata) -> io::Result<()> {
+    let mut source = File::open(source)?;
+    let mut target = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(target)?;
+    // Cloning fails as a whole, so nothing was written if it didn't work out
+    if reflink(&source, &target).is_err() {
+        io::copy(&mut source, &mut target)?;
+    }
+    target.set_permissions(meta.permissions())
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(source: &File, target: &File) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    if unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_source: &File, _target: &File) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reflinks are only supported on Linux",
+    ))
+}
+
+/// Pass on the result of an operation that may need privileges, explaining a permission error
+/// when we aren't root, since a bare EPERM doesn't say that running as root would fix it
+fn privileged(
+    result: io::Result<()>,
+    operation: &str,
+    path: &Path,
+    capability: &str,
+) -> eyre::Result<()> {
+    let root = unsafe { libc::geteuid() } == 0;
+    result.map_err(|error| explain_privileged(error, operation, path, capability, root))
+}
+
+fn explain_privileged(
+    error: io::Error,
+    operation: &str,
+    path: &Path,
+    capability: &str,
+    root: bool,
+) -> eyre::Report {
+    if error.kind() == io::ErrorKind::PermissionDenied && !root {
+        eyre!(
+            "{operation} {} requires root (or {capability}): {error}",
+            path.display()
+        )
+    } else {
+        error.into()
+    }
+}
+
+/// Change the owner and group of `path` to the ones on the line, or for objects we just `created`
+/// to `default_owner` where the line has `-`. A mode that keeps existing permissions also keeps
+/// the existing ownership, so only objects we just `created` are changed.
+fn set_ownership(
+    context: &Context,
+    path: &Path,
+    file: &File,
+    line: &Line,
+    created: bool,
+) -> eyre::Result<()> {
+    let keep_existing = line
+        .mode
+        .data
+        .as_ref()
+        .is_some_and(|mode| mode.mode_behavior == ModeBehavior::KeepExisting);
+    if keep_existing && !created {
+        return Ok(());
+    }
+    // `-` leaves an existing node's owner alone, but gives a new one the default
+    let (default_uid, default_gid) = context.default_owner;
+    let uid = line
+        .owner
+        .data
+        .as_ref()
+        .map(users::resolve_user)
+        .transpose()?
+        .or(created.then_some(default_uid));
+    let gid = line
+        .group
+        .data
+        .as_ref()
+        .map(users::resolve_group)
+        .transpose()?
+        .or(created.then_some(default_gid));
+    // Like the mode, only change what differs, so an unchanged node keeps its ctime
+    let meta = file.metadata()?;
+    let uid = uid.filter(|&uid| uid != meta.uid());
+    let gid = gid.filter(|&gid| gid != meta.gid());
+    if uid.is_some() || gid.is_some() {
+        privileged(
+            context.syscalls.chown(path, file, uid, gid),
+            "changing the owner of",
+            path,
+            "CAP_CHOWN",
+        )?;
+    }
+    Ok(())
+}
+
+/// `w` and `w+`: write or append the argument to a file, only if it already exists. `w` leaves the
+/// file with the same contents however often it runs, but `w+` appends again each time, so it only
+/// runs at boot. The file is written in place, like systemd does, so a symlink's target is written
+/// rather than replaced, hard links keep sharing the contents, and the owner and mode stay as
+/// they are.
+fn write_file(path: &Path, line: &Line, context: &Context) -> eyre::Result<()> {
+    let Some(contents) = &line.argument.data else {
+        return Ok(());
+    };
+    if line.line_type.data.recreate && !context.boot {
+        return Ok(());
+    }
+    let mut options = OpenOptions::new();
+    if line.line_type.data.recreate {
+        options.append(true);
+    } else {
+        // Devices and fifos ignore O_TRUNC, where ftruncate would fail
+        options.write(true).truncate(true);
+    }
+    let mut file = match options.open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => Err(e)?,
+    };
+    file.write_all(contents.as_bytes())?;
+    // Files of kernel filesystems like `/proc/sys` are how settings are made, not files to date
+    if file.metadata()?.is_file() && !is_kernel_filesystem(&file)? {
+        stamp(context, &file)?;
+    }
+    Ok(())
+}
+
+/// Whether `file` is on a filesystem like procfs or sysfs, where writing is how settings are made
+#[cfg(target_os = "linux")]
+fn is_kernel_filesystem(file: &File) -> io::Result<bool> {
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatfs(std::os::fd::AsRawFd::as_raw_fd(file), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(matches!(
+        stat.f_type,
+        libc::PROC_SUPER_MAGIC
+            | libc::SYSFS_MAGIC
+            | libc::CGROUP_SUPER_MAGIC
+            | libc::CGROUP2_SUPER_MAGIC
+            | libc::DEBUGFS_MAGIC
+            | libc::SECURITYFS_MAGIC
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_kernel_filesystem(_file: &File) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// `t` and `T`: set extended attributes on a path, and for `T` on everything below it
+fn set_xattrs(path: &Path, line: &Line, context: &Context) -> eyre::Result<()> {
+    let Some(argument) = &line.argument.data else {
+        return Ok(());
+    };
+    let xattrs = xattr::parse_xattrs(argument.as_bytes())?;
+    let file = match open_path(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => Err(e)?,
+    };
+    let mut set = |path: &Path, file: &File| {
+        xattrs.iter().try_for_each(|xattr| {
+            context
+                .syscalls
+                .set_xattr(path, file, &xattr.name, &xattr.value)
+        })
+    };
+    set(path, &file)?;
+    if line.line_type.data.action == LineAction::SetXattrRecursive && file.metadata()?.is_dir() {
+        walk_entries(path, false, context.max_depth, &mut set)?;
+    }
+    Ok(())
+}
+
+/// `h` and `H`: change the inode flags of a path, and for `H` of everything below it. Only
+/// regular files and directories have them, anything else is skipped.
+fn set_attrs(path: &Path, line: &Line, context: &Context) -> eyre::Result<()> {
+    let Some(argument) = &line.argument.data else {
+        return Ok(());
+    };
+    let change = attr::parse_attrs(argument.as_bytes())?;
+    let file = match open_path(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => Err(e)?,
+    };
+    let attributes = argument.to_string_lossy();
+    let mut set = |path: &Path, file: &File| -> eyre::Result<()> {
+        let file_type = file.metadata()?.file_type();
+        if !file_type.is_file() && !file_type.is_dir() {
+            return Ok(());
+        }
+        let result = (|| {
+            // The ioctls need a handle that's open for real, the link in /proc gets one for the
+            // same file
+            let file = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(syscalls::fd_path(file))?;
+            let flags = context.syscalls.get_flags(path, &file)?;
+            let changed = change.apply(flags);
+            if changed == flags {
+                return Ok(());
+            }
+            context.syscalls.set_flags(path, &file, changed)
+        })();
+        match result {
+            // The filesystem has no inode flags, or not one of these, like ext4 without C
+            Err(e)
+                if matches!(e.raw_os_error(), Some(libc::EOPNOTSUPP | libc::ENOTTY))
+                    || e.kind() == io::ErrorKind::Unsupported =>
+            {
+                context.warn(
+                    Some(line),
+                    format_args!(
+                        "not setting file attributes {attributes} of {}: {e}",
+                        path.display()
+                    ),
+                );
+                Ok(())
+            }
+            Err(e) if e.kind() != io::ErrorKind::PermissionDenied => Err(eyre!(
+                "Can't set file attributes {attributes} of {}: {e}",
+                path.display()
+            )),
+            result => privileged(
+                result,
+                "setting file attributes of",
+                path,
+                "CAP_LINUX_IMMUTABLE",
+            ),
+        }
+    };
+    set(path, &file)?;
+    if line.line_type.data.action == LineAction::SetAttrRecursive && file.metadata()?.is_dir() {
+        walk_entries(path, false, context.max_depth, &mut set)?;
+    }
+    Ok(())
+}
+
+/// `a` and `A`: replace the ACL of a path, and for `A` of everything below it. With `+` the
+/// entries are added to the ACL instead.
+fn set_acl(path: &Path, line: &Line, context: &Context) -> eyre::Result<()> {
+    let Some(argument) = &line.argument.data else {
+        return Ok(());
+    };
+    let entries = acl::parse_acl(argument.as_bytes())?;
+    let file = match open_path(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => Err(e)?,
+    };
+    let meta = file.metadata()?;
+    if !meta.is_dir() && entries.iter().any(|entry| entry.default) {
+        context.warn(
+            Some(line),
+            format_args!(
+                "skipping default ACL entries for {}, which is not a directory",
+                path.display()
+            ),
+        );
+    }
+    let merge = line.line_type.data.recreate;
+    acl::set_acl(&*context.syscalls, path, &file, &entries, merge)?;
+    if line.line_type.data.action == LineAction::SetAclRecursive && meta.is_dir() {
+        walk_entries(path, false, context.max_depth, &mut |path, file| {
+            acl::set_acl(&*context.syscalls, path, file, &entries, merge)
+        })?;
+    }
+    Ok(())
+}
+
+/// A handle to whatever is at `path`, without following a symlink there, that can only name it,
+/// like the ones `walk_entries` gives for anything but directories
+fn open_path(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH | libc::O_NOFOLLOW)
+        .open(path)
+}
+
+pub(crate) fn too_deep(dir: &Path) -> io::Error {
+    io::Error::other(format!(
+        "{} is nested too deeply, see --max-depth",
+        dir.display()
+    ))
+}
+
+fn ignore_not_found(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn ctime(meta: &fs::Metadata) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::new(meta.ctime().max(0) as u64, meta.ctime_nsec() as u32)
+}
+
+/// Whether any of the timestamps selected by `age` is at or after `cutoff`. With no timestamps
+/// selected for this kind of entry it is always considered unused.
+fn used_since(meta: &fs::Metadata, age: &CleanupAge, cutoff: SystemTime) -> bool {
+    let (atime, btime, ctime_flag, mtime) = if meta.is_dir() {
+        (
+            age.consider_atime_dir,
+            age.consider_btime_dir,
+            age.consider_ctime_dir,
+            age.consider_mtime_dir,
+        )
+    } else {
+        (
+            age.consider_atime,
+            age.consider_btime,
+            age.consider_ctime,
+            age.consider_mtime,
+        )
+    };
+    // Birth time isn't available on every filesystem, skip it when missing
+    let newer = |time: io::Result<SystemTime>| time.is_ok_and(|time| time >= cutoff);
+    atime && newer(meta.accessed())
+        || btime && newer(meta.created())
+        || ctime_flag && ctime(meta) >= cutoff
+        || mtime && newer(meta.modified())
+}
+
+/// Remove entries below `dir` which haven't been used since `cutoff`. The directory itself is
+/// always kept, and so are its direct entries if the age has the `~` prefix.
+fn clean_directory(
+    context: &Context,
+    dir: &Path,
+    age: &CleanupAge,
+    cutoff: SystemTime,
+    ignored: &Ignored,
+) -> io::Result<()> {
+    if ignored.covers(dir) {
+        return Ok(());
+    }
+    match fs::symlink_metadata(dir) {
+        // Only entries are judged by their age, never the directory itself, and read_dir doesn't
+        // return `.` or `..`
+        Ok(meta) if meta.is_dir() => {
+            let handle = remove::open_tree(dir, false)?;
+            clean_entries(context, handle.as_fd(), dir, age, cutoff, ignored, 0)
+        }
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Clean what's in `dir`, which is at `path`. Everything below it is reached through `*at` calls
+/// with `O_NOFOLLOW` from `dir`, so a directory swapped for a symlink while we're working is never
+/// followed, and removing only ever removes from the directory we looked in. `depth` is 0 for the
+/// direct entries of the directory being cleaned. Directories at `context.max_depth` aren't
+/// descended into, so they're only removed if they're already empty.
+fn clean_entries(
+    context: &Context,
+    dir: BorrowedFd,
+    path: &Path,
+    age: &CleanupAge,
+    cutoff: SystemTime,
+    ignored: &Ignored,
+    depth: usize,
+) -> io::Result<()> {
+    let keep_entries = age.second_level && depth == 0;
+    let reason = || format!("not used within the cleanup age {age}");
+    // Never on another filesystem, since we don't go into those
+    let device = remove::fstat(dir)?.st_dev;
+    for name in remove::read_names(path)? {
+        let path = path.join(OsStr::from_bytes(name.to_bytes()));
+        // Entries that disappear under us are already gone
+        let stat = match remove::stat_at(dir, &name) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            result => result?,
+        };
+        if stat.st_dev != device || ignored.covers(&path) {
+            // Don't clean up other filesystems mounted below this one
+            continue;
+        }
+        let keep = keep_entries || ignored.keeps(&path);
+        if remove::is_dir(&stat) {
+            let child = File::from(remove::open_subdir(dir, &name, &stat)?);
+            // Use the times from before we removed anything, otherwise removing old children
+            // would keep their parent alive
+            let meta = child.metadata()?;
+            if depth < context.max_depth {
+                clean_entries(
+                    context,
+                    child.as_fd(),
+                    &path,
+                    age,
+                    cutoff,
+                    ignored,
+                    depth + 1,
+                )?;
+            }
+            if !keep && !used_since(&meta, age, cutoff) && context.confirm_removal(&path, reason) {
+                match remove::unlink_at(dir, &name, libc::AT_REMOVEDIR) {
+                    Err(e) if e.kind() == io::ErrorKind::DirectoryNotEmpty => {}
+                    result => ignore_not_found(result)?,
+                }
+            }
+        } else {
+            let meta = remove::open_entry(dir, &name, &stat)?.metadata()?;
+            if !keep && !used_since(&meta, age, cutoff) && context.confirm_removal(&path, reason) {
+                ignore_not_found(remove::unlink_at(dir, &name, 0))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Remove everything inside `dir` regardless of age, keeping the directory itself and what's
+/// `ignored`
+fn empty_directory(dir: &Path, ignored: &Ignored, context: &Context) -> io::Result<()> {
+    ignore_not_found(remove_tree_guarded(
+        dir,
+        RemoveOptions {
+            keep_root: true,
+            ignored: Some(ignored),
+            ..context.remove_options()
+        },
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        cell::RefCell,
+        fs::{self, File, FileTimes},
+        io,
+        os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt},
+        path::Path,
+        rc::Rc,
+        time::{Duration, SystemTime},
+    };
+
+    use tempfile::TempDir;
+
+    use crate::{
+        apply::{
+            clean, copy_file, create, create_subvolume, explain_privileged, reflink, remove,
+            repair, replace_file, Context, Outcome, DEFAULT_MAX_DEPTH,
+        },
+        config_file::{FileOwner, Line},
+        parser::{parse_line, FileSpan},
+        quota::{self, Project},
+        specifier::SpecifierSource,
+        syscalls::{Call, Recording},
+        users,
+    };
+
+    fn host() -> Context {
+        Context::new("/".into(), SpecifierSource::Host)
+    }
+
+    fn line(text: String) -> Line<'static> {
+        parse_line(FileSpan::from_slice(text.as_bytes(), Path::new(""))).unwrap()
+    }
+
+    fn make_old(path: &Path) {
+        let old = SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60);
+        File::open(path)
+            .unwrap()
+            .set_times(FileTimes::new().set_accessed(old).set_modified(old))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_clean_d_removes_aged_entries() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path().join("d");
+        fs::create_dir_all(dir.join("old_dir")).unwrap();
+        fs::write(dir.join("old_dir/old"), b"").unwrap();
+        fs::write(dir.join("old"), b"").unwrap();
+        fs::write(dir.join("new"), b"").unwrap();
+        make_old(&dir.join("old_dir/old"));
+        make_old(&dir.join("old_dir"));
+        make_old(&dir.join("old"));
+
+        clean(
+            &[line(format!("d {} - - - mM:1d", dir.display()))],
+            &host(),
+            SystemTime::now(),
+        )
+        .unwrap();
+        assert!(!dir.join("old").exists());
+        assert!(!dir.join("old_dir").exists());
+        assert!(dir.join("new").exists());
+    }
+
+    #[test]
+    fn test_clean_keeps_old_directory() {
+        let tmp = TempDir::new().unwrap();
+        let empty = tmp.path().join("empty");
+        let full = tmp.path().join("full");
+        fs::create_dir_all(&empty).unwrap();
+        fs::create_dir_all(&full).unwrap();
+        fs::write(full.join("old"), b"").unwrap();
+        make_old(&full.join("old"));
+        make_old(&full);
+        make_old(&empty);
+
+        clean(
+            &[
+                line(format!("d {} 1777 - - mM:1d", empty.display())),
+                line(format!("d {} 1777 - - mM:1d", full.display())),
+            ],
+            &host(),
+            SystemTime::now(),
+        )
+        .unwrap();
+        assert!(empty.is_dir());
+        assert!(full.is_dir());
+        assert!(!full.join("old").exists());
+    }
+
+    #[test]
+    fn test_clean_second_level() {
+        let tmp = TempDir::new().unwrap();
+        fs::create_dir_all(tmp.path().join("top/nested_dir")).unwrap();
+        fs::write(tmp.path().join("old"), b"").unwrap();
+        fs::write(tmp.path().join("top/old"), b"").unwrap();
+        fs::write(tmp.path().join("top/nested_dir/old"), b"").unwrap();
+        for path in [
+            "old",
+            "top/old",
+            "top/nested_dir/old",
+            "top/nested_dir",
+            "top",
+        ] {
+            make_old(&tmp.path().join(path));
+        }
+
+        clean(
+            &[line(format!("d {} - - - ~mM:1d", tmp.path().display()))],
+            &host(),
+            SystemTime::now(),
+        )
+        .unwrap();
+        assert!(tmp.path().join("old").exists());
+        assert!(tmp.path().join("top").is_dir());
+        assert!(!tmp.path().join("top/old").exists());
+        assert!(!tmp.path().join("top/nested_dir").exists());
+    }
+
+    #[test]
+    fn test_clean_max_depth() {
+        let tmp = TempDir::new().unwrap();
+        fs::create_dir_all(tmp.path().join("top/nested_dir")).unwrap();
+        fs::create_dir(tmp.path().join("empty")).unwrap();
+        for path in ["old", "top/old", "top/nested_dir/old"] {
+            fs::write(tmp.path().join(path), b"").unwrap();
+        }
+        for path in [
+            "old",
+            "top/old",
+            "top/nested_dir/old",
+            "top/nested_dir",
+            "top",
+            "empty",
+        ] {
+            make_old(&tmp.path().join(path));
+        }
+        let clean_with = |age: &str, max_depth: usize| {
+            let context = Context {
+                max_depth,
+                ..host()
+            };
+            let config = [line(format!("d {} - - - {age}", tmp.path().display()))];
+            clean(&config, &context, SystemTime::now()).unwrap();
+        };
+
+        // Only the direct entries are judged, so `top` stays since it isn't empty, but the
+        // empty directory goes
+        clean_with("mM:1d", 0);
+        assert!(!tmp.path().join("old").exists());
+        assert!(tmp.path().join("top/old").exists());
+        assert!(!tmp.path().join("empty").exists());
+
+        // With `~` the direct entries are kept too, so depth 0 cleans nothing
+        fs::write(tmp.path().join("old"), b"").unwrap();
+        make_old(&tmp.path().join("old"));
+        clean_with("~mM:1d", 0);
+        assert!(tmp.path().join("old").exists());
+        assert!(tmp.path().join("top/old").exists());
+
+        // Depth 1 reaches the second level, but `nested_dir` still isn't descended into
+        clean_with("~mM:1d", 1);
+        assert!(tmp.path().join("old").exists());
+        assert!(!tmp.path().join("top/old").exists());
+        assert!(tmp.path().join("top/nested_dir/old").exists());
+
+        clean_with("~mM:1d", DEFAULT_MAX_DEPTH);
+        assert!(!tmp.path().join("top/nested_dir").exists());
+        assert!(tmp.path().join("top").is_dir());
+    }
+
+    #[test]
+    fn test_clean_ignored() {
+        let tmp = TempDir::new().unwrap();
+        for dir in ["cache/foo", "cache/bar/sub"] {
+            fs::create_dir_all(tmp.path().join(dir)).unwrap();
+        }
+        let paths = [
+            "cache/foo/old.bin",
+            "cache/bar/old.bin",
+            "cache/bar/sub/old.bin",
+            "cache/kept.bin",
+            "cache/old.bin",
+        ];
+        for path in paths {
+            fs::write(tmp.path().join(path), b"").unwrap();
+        }
+        for path in paths
+            .iter()
+            .chain(&["cache/foo", "cache/bar/sub", "cache/bar"])
+        {
+            make_old(&tmp.path().join(path));
+        }
+        let cache = tmp.path().join("cache");
+        clean(
+            &[
+                line(format!("d {} - - - mM:1d", cache.display())),
+                line(format!("X {}/foo", cache.display())),
+                line(format!("x {}/bar", cache.display())),
+                line(format!("X {}/kept.bin", cache.display())),
+            ],
+            &host(),
+            SystemTime::now(),
+        )
+        .unwrap();
+        // With X the directory survives, but not what's in it
+        assert!(cache.join("foo").is_dir());
+        assert!(!cache.join("foo/old.bin").exists());
+        // With x nothing below is touched
+        assert!(cache.join("bar/old.bin").exists());
+        assert!(cache.join("bar/sub/old.bin").exists());
+        assert!(cache.join("kept.bin").exists());
+        assert!(!cache.join("old.bin").exists());
+    }
+
+    #[test]
+    fn test_clean_d_without_age() {
+        let tmp = TempDir::new().unwrap();
+        fs::write(tmp.path().join("old"), b"").unwrap();
+        make_old(&tmp.path().join("old"));
+
+        clean(
+            &[line(format!("d {} - - - -", tmp.path().display()))],
+            &host(),
+            SystemTime::now(),
+        )
+        .unwrap();
+        assert!(tmp.path().join("old").exists());
+    }
+
+    #[test]
+    fn test_clean_injected_now() {
+        let tmp = TempDir::new().unwrap();
+        let epoch = SystemTime::UNIX_EPOCH;
+        for (name, secs) in [("a", 1000), ("b", 2000), ("c", 3000)] {
+            let path = tmp.path().join(name);
+            File::create(&path)
+                .unwrap()
+                .set_modified(epoch + Duration::from_secs(secs))
+                .unwrap();
+        }
+        let config = [line(format!("d {} - - - m:1000s", tmp.path().display()))];
+
+        // Exactly at the cutoff counts as used
+        clean(&config, &host(), epoch + Duration::from_secs(3000)).unwrap();
+        assert!(!tmp.path().join("a").exists());
+        assert!(tmp.path().join("b").exists());
+        clean(&config, &host(), epoch + Duration::from_secs(3500)).unwrap();
+        assert!(!tmp.path().join("b").exists());
+        assert!(tmp.path().join("c").exists());
+    }
+
+    #[test]
+    fn test_clean_symlink_swap() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path().join("dir");
+        let outside = tmp.path().join("outside");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::create_dir(&outside).unwrap();
+        for path in [dir.join("sub/old"), outside.join("old")] {
+            fs::write(&path, b"").unwrap();
+            make_old(&path);
+        }
+
+        // Swap sub for a symlink out of the tree right before its entry is removed
+        let mut context = host();
+        context.confirm = Some(Box::new({
+            let (dir, outside, moved) = (dir.clone(), outside.clone(), tmp.path().join("moved"));
+            move |path: &Path, _: &str| {
+                if path == dir.join("sub/old") {
+                    fs::rename(dir.join("sub"), &moved).unwrap();
+                    std::os::unix::fs::symlink(&outside, dir.join("sub")).unwrap();
+                }
+                true
+            }
+        }));
+        clean(
+            &[line(format!("d {} - - - mM:1d", dir.display()))],
+            &context,
+            SystemTime::now(),
+        )
+        .unwrap();
+        assert!(outside.join("old").exists());
+        assert!(!tmp.path().join("moved/old").exists());
+    }
+
+    #[test]
+    fn test_clean_upper_d_wipes_contents() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path().join("D");
+        fs::create_dir_all(dir.join("sub/nested")).unwrap();
+        fs::write(dir.join("sub/nested/file"), b"").unwrap();
+        fs::write(dir.join("new"), b"").unwrap();
+
+        clean(
+            &[line(format!("D {} - - - -", dir.display()))],
+            &host(),
+            SystemTime::now(),
+        )
+        .unwrap();
+        assert!(dir.is_dir());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_remove_upper_d_only() {
+        let tmp = TempDir::new().unwrap();
+        let lower = tmp.path().join("d");
+        let upper = tmp.path().join("D");
+        fs::create_dir_all(lower.join("sub")).unwrap();
+        fs::create_dir_all(upper.join("sub")).unwrap();
+
+        remove(
+            &[
+                line(format!("d {} - - - -", lower.display())),
+                line(format!("D {} - - - -", upper.display())),
+            ],
+            &host(),
+        )
+        .unwrap();
+        assert!(lower.join("sub").is_dir());
+        assert!(!upper.exists());
+    }
+
+    #[test]
+    fn test_root_and_specifiers() {
+        let tmp = TempDir::new().unwrap();
+        fs::create_dir_all(tmp.path().join("etc")).unwrap();
+        fs::write(tmp.path().join("etc/machine-id"), b"0123\n").unwrap();
+        fs::create_dir_all(tmp.path().join("run/0123/sub")).unwrap();
+
+        let target = Context::new(tmp.path().to_owned(), SpecifierSource::Target);
+        remove(&[line("D /run/%m/sub - - - -".to_owned())], &target).unwrap();
+        assert!(!tmp.path().join("run/0123/sub").exists());
+        assert!(tmp.path().join("run/0123").exists());
+    }
+
+    #[test]
+    fn test_explain_privileged() {
+        let path = Path::new("/etc/owned");
+        let denied = || io::Error::from_raw_os_error(libc::EPERM);
+        let message =
+            explain_privileged(denied(), "changing the owner of", path, "CAP_CHOWN", false)
+                .to_string();
+        assert!(
+            message.starts_with("changing the owner of /etc/owned requires root (or CAP_CHOWN)")
+        );
+        // As root the error is something else, like an immutable file, so it's passed on as is
+        let report = explain_privileged(denied(), "changing the owner of", path, "CAP_CHOWN", true);
+        assert!(report.downcast_ref::<io::Error>().is_some());
+        let missing = io::Error::from(io::ErrorKind::NotFound);
+        let report = explain_privileged(missing, "changing the owner of", path, "CAP_CHOWN", false);
+        assert!(report.downcast_ref::<io::Error>().is_some());
+    }
+
+    #[test]
+    fn test_adjust_tree() {
+        let tmp = TempDir::new().unwrap();
+        let tree = tmp.path().join("tree");
+        fs::create_dir_all(tree.join("sub")).unwrap();
+        fs::write(tree.join("sub/file"), b"").unwrap();
+        fs::write(tmp.path().join("outside"), b"").unwrap();
+        std::os::unix::fs::symlink("../outside", tree.join("link")).unwrap();
+        let recording = Rc::new(Recording::default());
+        let context = Context {
+            syscalls: Box::new(recording.clone()),
+            ..host()
+        };
+        create(
+            &[line(format!("Z {} 0750 1234 5678", tree.display()))],
+            &context,
+        )
+        .unwrap();
+
+        let calls = recording.calls.borrow();
+        for path in [tree.clone(), tree.join("sub"), tree.join("sub/file")] {
+            assert!(calls.contains(&Call::Chmod {
+                path: path.clone(),
+                mode: 0o750,
+            }));
+            assert!(calls.contains(&Call::Chown {
+                path,
+                uid: Some(1234),
+                gid: Some(5678),
+            }));
+        }
+        // The symlink isn't followed, nor changed itself
+        assert_eq!(calls.len(), 6);
+    }
+
+    #[test]
+    fn test_adjust_special_files() {
+        let tmp = TempDir::new().unwrap();
+        let socket = tmp.path().join("socket");
+        let unreadable = tmp.path().join("unreadable");
+        let _listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        fs::write(&unreadable, b"").unwrap();
+        fs::set_permissions(&unreadable, fs::Permissions::from_mode(0o000)).unwrap();
+
+        // Neither can be opened to read, which z doesn't need
+        create(
+            &[
+                line(format!("z {} 0600", socket.display())),
+                line(format!("z {} 0600", unreadable.display())),
+            ],
+            &host(),
+        )
+        .unwrap();
+        let mode = |path| fs::symlink_metadata(path).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode(&socket), 0o600);
+        assert_eq!(mode(&unreadable), 0o600);
+    }
+
+    #[test]
+    fn test_adjust_symlink() {
+        let tmp = TempDir::new().unwrap();
+        let target = tmp.path().join("target");
+        let link = tmp.path().join("link");
+        fs::write(&target, b"").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o644)).unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        let recording = Rc::new(Recording::default());
+        let config = [line(format!("z {} 0600 1234 5678", link.display()))];
+
+        let context = Context {
+            syscalls: Box::new(recording.clone()),
+            ..host()
+        };
+        create(&config, &context).unwrap();
+        assert!(recording.calls.borrow().is_empty());
+
+        let context = Context {
+            syscalls: Box::new(recording.clone()),
+            dereference: true,
+            ..host()
+        };
+        create(&config, &context).unwrap();
+        assert_eq!(
+            *recording.calls.borrow(),
+            [
+                Call::Chmod {
+                    path: link.clone(),
+                    mode: 0o600,
+                },
+                Call::Chown {
+                    path: link,
+                    uid: Some(1234),
+                    gid: Some(5678),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_repair() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path().join("dir");
+        let file = tmp.path().join("file");
+        let adjusted = tmp.path().join("adjusted");
+        let swapped = tmp.path().join("swapped");
+        fs::create_dir(&dir).unwrap();
+        for path in [&file, &adjusted, &swapped] {
+            fs::write(path, b"contents").unwrap();
+            fs::set_permissions(path, fs::Permissions::from_mode(0o666)).unwrap();
+        }
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777)).unwrap();
+        let config = [
+            line(format!("d {} 0750", dir.display())),
+            line(format!("F {} 0600 - - - new", file.display())),
+            line(format!("z {} 0640", adjusted.display())),
+            line(format!("d {} 0755", swapped.display())),
+            line(format!("f {}/missing 0600", tmp.path().display())),
+            line(format!(
+                "d {}/missing-dir 0700 - - 1s",
+                tmp.path().display()
+            )),
+        ];
+        let context = Context {
+            quiet: true,
+            ..host()
+        };
+        repair(&config, &context).unwrap();
+
+        let mode = |path: &Path| fs::symlink_metadata(path).unwrap().mode() & 0o7777;
+        assert_eq!(mode(&dir), 0o750);
+        assert_eq!(mode(&file), 0o600);
+        assert_eq!(mode(&adjusted), 0o640);
+        // Contents, types, and missing paths are left alone
+        assert_eq!(fs::read(&file).unwrap(), b"contents");
+        assert!(swapped.is_file());
+        assert_eq!(mode(&swapped), 0o666);
+        assert!(!tmp.path().join("missing").exists());
+        assert!(!tmp.path().join("missing-dir").exists());
+    }
+
+    #[test]
+    fn test_graceful() {
+        let tmp = TempDir::new().unwrap();
+        fs::create_dir(tmp.path().join("etc")).unwrap();
+        fs::write(tmp.path().join("etc/os-release"), b"ID=minimal\n").unwrap();
+        let config = [line("d /build/%B".to_owned()), line("d /os/%o".to_owned())];
+
+        let context = Context::new(tmp.path().to_owned(), SpecifierSource::Target);
+        let error = create(&config, &context).unwrap_err();
+        assert!(error.to_string().contains("BUILD_ID"));
+        assert!(!tmp.path().join("os").exists());
+
+        let context = Context {
+            graceful: true,
+            quiet: true,
+            ..Context::new(tmp.path().to_owned(), SpecifierSource::Target)
+        };
+        create(&config, &context).unwrap();
+        assert!(!tmp.path().join("build").exists());
+        assert!(tmp.path().join("os/minimal").is_dir());
+    }
+
+    #[test]
+    fn test_graceful_unknown_group() {
+        let tmp = TempDir::new().unwrap();
+        let config = [
+            line(format!(
+                "d {}/a - - no-such-group-here",
+                tmp.path().display()
+            )),
+            line(format!("d {}/b", tmp.path().display())),
+        ];
+
+        let error = create(&config, &host()).unwrap_err();
+        assert_eq!(error.to_string(), "unknown group no-such-group-here");
+        assert!(!tmp.path().join("a").exists());
+
+        let context = Context {
+            graceful: true,
+            quiet: true,
+            ..host()
+        };
+        let outcomes = create(&config, &context).unwrap();
+        assert_eq!(
+            outcomes[0].outcome.to_string(),
+            "skipped: unknown group no-such-group-here"
+        );
+        assert!(!tmp.path().join("a").exists());
+        assert!(tmp.path().join("b").is_dir());
+    }
+
+    #[test]
+    fn test_leading_runtime_dir() {
+        let tmp = TempDir::new().unwrap();
+        let context = Context::new(tmp.path().to_owned(), SpecifierSource::Host);
+        // %t may lead a path since it's an absolute directory, and that directory is /run
+        let path = context.line_path(&line("d %t/app".to_owned())).unwrap();
+        assert_eq!(path, tmp.path().join("run/app"));
+    }
+
+    #[test]
+    fn test_create_file_base64() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("thing");
+        create(
+            &[line(format!("f~ {} 0600 - - - AAEC/w==", path.display()))],
+            &host(),
+        )
+        .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), [0x00, 0x01, 0x02, 0xff]);
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o7777,
+            0o600
+        );
+
+        // Plain f leaves the contents of an existing file alone
+        create(
+            &[line(format!("f~ {} - - - - aGVsbG8=", path.display()))],
+            &host(),
+        )
+        .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), [0x00, 0x01, 0x02, 0xff]);
+    }
+
+    #[test]
+    fn test_write_file_base64() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("thing");
+        let missing = tmp.path().join("missing");
+        fs::write(&path, b"old contents").unwrap();
+        create(
+            &[
+                line(format!("w~ {} - - - - aGVsbG8=", path.display())),
+                line(format!("w+~ {} - - - - /w==", path.display())),
+                line(format!("w {} - - - - hello", missing.display())),
+            ],
+            &Context {
+                boot: true,
+                ..host()
+            },
+        )
+        .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"hello\xff");
+        assert!(!missing.exists());
+    }
+
+    #[test]
+    fn test_write_file_repeated() {
+        let tmp = TempDir::new().unwrap();
+        let overwritten = tmp.path().join("overwritten");
+        let appended = tmp.path().join("appended");
+        fs::write(&overwritten, b"old").unwrap();
+        fs::write(&appended, b"old").unwrap();
+        let config = [
+            line(format!("w {} - - - - new", overwritten.display())),
+            line(format!("w+ {} - - - - new", appended.display())),
+        ];
+
+        // Without --boot, applying again changes nothing
+        for _ in 0..2 {
+            create(&config, &host()).unwrap();
+            assert_eq!(fs::read(&overwritten).unwrap(), b"new");
+            assert_eq!(fs::read(&appended).unwrap(), b"old");
+        }
+
+        // At boot w+ appends on every run
+        let boot = Context {
+            boot: true,
+            ..host()
+        };
+        create(&config, &boot).unwrap();
+        create(&config, &boot).unwrap();
+        assert_eq!(fs::read(&overwritten).unwrap(), b"new");
+        assert_eq!(fs::read(&appended).unwrap(), b"oldnewnew");
+    }
+
+    #[test]
+    fn test_write_file_in_place() {
+        let tmp = TempDir::new().unwrap();
+        let file = tmp.path().join("file");
+        let hard_link = tmp.path().join("hard-link");
+        let symlink = tmp.path().join("symlink");
+        fs::write(&file, b"old").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o640)).unwrap();
+        fs::hard_link(&file, &hard_link).unwrap();
+        std::os::unix::fs::symlink(&file, &symlink).unwrap();
+        let inode = fs::metadata(&file).unwrap().ino();
+
+        create(
+            &[line(format!("w {} - - - - new", symlink.display()))],
+            &host(),
+        )
+        .unwrap();
+        // The symlink still leads to the same file, which every link to it sees written
+        assert!(fs::symlink_metadata(&symlink).unwrap().is_symlink());
+        let meta = fs::metadata(&file).unwrap();
+        assert_eq!(meta.ino(), inode);
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o640);
+        assert_eq!(fs::read(&hard_link).unwrap(), b"new");
+    }
+
+    #[cfg(target_os = "linux")]
+    fn xattr(path: &Path, name: &std::ffi::CStr) -> Option<Vec<u8>> {
+        use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let mut buffer = vec![0u8; 1024];
+        let len = unsafe {
+            libc::lgetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buffer.as_mut_ptr().cast(),
+                buffer.len(),
+            )
+        };
+        usize::try_from(len).ok().map(|len| buffer[..len].to_vec())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_acl_defaults_only_on_directories() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path().join("dir");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("file"), b"").unwrap();
+
+        create(
+            &[line(format!(
+                "A {} - - - - u:0:rw,d:u:0:rwx",
+                dir.display()
+            ))],
+            &host(),
+        )
+        .unwrap();
+        assert!(xattr(&dir, c"system.posix_acl_access").is_some());
+        assert!(xattr(&dir, c"system.posix_acl_default").is_some());
+        assert!(xattr(&dir.join("file"), c"system.posix_acl_access").is_some());
+        assert!(xattr(&dir.join("file"), c"system.posix_acl_default").is_none());
+
+        // Default entries on a file are skipped with a warning rather than failing
+        let file = tmp.path().join("file");
+        fs::write(&file, b"").unwrap();
+        create(
+            &[line(format!("a {} - - - - d:u:0:rwx", file.display()))],
+            &host(),
+        )
+        .unwrap();
+        assert!(xattr(&file, c"system.posix_acl_access").is_none());
+    }
+
+    #[test]
+    fn test_acl_recorded() {
+        let tmp = TempDir::new().unwrap();
+        let file = tmp.path().join("file");
+        fs::write(&file, b"").unwrap();
+        let recording = Rc::new(Recording::default());
+        let context = Context {
+            syscalls: Box::new(recording.clone()),
+            ..host()
+        };
+        create(
+            &[line(format!("a {} - - - - u:0:rw", file.display()))],
+            &context,
+        )
+        .unwrap();
+        let calls = recording.calls.borrow();
+        assert!(matches!(
+            &calls[..],
+            [Call::SetXattr { path, name, .. }]
+                if *path == file && name.as_c_str() == c"system.posix_acl_access"
+        ));
+    }
+
+    #[test]
+    fn test_new_project_quota() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path().join("cache");
+        let config = [
+            line(format!("Q {} 0755", dir.display())),
+            line(format!("q {}/app 0755", dir.display())),
+        ];
+        let context = Context {
+            quiet: true,
+            ..host()
+        };
+        // Anywhere else than btrfs these are directories, with a project quota where there are any
+        create(&config, &context).unwrap();
+        assert!(dir.join("app").is_dir());
+
+        let mut stat = std::mem::MaybeUninit::<libc::statfs>::uninit();
+        let path = std::ffi::CString::new(tmp.path().as_os_str().as_encoded_bytes()).unwrap();
+        assert_eq!(unsafe { libc::statfs(path.as_ptr(), stat.as_mut_ptr()) }, 0);
+        let on_xfs = unsafe { stat.assume_init() }.f_type as u32 == libc::XFS_SUPER_MAGIC as u32;
+        if !on_xfs || unsafe { libc::geteuid() } != 0 {
+            // Only XFS always has project quotas, and only root may set them up everywhere
+            return;
+        }
+        let project = |path: &Path| quota::get_project(&File::open(path).unwrap()).unwrap();
+        let inode = fs::metadata(&dir).unwrap().ino() as u32;
+        assert_eq!(
+            project(&dir),
+            Project {
+                id: inode,
+                inherit: true
+            }
+        );
+        assert_eq!(project(&dir.join("app")), project(&dir));
+        // Applying again keeps the project
+        create(&config, &context).unwrap();
+        assert_eq!(project(&dir).id, inode);
+    }
+
+    #[test]
+    fn test_subvolume_fallback() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path().join("var/lib/machines");
+        let context = Context {
+            quiet: true,
+            ..host()
+        };
+        let subvolume = line(format!("Q {} 0700", dir.display()));
+        // Off btrfs the ioctls are refused, which leaves a plain directory instead of a panic
+        create_subvolume(&dir, &subvolume, &context).unwrap();
+        let meta = fs::symlink_metadata(&dir).unwrap();
+        assert!(meta.is_dir());
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o700);
+        create_subvolume(&dir, &subvolume, &context).unwrap();
+
+        let file = tmp.path().join("file");
+        fs::write(&file, b"").unwrap();
+        assert!(create_subvolume(&file, &line(format!("v {}", file.display())), &context).is_err());
+        create_subvolume(&file, &line(format!("v= {}", file.display())), &context).unwrap();
+        assert!(file.is_dir());
+    }
+
+    #[test]
+    fn test_create_directory_keep_existing_owner() {
+        if unsafe { libc::geteuid() } != 0 {
+            // Changing ownership needs root
+            return;
+        }
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path().join("parent/dir");
+        let keep = [line(format!("d {} :0700 1234 1234", dir.display()))];
+        let force = [line(format!("d {} 0750 1234 1234", dir.display()))];
+
+        create(&keep, &host()).unwrap();
+        let meta = fs::metadata(&dir).unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (1234, 1234));
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o700);
+
+        // An existing directory keeps the owner an admin gave it
+        std::os::unix::fs::chown(&dir, Some(0), Some(0)).unwrap();
+        create(&keep, &host()).unwrap();
+        let meta = fs::metadata(&dir).unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (0, 0));
+
+        create(&force, &host()).unwrap();
+        let meta = fs::metadata(&dir).unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (1234, 1234));
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o750);
+    }
+
+    #[test]
+    fn test_copy_recreate() {
+        let tmp = TempDir::new().unwrap();
+        let source = tmp.path().join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("sub/new"), b"new").unwrap();
+        std::os::unix::fs::symlink("sub/new", source.join("link")).unwrap();
+        let target = tmp.path().join("target");
+        fs::create_dir_all(target.join("sub")).unwrap();
+        fs::write(target.join("sub/old"), b"old").unwrap();
+
+        create(
+            &[line(format!(
+                "C {} - - - - {}",
+                target.display(),
+                source.display()
+            ))],
+            &host(),
+        )
+        .unwrap();
+        assert!(target.join("sub/old").exists());
+        assert!(!target.join("sub/new").exists());
+        assert_eq!(
+            fs::read_link(target.join("link")).unwrap(),
+            Path::new("sub/new")
+        );
+
+        create(
+            &[line(format!(
+                "C+ {} - - - - {}",
+                target.display(),
+                source.display()
+            ))],
+            &host(),
+        )
+        .unwrap();
+        assert!(!target.join("sub/old").exists());
+        assert_eq!(fs::read(target.join("sub/new")).unwrap(), b"new");
+        assert_eq!(
+            fs::read_link(target.join("link")).unwrap(),
+            Path::new("sub/new")
+        );
+    }
+
+    #[test]
+    fn test_copy_into_directory() {
+        let tmp = TempDir::new().unwrap();
+        let source = tmp.path().join("skel");
+        fs::create_dir_all(source.join(".config")).unwrap();
+        fs::write(source.join(".config/app"), b"default").unwrap();
+        fs::write(source.join(".profile"), b"default").unwrap();
+        fs::write(source.join(".bashrc"), b"default").unwrap();
+        let target = tmp.path().join("home");
+        fs::create_dir(&target).unwrap();
+        fs::write(target.join(".bashrc"), b"edited").unwrap();
+        let config = [line(format!(
+            "C {} - - - - {}",
+            target.display(),
+            source.display()
+        ))];
+
+        create(&config, &host()).unwrap();
+        assert_eq!(fs::read(target.join(".bashrc")).unwrap(), b"edited");
+        assert_eq!(fs::read(target.join(".profile")).unwrap(), b"default");
+        assert_eq!(fs::read(target.join(".config/app")).unwrap(), b"default");
+
+        // Copying again leaves everything there alone, even what was copied and then changed
+        fs::write(target.join(".profile"), b"edited").unwrap();
+        fs::remove_file(target.join(".config/app")).unwrap();
+        create(&config, &host()).unwrap();
+        assert_eq!(fs::read(target.join(".profile")).unwrap(), b"edited");
+        assert!(!target.join(".config/app").exists());
+
+        // Only a directory is copied into
+        let file = tmp.path().join("file");
+        fs::write(&file, b"file").unwrap();
+        create(
+            &[line(format!(
+                "C {} - - - - {}",
+                file.display(),
+                source.display()
+            ))],
+            &host(),
+        )
+        .unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"file");
+    }
+
+    #[test]
+    fn test_copy_too_deep() {
+        let tmp = TempDir::new().unwrap();
+        let source = tmp.path().join("source");
+        let target = tmp.path().join("target");
+        fs::create_dir_all(source.join("a/b")).unwrap();
+        let context = Context {
+            max_depth: 2,
+            ..host()
+        };
+        let config = [line(format!(
+            "C {} - - - - {}",
+            target.display(),
+            source.display()
+        ))];
+
+        // The source and two directories below it
+        let error = create(&config, &context).unwrap_err();
+        assert!(error.to_string().contains("nested too deeply"));
+        // Copying into the directory already there goes no deeper
+        fs::remove_dir_all(target.join("a")).unwrap();
+        let error = create(&config, &context).unwrap_err();
+        assert!(error.to_string().contains("nested too deeply"));
+
+        fs::remove_dir_all(&target).unwrap();
+        let context = Context {
+            max_depth: 3,
+            ..host()
+        };
+        create(&config, &context).unwrap();
+        assert!(target.join("a/b").is_dir());
+    }
+
+    #[test]
+    fn test_missing_factory_default() {
+        let root = TempDir::new().unwrap();
+        let context = Context::new(root.path().to_owned(), SpecifierSource::Host);
+        let config = [line("C /etc/foo".to_owned()), line("L /etc/bar".to_owned())];
+        create(&config, &context).unwrap();
+        assert!(!root.path().join("etc/foo").exists());
+        assert!(fs::symlink_metadata(root.path().join("etc/bar")).is_err());
+
+        let factory = root.path().join("usr/share/factory/etc");
+        fs::create_dir_all(&factory).unwrap();
+        fs::write(factory.join("foo"), b"factory").unwrap();
+        fs::write(factory.join("bar"), b"factory").unwrap();
+        create(&config, &context).unwrap();
+        assert_eq!(fs::read(root.path().join("etc/foo")).unwrap(), b"factory");
+        assert_eq!(
+            fs::read_link(root.path().join("etc/bar")).unwrap(),
+            Path::new("/usr/share/factory/etc/bar")
+        );
+    }
+
+    #[test]
+    fn test_directory_replaces_mismatch() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("dir");
+        fs::write(&path, b"file").unwrap();
+        let error = create(&[line(format!("d {}", path.display()))], &host()).unwrap_err();
+        assert!(error.to_string().ends_with("exists and is not a directory"));
+        assert!(path.is_file());
+
+        create(&[line(format!("d= {} 0750", path.display()))], &host()).unwrap();
+        let meta = fs::symlink_metadata(&path).unwrap();
+        assert!(meta.is_dir());
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o750);
+        // An existing directory stays as it is
+        fs::write(path.join("kept"), b"").unwrap();
+        create(&[line(format!("d= {} 0750", path.display()))], &host()).unwrap();
+        assert!(path.join("kept").exists());
+    }
+
+    #[test]
+    fn test_force_replaces_mismatch() {
+        let tmp = TempDir::new().unwrap();
+        let path = |name: &str| tmp.path().join(name);
+        fs::create_dir_all(path("file/child")).unwrap();
+        for name in ["fifo", "link", "device", "copy"] {
+            fs::write(path(name), b"").unwrap();
+        }
+        std::os::unix::fs::symlink("/elsewhere", path("other-link")).unwrap();
+        fs::create_dir(path("source")).unwrap();
+        fs::write(path("source/inner"), b"copied").unwrap();
+        fs::create_dir(path("kept-copy")).unwrap();
+
+        let config = [
+            line(format!("f= {}", path("file").display())),
+            line(format!("p= {}", path("fifo").display())),
+            line(format!("L= {} - - - - /target", path("link").display())),
+            line(format!(
+                "L= {} - - - - /target",
+                path("other-link").display()
+            )),
+            line(format!(
+                "C= {} - - - - {}",
+                path("copy").display(),
+                path("source").display()
+            )),
+            line(format!(
+                "C= {} - - - - {}",
+                path("kept-copy").display(),
+                path("source").display()
+            )),
+        ];
+        // Without = each of them refuses or leaves the object in the way alone
+        for line in &config {
+            let mut line = line.clone();
+            line.line_type.data.force = false;
+            let _ = create(&[line], &host());
+        }
+        assert!(path("file").is_dir());
+        assert!(path("fifo").is_file());
+        assert!(path("link").is_file());
+        assert!(path("copy").is_file());
+
+        create(&config, &host()).unwrap();
+        assert!(path("file").is_file());
+        assert!(fs::symlink_metadata(path("fifo"))
+            .unwrap()
+            .file_type()
+            .is_fifo());
+        assert_eq!(fs::read_link(path("link")).unwrap(), Path::new("/target"));
+        // A symlink is the right type, wherever it points
+        assert_eq!(
+            fs::read_link(path("other-link")).unwrap(),
+            Path::new("/elsewhere")
+        );
+        assert_eq!(fs::read(path("copy/inner")).unwrap(), b"copied");
+        // A directory is the right type too, and only gets what it's missing
+        assert_eq!(fs::read(path("kept-copy/inner")).unwrap(), b"copied");
+
+        if unsafe { libc::geteuid() } != 0 {
+            // Creating a device needs root
+            return;
+        }
+        create(
+            &[line(format!("c= {} - - - - 1:3", path("device").display()))],
+            &host(),
+        )
+        .unwrap();
+        assert!(fs::symlink_metadata(path("device"))
+            .unwrap()
+            .file_type()
+            .is_char_device());
+    }
+
+    #[test]
+    fn test_force_replaces_parents() {
+        let tmp = TempDir::new().unwrap();
+        let fifo = tmp.path().join("fifo");
+        let dangling = tmp.path().join("dangling");
+        create(&[line(format!("p {}", fifo.display()))], &host()).unwrap();
+        std::os::unix::fs::symlink(tmp.path().join("nowhere"), &dangling).unwrap();
+
+        let config = [
+            line(format!("d {}/sub", fifo.display())),
+            line(format!("p {}/sub", dangling.display())),
+        ];
+        for line in &config {
+            assert!(create(std::slice::from_ref(line), &host()).is_err());
+        }
+        let config = [
+            line(format!("d= {}/sub", fifo.display())),
+            line(format!("p= {}/sub", dangling.display())),
+        ];
+        create(&config, &host()).unwrap();
+        assert!(fifo.join("sub").is_dir());
+        assert!(fs::symlink_metadata(dangling.join("sub"))
+            .unwrap()
+            .file_type()
+            .is_fifo());
+    }
+
+    #[test]
+    fn test_noerror() {
+        let tmp = TempDir::new().unwrap();
+        let source = tmp.path().join("source");
+        let blocker = tmp.path().join("blocker");
+        fs::write(&source, b"").unwrap();
+        fs::write(&blocker, b"").unwrap();
+        // Nothing can be created inside a regular file, so every one of these fails
+        let inside = blocker.join("sub").join("path");
+        let inside = inside.display();
+        for (modified, argument) in [
+            ("f", ""),
+            ("F", ""),
+            ("w", "text"),
+            ("d", ""),
+            ("D", ""),
+            ("e", ""),
+            ("v", ""),
+            ("q", ""),
+            ("Q", ""),
+            ("p", ""),
+            ("L", "/target"),
+            ("c", "1:3"),
+            ("b", "7:0"),
+            ("C", &*source.to_string_lossy()),
+        ] {
+            let failing = line(format!("{modified} {inside} - - - - {argument}"));
+            assert!(
+                create(std::slice::from_ref(&failing), &host()).is_err(),
+                "{failing}"
+            );
+            let allowed = line(format!("{modified}- {inside} - - - - {argument}"));
+            let outcomes = create(std::slice::from_ref(&allowed), &host()).unwrap();
+            assert!(
+                !matches!(outcomes[0].outcome, Outcome::Failed(_)),
+                "{allowed}"
+            );
+        }
+        assert!(blocker.is_file());
+    }
+
+    #[test]
+    fn test_copy_source_rerooting() {
+        let host_dir = TempDir::new().unwrap();
+        fs::write(host_dir.path().join("file"), b"host").unwrap();
+        let root = TempDir::new().unwrap();
+        let rooted_source = root.path().join(host_dir.path().strip_prefix("/").unwrap());
+        fs::create_dir_all(&rooted_source).unwrap();
+        fs::write(rooted_source.join("file"), b"root").unwrap();
+        fs::create_dir_all(root.path().join("usr/share/factory/etc")).unwrap();
+        fs::write(
+            root.path().join("usr/share/factory/etc/default"),
+            b"factory",
+        )
+        .unwrap();
+
+        let mut context = Context::new(root.path().to_owned(), SpecifierSource::Host);
+        let config = [
+            line(format!(
+                "C /etc/copied - - - - {}/file",
+                host_dir.path().display()
+            )),
+            line("C /etc/default".to_owned()),
+            // Symlink targets stay as written
+            line(format!("L /etc/link - - - - {}", host_dir.path().display())),
+        ];
+        create(&config, &context).unwrap();
+        assert_eq!(fs::read(root.path().join("etc/copied")).unwrap(), b"root");
+        assert_eq!(
+            fs::read(root.path().join("etc/default")).unwrap(),
+            b"factory"
+        );
+        assert_eq!(
+            fs::read_link(root.path().join("etc/link")).unwrap(),
+            host_dir.path()
+        );
+
+        fs::remove_file(root.path().join("etc/copied")).unwrap();
+        context.copy_from_host = true;
+        create(&config[..1], &context).unwrap();
+        assert_eq!(fs::read(root.path().join("etc/copied")).unwrap(), b"host");
+    }
+
+    #[test]
+    fn test_copy_source_specifiers() {
+        let root = TempDir::new().unwrap();
+        fs::create_dir(root.path().join("source")).unwrap();
+        let source = root.path().join("source/100%");
+        fs::write(&source, b"copied").unwrap();
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o600)).unwrap();
+        let context = Context::new(root.path().to_owned(), SpecifierSource::Host);
+
+        // The source is expanded and moved below the root like the path, and the copy gets the
+        // mode on the line, whether it's new or was already there
+        let config = [line("C /etc/copied 0640 - - - /source/100%%".to_owned())];
+        let mode = || {
+            fs::metadata(root.path().join("etc/copied"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777
+        };
+        create(&config, &context).unwrap();
+        assert_eq!(fs::read(root.path().join("etc/copied")).unwrap(), b"copied");
+        assert_eq!(mode(), 0o640);
+        fs::set_permissions(
+            root.path().join("etc/copied"),
+            fs::Permissions::from_mode(0o600),
+        )
+        .unwrap();
+        create(&config, &context).unwrap();
+        assert_eq!(mode(), 0o640);
+    }
+
+    #[test]
+    fn test_create_directory_special_bits() {
+        let tmp = TempDir::new().unwrap();
+        let sticky = tmp.path().join("sticky");
+        let setgid = tmp.path().join("setgid");
+        create(
+            &[
+                line(format!("d {} 1777", sticky.display())),
+                line(format!("d {} 2755", setgid.display())),
+            ],
+            &host(),
+        )
+        .unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode(&sticky), 0o1777);
+        assert_eq!(mode(&setgid), 0o2755);
+    }
+
+    #[test]
+    fn test_finalize_node_setuid() {
+        let tmp = TempDir::new().unwrap();
+        let file = tmp.path().join("file");
+        let fifo = tmp.path().join("fifo");
+        fs::write(&fifo, b"").unwrap();
+        // Changing the owner, even to the same one, would drop the setuid bit if set first
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        create(
+            &[
+                line(format!("f {} 4755 {uid} {gid}", file.display())),
+                line(format!("p+ {} 0640 {uid} {gid}", fifo.display())),
+            ],
+            &host(),
+        )
+        .unwrap();
+        let meta = |path: &Path| fs::symlink_metadata(path).unwrap();
+        assert_eq!(meta(&file).permissions().mode() & 0o7777, 0o4755);
+        assert!(meta(&fifo).file_type().is_fifo());
+        assert_eq!(meta(&fifo).permissions().mode() & 0o7777, 0o640);
+    }
+
+    #[test]
+    fn test_default_owner() {
+        let tmp = TempDir::new().unwrap();
+        let existing = tmp.path().join("existing");
+        fs::write(&existing, b"").unwrap();
+        let recording = Rc::new(Recording::default());
+        let context = Context {
+            syscalls: Box::new(recording.clone()),
+            default_owner: (1234, 5678),
+            ..host()
+        };
+        create(
+            &[
+                line(format!("f {}/new", tmp.path().display())),
+                line(format!("f {}/owned - 42", tmp.path().display())),
+                line(format!("z {} 0600", existing.display())),
+            ],
+            &context,
+        )
+        .unwrap();
+
+        let chowns = recording
+            .calls
+            .borrow()
+            .iter()
+            .filter_map(|call| match call {
+                Call::Chown { path, uid, gid } => Some((path.clone(), *uid, *gid)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // Only created files get the default, an existing one keeps its owner
+        assert_eq!(
+            chowns,
+            [
+                (tmp.path().join("new"), Some(1234), Some(5678)),
+                (tmp.path().join("owned"), Some(42), Some(5678)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mixed_owner_names_and_ids() {
+        let tmp = TempDir::new().unwrap();
+        let (a, b) = (tmp.path().join("a"), tmp.path().join("b"));
+        fs::write(&a, b"").unwrap();
+        fs::write(&b, b"").unwrap();
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        // Not every system has a group called root
+        let group = String::from_utf8(users::group_name_by_gid(gid).unwrap()).unwrap();
+        let recording = Rc::new(Recording::default());
+        let context = Context {
+            syscalls: Box::new(recording.clone()),
+            ..host()
+        };
+        create(
+            &[
+                line(format!("z {} 0644 root 4242", a.display())),
+                line(format!("z {} 0644 4242 {group}", b.display())),
+            ],
+            &context,
+        )
+        .unwrap();
+
+        let chowns = recording
+            .calls
+            .borrow()
+            .iter()
+            .filter_map(|call| match call {
+                Call::Chown { path, uid, gid } => Some((path.clone(), *uid, *gid)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // Names are looked up and ids used as they are, ids that already match are left out
+        assert_eq!(
+            chowns,
+            [
+                (a, (uid != 0).then_some(0), Some(4242)),
+                (b, Some(4242), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expand_owners() {
+        let mut line = line("d /tmp/x - %U %G".to_owned());
+        host().expand_owners(&mut line).unwrap();
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        assert_eq!(line.owner.data, Some(FileOwner::Id(uid)));
+        assert_eq!(line.group.data, Some(FileOwner::Id(gid)));
+
+        let mut line = self::line("z /run/user 0700 %u %g -".to_owned());
+        assert!(matches!(line.owner.data, Some(FileOwner::Specifiers(_))));
+        host().expand_owners(&mut line).unwrap();
+        let name = |owner: Option<FileOwner>| match owner {
+            Some(FileOwner::Name(name)) => crate::users::uid_by_name(&name).unwrap(),
+            owner => panic!("expected a name, got {owner:?}"),
+        };
+        assert_eq!(name(line.owner.data), uid);
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mtimes = || {
+            let tmp = TempDir::new().unwrap();
+            let context = Context {
+                timestamp: Some(timestamp),
+                ..Context::new(tmp.path().to_owned(), SpecifierSource::Host)
+            };
+            let config = [
+                line("f /new - - - - contents".to_owned()),
+                line("f /empty".to_owned()),
+                line("d /dir".to_owned()),
+                line("p /fifo".to_owned()),
+                line("w /empty - - - - written".to_owned()),
+            ];
+            create(&config, &context).unwrap();
+            ["new", "empty", "dir", "fifo"].map(|name| {
+                let meta = fs::symlink_metadata(tmp.path().join(name)).unwrap();
+                (meta.modified().unwrap(), meta.accessed().unwrap())
+            })
+        };
+        let first = mtimes();
+        assert_eq!(first, mtimes());
+        assert!(first.iter().all(|&times| times == (timestamp, timestamp)));
+    }
+
+    #[test]
+    fn test_copy_file() {
+        let tmp = TempDir::new().unwrap();
+        let source = tmp.path().join("source");
+        let contents = (0..=255).cycle().take(1 << 16).collect::<Vec<u8>>();
+        fs::write(&source, &contents).unwrap();
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o640)).unwrap();
+
+        // Whether the filesystem of the temporary directory can clone or not, the copy is the same
+        let cloned = reflink(
+            &File::open(&source).unwrap(),
+            &File::create(tmp.path().join("clone")).unwrap(),
+        );
+        if cloned.is_ok() {
+            assert_eq!(fs::read(tmp.path().join("clone")).unwrap(), contents);
+        }
+        let target = tmp.path().join("target");
+        copy_file(&source, &target, &fs::metadata(&source).unwrap()).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), contents);
+        assert_eq!(fs::metadata(&target).unwrap().mode() & 0o7777, 0o640);
+    }
+
+    #[test]
+    fn test_set_attrs() {
+        let tmp = TempDir::new().unwrap();
+        let file = tmp.path().join("file");
+        fs::write(&file, b"").unwrap();
+        let recording = Rc::new(Recording::default());
+        // Append only and no atime updates
+        recording.flags.borrow_mut().insert(file.clone(), 0xa0);
+        let context = Context {
+            syscalls: Box::new(recording.clone()),
+            ..host()
+        };
+        let flags = |argument: &str| {
+            create(
+                &[line(format!("h {} - - - - {argument}", file.display()))],
+                &context,
+            )
+            .unwrap();
+            recording.flags.borrow()[&file]
+        };
+
+        assert_eq!(flags("+i"), 0xb0);
+        assert_eq!(flags("-a"), 0x90);
+        // Flags we don't know about are left alone, even with `=`
+        recording
+            .flags
+            .borrow_mut()
+            .insert(file.clone(), 0x1000_0090);
+        assert_eq!(flags("=a"), 0x1000_0020);
+        // Nothing changes, so nothing is written
+        let calls = recording.calls.borrow().len();
+        assert_eq!(flags("+a"), 0x1000_0020);
+        assert_eq!(recording.calls.borrow().len(), calls);
+    }
+
+    #[test]
+    fn test_unsupported_attrs() {
+        let tmp = TempDir::new().unwrap();
+        let file = tmp.path().join("file");
+        fs::write(&file, b"").unwrap();
+        let context = Context {
+            quiet: true,
+            ..host()
+        };
+        // Not every filesystem has every flag, ext4 has no C and tmpfs none at all, which is only
+        // worth a warning
+        create(
+            &[line(format!("h {} - - - - +C", file.display()))],
+            &context,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_boot_only_device() {
+        let tmp = TempDir::new().unwrap();
+        let device = tmp.path().join("dev/null");
+        let file = tmp.path().join("file");
+        let recording = Rc::new(Recording::default());
+        let context = Context {
+            syscalls: Box::new(recording.clone()),
+            ..host()
+        };
+        let config = [
+            line(format!("c! {} 0666 - - - 1:3", device.display())),
+            line(format!("f {}", file.display())),
+        ];
+        create(&config, &context).unwrap();
+        // Without --boot the device is skipped, while the lines after it still apply
+        assert!(!recording
+            .calls
+            .borrow()
+            .iter()
+            .any(|call| matches!(call, Call::Mknod { .. })));
+        assert!(!device.exists());
+        assert!(file.exists());
+    }
+
+    #[test]
+    fn test_boot_only_remove() {
+        let tmp = TempDir::new().unwrap();
+        let stale = tmp.path().join("stale");
+        let lock = tmp.path().join("group.lock");
+        let full = tmp.path().join("full");
+        fs::create_dir_all(stale.join("sub")).unwrap();
+        fs::write(stale.join("sub/file"), b"").unwrap();
+        fs::write(&lock, b"").unwrap();
+        fs::create_dir_all(full.join("sub")).unwrap();
+        let config = [
+            line(format!("R! {}", stale.display())),
+            line(format!("r! {}", lock.display())),
+            line(format!("r {}", full.display())),
+        ];
+
+        let outcomes = remove(&config, &host()).unwrap();
+        let outcomes = outcomes
+            .iter()
+            .map(|outcome| outcome.outcome.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            outcomes,
+            [
+                "skipped: only applied at boot",
+                "skipped: only applied at boot",
+                "unchanged"
+            ]
+        );
+        assert!(stale.join("sub/file").exists());
+        assert!(lock.exists());
+
+        let context = Context {
+            boot: true,
+            ..host()
+        };
+        remove(&config, &context).unwrap();
+        assert!(!stale.exists());
+        assert!(!lock.exists());
+        // r leaves directories that aren't empty alone
+        assert!(full.join("sub").exists());
+    }
+
+    #[test]
+    fn test_clean_up_directory() {
+        let tmp = TempDir::new().unwrap();
+        let missing = tmp.path().join("missing");
+        let dir = tmp.path().join("dir");
+        let boot_only = tmp.path().join("boot");
+        for dir in [&dir, &boot_only] {
+            fs::create_dir(dir).unwrap();
+            fs::set_permissions(dir, fs::Permissions::from_mode(0o755)).unwrap();
+            fs::write(dir.join("old"), b"").unwrap();
+            fs::write(dir.join("new"), b"").unwrap();
+            make_old(&dir.join("old"));
+        }
+        let config = [
+            line(format!("e {} 0700 - - mM:1d", missing.display())),
+            line(format!("e {} 0700 - - mM:1d", dir.display())),
+            line(format!("e! {} 0700 - - mM:1d", boot_only.display())),
+        ];
+        let mode = |path: &Path| fs::metadata(path).unwrap().mode() & 0o7777;
+
+        // Creating only adjusts what's there, and leaves the contents alone
+        create(&config, &host()).unwrap();
+        assert!(!missing.exists());
+        assert_eq!(mode(&dir), 0o700);
+        assert!(dir.join("old").exists());
+        assert_eq!(mode(&boot_only), 0o755);
+
+        // Cleaning removes what's aged, and still creates nothing
+        clean(&config, &host(), SystemTime::now()).unwrap();
+        assert!(!missing.exists());
+        assert!(!dir.join("old").exists());
+        assert!(dir.join("new").exists());
+        assert!(boot_only.join("old").exists());
+
+        // At boot the e! line is applied as well
+        let boot = Context {
+            boot: true,
+            ..host()
+        };
+        create(&config, &boot).unwrap();
+        clean(&config, &boot, SystemTime::now()).unwrap();
+        assert!(!missing.exists());
+        assert_eq!(mode(&boot_only), 0o700);
+        assert!(!boot_only.join("old").exists());
+        assert!(boot_only.join("new").exists());
+    }
+
+    #[test]
+    fn test_remove_ignored() {
+        let tmp = TempDir::new().unwrap();
+        let tree = tmp.path().join("tree");
+        fs::create_dir_all(tree.join("kept/sub")).unwrap();
+        fs::write(tree.join("kept/sub/file"), b"").unwrap();
+        fs::create_dir_all(tree.join("own/sub")).unwrap();
+        fs::write(tree.join("own/file"), b"").unwrap();
+        fs::write(tree.join("gone"), b"").unwrap();
+        let lock = tmp.path().join("lock");
+        fs::write(&lock, b"").unwrap();
+        let config = [
+            line(format!("x {}/kept", tree.display())),
+            line(format!("X {}/own", tree.display())),
+            line(format!("X {}", lock.display())),
+            line(format!("R {}", tree.display())),
+            line(format!("r {}", lock.display())),
+        ];
+
+        // Create skips every one of these lines
+        create(&config, &host()).unwrap();
+        remove(&config, &host()).unwrap();
+        assert!(tree.join("kept/sub/file").exists());
+        // X keeps the directory itself, but not what's in it
+        assert!(tree.join("own").is_dir());
+        assert!(!tree.join("own/file").exists());
+        assert!(!tree.join("own/sub").exists());
+        assert!(!tree.join("gone").exists());
+        assert!(lock.exists());
+
+        // D's contents are kept the same way when cleaning
+        let dir = tmp.path().join("d");
+        fs::create_dir_all(dir.join("kept")).unwrap();
+        fs::write(dir.join("gone"), b"").unwrap();
+        let config = [
+            line(format!("D {}", dir.display())),
+            line(format!("x {}/kept", dir.display())),
+        ];
+        clean(&config, &host(), SystemTime::now()).unwrap();
+        assert!(dir.join("kept").is_dir());
+        assert!(!dir.join("gone").exists());
+        remove(&config, &host()).unwrap();
+        assert!(dir.join("kept").is_dir());
+    }
+
+    #[test]
+    fn test_credential_argument() {
+        let tmp = TempDir::new().unwrap();
+        let credentials = tmp.path().join("credentials");
+        fs::create_dir(&credentials).unwrap();
+        fs::write(credentials.join("motd"), b"hello\n").unwrap();
+        let file = tmp.path().join("motd");
+        let unset = tmp.path().join("unset");
+        let config = [
+            line(format!("f^ {} - - - - motd", file.display())),
+            line(format!("f^ {} - - - - missing", unset.display())),
+        ];
+
+        // Without credentials, the lines are skipped
+        create(&config, &host()).unwrap();
+        assert!(!file.exists());
+
+        let context = Context {
+            credentials: Some(credentials.clone()),
+            ..host()
+        };
+        create(&config, &context).unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"hello\n");
+        assert!(!unset.exists());
+
+        fs::write(&file, b"old").unwrap();
+        create(
+            &[line(format!("w^ {} - - - - motd", file.display()))],
+            &context,
+        )
+        .unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"hello\n");
+
+        let error = create(
+            &[line(format!("f^ {} - - - - ../motd", file.display()))],
+            &context,
+        )
+        .unwrap_err();
+        assert!(error.to_string().starts_with("Invalid credential name"));
+    }
+
+    #[test]
+    fn test_create_skips_removal() {
+        let tmp = TempDir::new().unwrap();
+        let stale = tmp.path().join("stale");
+        let lock = tmp.path().join("lock");
+        fs::create_dir_all(stale.join("sub")).unwrap();
+        fs::write(&lock, b"").unwrap();
+        let dir = tmp.path().join("dir");
+        let config = [
+            line(format!("R {}", stale.display())),
+            line(format!("d {} 0755", dir.display())),
+            line(format!("r {}", lock.display())),
+        ];
+
+        create(&config, &host()).unwrap();
+        assert!(dir.is_dir());
+        assert!(stale.join("sub").exists());
+        assert!(lock.exists());
+
+        remove(&config, &host()).unwrap();
+        assert!(!stale.exists());
+        assert!(!lock.exists());
+        assert!(dir.is_dir());
+    }
+
+    #[test]
+    fn test_keep_going() {
+        let tmp = TempDir::new().unwrap();
+        let blocker = tmp.path().join("blocker");
+        let after = tmp.path().join("after");
+        fs::write(&blocker, b"").unwrap();
+        // Can't create a directory inside a regular file
+        let config = [
+            line(format!("d {}/sub", blocker.display())),
+            line(format!("d {}", after.display())),
+        ];
+
+        assert!(create(&config, &host()).is_err());
+        assert!(!after.exists());
+
+        let context = Context {
+            keep_going: true,
+            ..host()
+        };
+        let outcomes = create(&config, &context).unwrap();
+        assert!(after.is_dir());
+        assert!(matches!(outcomes[0].outcome, Outcome::Failed(_)));
+        assert!(matches!(outcomes[1].outcome, Outcome::Created));
+    }
+
+    #[test]
+    fn test_safe_roots() {
+        let tmp = TempDir::new().unwrap();
+        let safe = tmp.path().join("safe");
+        let outside = tmp.path().join("outside");
+        for dir in [safe.join("inner"), outside.clone()] {
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("file"), b"").unwrap();
+        }
+        let context = Context {
+            safe_roots: vec![safe.clone()],
+            ..host()
+        };
+
+        // The path only leaves the safe root once `..` is taken into account
+        let escaping = line(format!("D {}/../outside", safe.display()));
+        let error = remove(&[escaping], &context).unwrap_err();
+        assert!(error.to_string().starts_with(&format!(
+            "Refusing to delete anything in {}, which is outside of the safe roots",
+            outside.display()
+        )));
+        assert!(outside.join("file").exists());
+
+        remove(&[line(format!("D {}/inner", safe.display()))], &context).unwrap();
+        assert!(!safe.join("inner").exists());
+    }
+
+    #[test]
+    fn test_root_path() {
+        let tmp = TempDir::new().unwrap();
+        let error = remove(&[line("R /".to_owned())], &host()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Refusing to apply R / to the root directory, pass --allow-root-path if that's \
+             intended"
+        );
+        // Below another root, its top is protected just the same, however the path is written
+        let context = Context::new(tmp.path().to_owned(), SpecifierSource::Host);
+        assert!(create(&[line("Z /. 0700".to_owned())], &context).is_err());
+
+        let context = Context {
+            allow_root_path: true,
+            ..context
+        };
+        create(&[line("Z /. 0700".to_owned())], &context).unwrap();
+        assert_eq!(
+            fs::metadata(tmp.path()).unwrap().permissions().mode() & 0o7777,
+            0o700
+        );
+    }
+
+    #[test]
+    fn test_outcomes() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path().join("dir");
+        let blocker = tmp.path().join("blocker");
+        fs::write(&blocker, b"").unwrap();
+        let context = Context {
+            keep_going: true,
+            ..host()
+        };
+        let outcomes = |config: &[Line]| {
+            create(config, &context)
+                .unwrap()
+                .into_iter()
+                .map(|line| line.outcome.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let config = [
+            line(format!("d {} 0755", dir.display())),
+            line(format!("f! {}/file", dir.display())),
+            line(format!("d {}/sub", blocker.display())),
+        ];
+        let first = outcomes(&config);
+        assert_eq!(first[..2], ["created", "skipped: only applied at boot"]);
+        assert!(first[2].starts_with("failed: "));
+        assert_eq!(outcomes(&config[..1]), ["unchanged"]);
+        assert_eq!(
+            outcomes(&[line(format!("d {} 0700", dir.display()))]),
+            ["modified"]
+        );
+
+        // The span covers the whole line
+        let text = format!("d {} 0700", dir.display());
+        let outcome = &create(&[line(text.clone())], &context).unwrap()[0];
+        assert_eq!((outcome.pass, outcome.action), ("create", 'd'));
+        assert_eq!(outcome.characters, 0..text.len());
+    }
+
+    #[test]
+    fn test_replace_file_failure() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("config");
+        fs::write(&path, b"original").unwrap();
+
+        let error = replace_file(&path, b"partial", |_| Err(eyre::eyre!("simulated failure")));
+        assert!(error.is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"original");
+        // The temporary file is gone too
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 1);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        create(
+            &[line(format!("F {} - - - - replaced", path.display()))],
+            &host(),
+        )
+        .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"replaced");
+        // Without a mode on the line, the replaced file's mode is kept
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o7777,
+            0o640
+        );
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_truncate_file() {
+        let tmp = TempDir::new().unwrap();
+        let upper = tmp.path().join("upper");
+        let plus = tmp.path().join("plus");
+        let plain = tmp.path().join("plain");
+        let missing = tmp.path().join("missing");
+        for path in [&upper, &plus, &plain] {
+            fs::write(path, b"old contents").unwrap();
+        }
+        // Truncating happens on every run, not just at boot
+        create(
+            &[
+                line(format!("F {}", upper.display())),
+                line(format!("f+ {}", plus.display())),
+                line(format!("f {}", plain.display())),
+                line(format!("F {} - - - - new", missing.display())),
+            ],
+            &host(),
+        )
+        .unwrap();
+        assert_eq!(fs::read(&upper).unwrap(), b"");
+        assert_eq!(fs::read(&plus).unwrap(), b"");
+        assert_eq!(fs::read(&plain).unwrap(), b"old contents");
+        assert_eq!(fs::read(&missing).unwrap(), b"new");
+    }
+
+    #[test]
+    fn test_recorded_syscalls() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path().join("dir");
+        let link = tmp.path().join("link");
+        let recording = Rc::new(Recording::default());
+        let context = Context {
+            syscalls: Box::new(Rc::clone(&recording)),
+            ..host()
+        };
+        // Ownership changes are only recorded, so this works without root
+        create(
+            &[
+                line(format!("d {} 0750 1234 5678", dir.display())),
+                line(format!("L {} - - - - /target", link.display())),
+            ],
+            &context,
+        )
+        .unwrap();
+        assert_eq!(
+            *recording.calls.borrow(),
+            [
+                Call::Chmod {
+                    path: dir.clone(),
+                    mode: 0o750
+                },
+                Call::Chown {
+                    path: dir,
+                    uid: Some(1234),
+                    gid: Some(5678)
+                },
+                Call::Symlink {
+                    target: "/target".into(),
+                    link
+                },
+            ]
+        );
+
+        // A call set up to fail is still recorded, and fails the line
+        let denied = tmp.path().join("denied");
+        recording
+            .errors
+            .borrow_mut()
+            .insert(denied.clone(), io::ErrorKind::PermissionDenied);
+        let config = [line(format!("d {} 0750", denied.display()))];
+        assert!(create(&config, &context).is_err());
+        assert_eq!(
+            recording.calls.borrow().last(),
+            Some(&Call::Chmod {
+                path: denied,
+                mode: 0o750
+            })
+        );
+    }
+
+    #[test]
+    fn test_e_never_creates() {
+        let tmp = TempDir::new().unwrap();
+        let missing = tmp.path().join("missing");
+        let existing = tmp.path().join("existing");
+        fs::create_dir(&existing).unwrap();
+        fs::set_permissions(&existing, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(existing.join("old"), b"").unwrap();
+        make_old(&existing.join("old"));
+
+        let config = [
+            line(format!("e {} 0700 - - mM:1d", missing.display())),
+            line(format!("e {} 0700 - - mM:1d", existing.display())),
+        ];
+        create(&config, &host()).unwrap();
+        clean(&config, &host(), SystemTime::now()).unwrap();
+        assert!(!missing.exists());
+        assert_eq!(
+            fs::metadata(&existing).unwrap().permissions().mode() & 0o7777,
+            0o700
+        );
+        assert!(!existing.join("old").exists());
+    }
+
+    #[test]
+    fn test_symlink_target_specifiers() {
+        let tmp = TempDir::new().unwrap();
+        fs::create_dir_all(tmp.path().join("etc")).unwrap();
+        // A value with a slash is just more path components
+        fs::write(tmp.path().join("etc/machine-id"), b"ab/cd\n").unwrap();
+
+        let target = Context::new(tmp.path().to_owned(), SpecifierSource::Target);
+        let config = [line("L /run/%m/sock - - - - /actual/%m/sock".to_owned())];
+        create(&config, &target).unwrap();
+        assert_eq!(
+            fs::read_link(tmp.path().join("run/ab/cd/sock")).unwrap(),
+            Path::new("/actual/ab/cd/sock")
+        );
+        // Plain L leaves an existing link alone
+        fs::remove_file(tmp.path().join("run/ab/cd/sock")).unwrap();
+        std::os::unix::fs::symlink("/elsewhere", tmp.path().join("run/ab/cd/sock")).unwrap();
+        create(&config, &target).unwrap();
+        assert_eq!(
+            fs::read_link(tmp.path().join("run/ab/cd/sock")).unwrap(),
+            Path::new("/elsewhere")
+        );
+    }
+
+    #[test]
+    fn test_confirm_removal() {
+        let tmp = TempDir::new().unwrap();
+        fs::write(tmp.path().join("keep"), b"").unwrap();
+        fs::write(tmp.path().join("remove"), b"").unwrap();
+        make_old(&tmp.path().join("keep"));
+        make_old(&tmp.path().join("remove"));
+        let upper = tmp.path().join("D");
+        fs::create_dir_all(upper.join("sub")).unwrap();
+
+        let asked = Rc::new(RefCell::new(Vec::new()));
+        let mut context = host();
+        context.confirm = Some(Box::new({
+            let asked = asked.clone();
+            move |path: &Path, reason: &str| {
+                asked
+                    .borrow_mut()
+                    .push((path.to_owned(), reason.to_owned()));
+                path.ends_with("remove")
+            }
+        }));
+        let config = [
+            line(format!("d {} - - - mM:1d", tmp.path().display())),
+            line(format!("D {}", upper.display())),
+        ];
+        clean(&config, &context, SystemTime::now()).unwrap();
+        remove(&config, &context).unwrap();
+
+        assert!(tmp.path().join("keep").exists());
+        assert!(!tmp.path().join("remove").exists());
+        assert!(upper.join("sub").exists());
+        let mut asked = asked.take();
+        asked.sort();
+        assert_eq!(
+            asked,
+            [
+                (upper.clone(), "D directory".to_owned()),
+                (upper, "contents of a D directory".to_owned()),
+                (
+                    tmp.path().join("keep"),
+                    "not used within the cleanup age mM:1d".to_owned()
+                ),
+                (
+                    tmp.path().join("remove"),
+                    "not used within the cleanup age mM:1d".to_owned()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,155 @@
+use std::{error::Error, fmt, fs::File, io};
+
+/// Inode flags as in `linux/fs.h`, with the letters `chattr` uses for them
+const FLAGS: &[(u8, u32)] = &[
+    (b's', 0x1),        // FS_SECRM_FL, secure deletion
+    (b'u', 0x2),        // FS_UNRM_FL, undeletable
+    (b'c', 0x4),        // FS_COMPR_FL, compressed
+    (b'S', 0x8),        // FS_SYNC_FL, synchronous updates
+    (b'i', 0x10),       // FS_IMMUTABLE_FL
+    (b'a', 0x20),       // FS_APPEND_FL, append only
+    (b'd', 0x40),       // FS_NODUMP_FL
+    (b'A', 0x80),       // FS_NOATIME_FL
+    (b'j', 0x4000),     // FS_JOURNAL_DATA_FL
+    (b't', 0x8000),     // FS_NOTAIL_FL
+    (b'D', 0x10000),    // FS_DIRSYNC_FL
+    (b'T', 0x20000),    // FS_TOPDIR_FL
+    (b'e', 0x80000),    // FS_EXTENT_FL
+    (b'C', 0x800000),   // FS_NOCOW_FL
+    (b'P', 0x20000000), // FS_PROJINHERIT_FL
+];
+
+/// A change to the inode flags of a file, from an `h` argument like `+ai`, `-a`, or `=i`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct AttrChange {
+    /// The flags this change decides about
+    pub mask: u32,
+    /// Which of the flags in `mask` end up set, the rest of them are cleared
+    pub value: u32,
+}
+
+impl AttrChange {
+    /// The flags of a file after the change, leaving flags outside `mask` as they were
+    pub fn apply(self, flags: u32) -> u32 {
+        flags & !self.mask | self.value
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AttrParseError {
+    Empty,
+    UnknownFlag(u8),
+}
+
+impl fmt::Display for AttrParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "no file attributes given"),
+            Self::UnknownFlag(ch) => {
+                write!(f, "unknown file attribute '{}'", ch.escape_ascii())
+            }
+        }
+    }
+}
+
+impl Error for AttrParseError {}
+
+/// Parse an `h` argument. `+` sets the listed flags, `-` clears them, and `=` or no prefix sets
+/// exactly the listed flags, clearing every other flag we know of.
+pub fn parse_attrs(input: &[u8]) -> Result<AttrChange, AttrParseError> {
+    let (op, letters) = match input {
+        [op @ (b'+' | b'-' | b'='), letters @ ..] => (*op, letters),
+        letters => (b'=', letters),
+    };
+    if letters.is_empty() {
+        Err(AttrParseError::Empty)?
+    }
+    let flags = letters.iter().try_fold(0, |flags, &letter| {
+        let Some(&(_, flag)) = FLAGS.iter().find(|&&(ch, _)| ch == letter) else {
+            Err(AttrParseError::UnknownFlag(letter))?
+        };
+        Ok(flags | flag)
+    })?;
+    Ok(match op {
+        b'+' => AttrChange {
+            mask: flags,
+            value: flags,
+        },
+        b'-' => AttrChange {
+            mask: flags,
+            value: 0,
+        },
+        _ => AttrChange {
+            mask: FLAGS.iter().fold(0, |mask, &(_, flag)| mask | flag),
+            value: flags,
+        },
+    })
+}
+
+#[cfg(target_os = "linux")]
+pub fn get_flags(file: &File) -> io::Result<u32> {
+    use std::os::fd::AsRawFd;
+
+    let mut flags: libc::c_int = 0;
+    // Despite the name of the ioctl's argument type, the kernel reads and writes an int
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags as u32)
+}
+
+#[cfg(target_os = "linux")]
+pub fn set_flags(file: &File, flags: u32) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let flags = flags as libc::c_int;
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn get_flags(_file: &File) -> io::Result<u32> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_flags(_file: &File, _flags: u32) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "file attributes are only supported on Linux",
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::attr::{parse_attrs, AttrChange, AttrParseError};
+
+    #[test]
+    fn test_parse_attrs() {
+        assert_eq!(
+            parse_attrs(b"+ai"),
+            Ok(AttrChange {
+                mask: 0x30,
+                value: 0x30
+            })
+        );
+        assert_eq!(
+            parse_attrs(b"-a"),
+            Ok(AttrChange {
+                mask: 0x20,
+                value: 0
+            })
+        );
+        assert_eq!(parse_attrs(b"=i").unwrap().value, 0x10);
+        assert_eq!(parse_attrs(b"i"), parse_attrs(b"=i"));
+        assert_eq!(parse_attrs(b"+"), Err(AttrParseError::Empty));
+        assert_eq!(parse_attrs(b"+x"), Err(AttrParseError::UnknownFlag(b'x')));
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    fmt, fs, io,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    apply::{symlink_target, target_mode, Context},
+    config_file::{Line, LineAction, ModeBehavior},
+    users,
+};
+
+/// How the filesystem compares to what a line asks for
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Status {
+    Ok,
+    Missing,
+    /// Each mismatch, like `mode is 0700, want 0755`
+    Differs(Vec<String>),
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ok => write!(f, "ok"),
+            Self::Missing => write!(f, "missing"),
+            Self::Differs(mismatches) => write!(f, "differs\t{}", mismatches.join(", ")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expected {
+    File,
+    Directory,
+    Symlink,
+    Fifo,
+    CharDevice,
+    BlockDevice,
+    /// Anything, as long as it's there
+    Exists,
+    /// Only adjusts what's there, so nothing existing is fine too
+    Adjusted,
+    Absent,
+}
+
+impl Expected {
+    fn of(action: LineAction) -> Option<Self> {
+        Some(match action {
+            LineAction::CreateFile => Self::File,
+            LineAction::CreateAndCleanUpDirectory
+            | LineAction::CreateAndRemoveDirectory
+            | LineAction::CreateSubvolume
+            | LineAction::CreateSubvolumeInheritQuota
+            | LineAction::CreateSubvolumeNewQuota => Self::Directory,
+            LineAction::CreateFifo => Self::Fifo,
+            LineAction::CreateSymlink => Self::Symlink,
+            LineAction::CreateCharDevice => Self::CharDevice,
+            LineAction::CreateBlockDevice => Self::BlockDevice,
+            LineAction::Copy => Self::Exists,
+            LineAction::WriteFile
+            | LineAction::CleanUpDirectory
+            | LineAction::SetMode
+            | LineAction::SetModeRecursive
+            | LineAction::SetXattr
+            | LineAction::SetXattrRecursive
+            | LineAction::SetAttr
+            | LineAction::SetAttrRecursive
+            | LineAction::SetAcl
+            | LineAction::SetAclRecursive => Self::Adjusted,
+            LineAction::Remove | LineAction::RemoveRecursive => Self::Absent,
+            // Ignored paths have no state to check
+            LineAction::Ignore | LineAction::IgnoreNonRecursive => return None,
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::File => "a regular file",
+            Self::Directory => "a directory",
+            Self::Symlink => "a symlink",
+            Self::Fifo => "a fifo",
+            Self::CharDevice => "a character device",
+            Self::BlockDevice => "a block device",
+            Self::Exists | Self::Adjusted => "anything",
+            Self::Absent => "nothing",
+        }
+    }
+
+    fn matches(self, file_type: fs::FileType) -> bool {
+        match self {
+            Self::File => file_type.is_file(),
+            Self::Directory => file_type.is_dir(),
+            Self::Symlink => file_type.is_symlink(),
+            Self::Fifo => file_type.is_fifo(),
+            Self::CharDevice => file_type.is_char_device(),
+            Self::BlockDevice => file_type.is_block_device(),
+            Self::Exists | Self::Adjusted => true,
+            Self::Absent => false,
+        }
+    }
+}
+
+fn type_name(file_type: fs::FileType) -> &'static str {
+    if file_type.is_file() {
+        "a regular file"
+    } else if file_type.is_dir() {
+        "a directory"
+    } else if file_type.is_symlink() {
+        "a symlink"
+    } else if file_type.is_fifo() {
+        "a fifo"
+    } else if file_type.is_char_device() {
+        "a character device"
+    } else if file_type.is_block_device() {
+        "a block device"
+    } else {
+        "a socket"
+    }
+}
+
+/// Compare each line against the filesystem without changing anything. Lines that only ignore
+/// paths are skipped.
+pub(crate) fn merge_check(
+    config: &[Line],
+    context: &Context,
+) -> eyre::Result<Vec<(PathBuf, Status)>> {
+    let mut results = Vec::new();
+    for line in config {
+        let Some(expected) = Expected::of(line.line_type.data.action) else {
+            continue;
+        };
+        let path = context.line_path(line)?;
+        let status = check_line(line, context, &path, expected)?;
+        results.push((path, status));
+    }
+    Ok(results)
+}
+
+/// Resolve everything applying `line` looks up, without changing anything: specifiers in its
+/// path, owner, group, and symlink target, and the users and groups it names. Returns the path it
+/// applies to.
+pub(crate) fn preflight(line: &Line, context: &Context) -> eyre::Result<PathBuf> {
+    let mut line = line.clone();
+    context.expand_owners(&mut line)?;
+    let path = context.line_path(&line)?;
+    if let Some(owner) = &line.owner.data {
+        users::resolve_user(owner)?;
+    }
+    if let Some(group) = &line.group.data {
+        users::resolve_group(group)?;
+    }
+    if line.line_type.data.action == LineAction::CreateSymlink {
+        symlink_target(&line, context)?;
+    }
+    Ok(path)
+}
+
+fn check_line(
+    line: &Line,
+    context: &Context,
+    path: &Path,
+    expected: Expected,
+) -> eyre::Result<Status> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(match expected {
+                Expected::Absent | Expected::Adjusted => Status::Ok,
+                _ => Status::Missing,
+            });
+        }
+        Err(e) => Err(e)?,
+    };
+    let file_type = meta.file_type();
+    if !expected.matches(file_type) {
+        return Ok(Status::Differs(vec![format!(
+            "is {}, want {}",
+            type_name(file_type),
+            expected.name()
+        )]));
+    }
+
+    let mut mismatches = Vec::new();
+    if expected == Expected::Symlink {
+        let target = symlink_target(line, context)?;
+        let existing = fs::read_link(path)?;
+        if existing != target {
+            mismatches.push(format!(
+                "target is {}, want {}",
+                existing.display(),
+                target.display()
+            ));
+        }
+        // Symlinks have no mode of their own, and ownership is rarely meaningful
+        return Ok(status(mismatches));
+    }
+
+    let default = if file_type.is_dir() { 0o755 } else { 0o644 };
+    let existing = meta.mode() & 0o7777;
+    if let Some(want) = target_mode(line.mode.data.as_ref(), default, Some(existing)) {
+        if want != existing {
+            mismatches.push(format!("mode is {existing:04o}, want {want:04o}"));
+        }
+    }
+
+    // With ~ the ownership of existing objects is left alone too
+    let keep_existing = line
+        .mode
+        .data
+        .as_ref()
+        .is_some_and(|mode| mode.mode_behavior == ModeBehavior::KeepExisting);
+    if !keep_existing {
+        if let Some(owner) = &line.owner.data {
+            let uid = users::resolve_user(owner)?;
+            if uid != meta.uid() {
+                mismatches.push(format!("owner is {}, want {uid}", meta.uid()));
+            }
+        }
+        if let Some(group) = &line.group.data {
+            let gid = users::resolve_group(group)?;
+            if gid != meta.gid() {
+                mismatches.push(format!("group is {}, want {gid}", meta.gid()));
+            }
+        }
+    }
+    Ok(status(mismatches))
+}
+
+fn status(mismatches: Vec<String>) -> Status {
+    if mismatches.is_empty() {
+        Status::Ok
+    } else {
+        Status::Differs(mismatches)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        os::unix::fs::{symlink, PermissionsExt},
+        path::Path,
+    };
+
+    use tempfile::TempDir;
+
+    use crate::{
+        apply::Context,
+        check::{merge_check, preflight, Status},
+        parser::{parse_line, FileSpan},
+        specifier::SpecifierSource,
+    };
+
+    #[test]
+    fn test_merge_check() {
+        let tmp = TempDir::new().unwrap();
+        let context = Context::new(tmp.path().to_owned(), SpecifierSource::Host);
+        fs::create_dir(tmp.path().join("dir")).unwrap();
+        fs::set_permissions(tmp.path().join("dir"), fs::Permissions::from_mode(0o700)).unwrap();
+        fs::write(tmp.path().join("file"), b"").unwrap();
+        fs::set_permissions(tmp.path().join("file"), fs::Permissions::from_mode(0o644)).unwrap();
+        symlink("/elsewhere", tmp.path().join("link")).unwrap();
+        fs::write(tmp.path().join("stale"), b"").unwrap();
+
+        let lines: &[&[u8]] = &[
+            b"d /dir 0700",
+            b"d /dir 0755",
+            b"d /file",
+            b"f /file",
+            b"f /new",
+            b"L /link - - - - /target",
+            b"r /stale",
+            b"r /gone",
+            b"z /gone 0600",
+            b"x /dir",
+        ];
+        let config = lines
+            .iter()
+            .map(|line| parse_line(FileSpan::from_slice(line, Path::new(""))).unwrap())
+            .collect::<Vec<_>>();
+        let statuses = merge_check(&config, &context)
+            .unwrap()
+            .into_iter()
+            .map(|(_, status)| status)
+            .collect::<Vec<_>>();
+        let differs = |mismatch: &str| Status::Differs(vec![mismatch.to_owned()]);
+        assert_eq!(
+            statuses,
+            [
+                Status::Ok,
+                differs("mode is 0700, want 0755"),
+                differs("is a regular file, want a directory"),
+                Status::Ok,
+                Status::Missing,
+                differs("target is /elsewhere, want /target"),
+                differs("is a regular file, want nothing"),
+                Status::Ok,
+                Status::Ok,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_preflight() {
+        let tmp = TempDir::new().unwrap();
+        // Nothing in the root to read a machine ID from
+        let context = Context::new(tmp.path().to_owned(), SpecifierSource::Target);
+        let preflight = |line: &[u8]| {
+            let line = parse_line(FileSpan::from_slice(line, Path::new(""))).unwrap();
+            preflight(&line, &context).map_err(|e| e.to_string())
+        };
+        assert_eq!(preflight(b"d /dir 0755 0 0"), Ok(tmp.path().join("dir")));
+        assert_eq!(
+            preflight(b"L /link - - - - /target"),
+            Ok(tmp.path().join("link"))
+        );
+        assert!(preflight(b"d /dir - no-such-user-here").is_err());
+        assert!(preflight(b"d /dir - - no-such-group-here").is_err());
+        assert!(preflight(b"d /%m").is_err());
+        assert!(preflight(b"L /link - - - - /%m").is_err());
+    }
+}
@@ -1,15 +1,28 @@
 use std::{
+    error::Error,
     ffi::OsString,
+    fmt,
     ops::{Deref, Range},
+    os::unix::ffi::OsStrExt,
     path::Path,
     time::Duration,
 };
 
+use base64::engine::Engine;
+
+use crate::parser::{decodes_base64, escape_field, unescapes_argument};
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum LineAction {
     CreateFile,
     WriteFile,
     CreateAndCleanUpDirectory,
+    /// `v`, a btrfs subvolume where supported, otherwise a directory
+    CreateSubvolume,
+    /// `q`, like `v` but sharing the parent's quota group
+    CreateSubvolumeInheritQuota,
+    /// `Q`, like `v` but with a new quota group, or outside of btrfs a new project quota
+    CreateSubvolumeNewQuota,
     CreateAndRemoveDirectory,
     CleanUpDirectory,
     CreateFifo,
@@ -31,6 +44,39 @@ pub enum LineAction {
     SetAclRecursive,
 }
 
+impl LineAction {
+    /// Character used for this action in the type field
+    pub fn type_char(self) -> char {
+        match self {
+            Self::CreateFile => 'f',
+            Self::WriteFile => 'w',
+            Self::CreateAndCleanUpDirectory => 'd',
+            Self::CreateSubvolume => 'v',
+            Self::CreateSubvolumeInheritQuota => 'q',
+            Self::CreateSubvolumeNewQuota => 'Q',
+            Self::CreateAndRemoveDirectory => 'D',
+            Self::CleanUpDirectory => 'e',
+            Self::CreateFifo => 'p',
+            Self::CreateSymlink => 'L',
+            Self::CreateCharDevice => 'c',
+            Self::CreateBlockDevice => 'b',
+            Self::Copy => 'C',
+            Self::Ignore => 'x',
+            Self::IgnoreNonRecursive => 'X',
+            Self::Remove => 'r',
+            Self::RemoveRecursive => 'R',
+            Self::SetMode => 'z',
+            Self::SetModeRecursive => 'Z',
+            Self::SetXattr => 't',
+            Self::SetXattrRecursive => 'T',
+            Self::SetAttr => 'h',
+            Self::SetAttrRecursive => 'H',
+            Self::SetAcl => 'a',
+            Self::SetAclRecursive => 'A',
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub struct LineType {
     /// Basic action, represented by first character
@@ -43,12 +89,28 @@ pub struct LineType {
     pub noerror: bool,
     /// Equals sign modifier, remove existing objects if they do not match
     pub force: bool,
+    /// Caret modifier, the argument names a service credential to take the contents from
+    pub credential: bool,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum FileOwner {
     Id(u32),
     Name(String),
+    /// A name with specifiers like `%u`, only known once they're expanded when the line is applied
+    Specifiers(SpecifierString),
+}
+
+impl fmt::Display for FileOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Id(id) => write!(f, "{id}"),
+            Self::Name(name) => write!(f, "{name}"),
+            Self::Specifiers(string) => {
+                write!(f, "{}", String::from_utf8_lossy(&string.to_bytes()))
+            }
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Default)]
@@ -94,13 +156,75 @@ impl CleanupAge {
     };
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+fn fmt_duration(duration: Duration, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if duration.is_zero() {
+        return write!(f, "0");
+    }
+    // Stick to units with an exact length, months and years are fractional days
+    let mut secs = duration.as_secs();
+    for (unit, length) in [("d", 86400), ("h", 3600), ("min", 60), ("s", 1)] {
+        if secs >= length {
+            write!(f, "{}{unit}", secs / length)?;
+            secs %= length;
+        }
+    }
+    let mut nanos = duration.subsec_nanos();
+    for (unit, length) in [("ms", 1_000_000), ("us", 1000), ("ns", 1)] {
+        if nanos >= length {
+            write!(f, "{}{unit}", nanos / length)?;
+            nanos %= length;
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for CleanupAge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = Self {
+            age: Duration::ZERO,
+            ..*self
+        };
+        if *self == Self::EMPTY {
+            return write!(f, "-");
+        } else if flags != Self::EMPTY {
+            if self.second_level {
+                write!(f, "~")?;
+            }
+            for (set, ch) in [
+                (self.consider_atime, 'a'),
+                (self.consider_atime_dir, 'A'),
+                (self.consider_btime, 'b'),
+                (self.consider_btime_dir, 'B'),
+                (self.consider_ctime, 'c'),
+                (self.consider_ctime_dir, 'C'),
+                (self.consider_mtime, 'm'),
+                (self.consider_mtime_dir, 'M'),
+            ] {
+                if set {
+                    write!(f, "{ch}")?;
+                }
+            }
+            write!(f, ":")?;
+        }
+        fmt_duration(self.age, f)
+    }
+}
+
+#[derive(PartialEq, Eq, Clone)]
 pub struct Spanned<'a, T> {
     pub data: T,
     file: &'a Path,
     characters: Range<usize>,
 }
 
+/// Shows where the data came from before the data itself, like `foo.conf@2..10 "/tmp"`
+impl<T: fmt::Debug> fmt::Debug for Spanned<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{:?} ", self.file.display(), self.characters)?;
+        self.data.fmt(f)
+    }
+}
+
 impl<'a, T> Spanned<'a, T> {
     pub fn new(data: T, file: &'a Path, characters: Range<usize>) -> Self {
         Self {
@@ -109,6 +233,14 @@ impl<'a, T> Spanned<'a, T> {
             characters,
         }
     }
+    /// Configuration file the data was parsed from
+    pub(crate) fn file(&self) -> &'a Path {
+        self.file
+    }
+    /// Byte offsets of the data within its file
+    pub(crate) fn characters(&self) -> Range<usize> {
+        self.characters.clone()
+    }
     pub fn map<U>(self, closure: impl FnOnce(T) -> U) -> Spanned<'a, U> {
         Spanned {
             data: closure(self.data),
@@ -202,12 +334,30 @@ impl<'a, T> Spanned<'a, Option<T>> {
     }
 }
 
+/// Major and minor numbers from the argument of `c` and `b` lines
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct DeviceNumbers {
+    pub major: u32,
+    pub minor: u32,
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Mode {
     pub(crate) value: u32,
     pub(crate) mode_behavior: ModeBehavior,
 }
 
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.mode_behavior {
+            ModeBehavior::Default => {}
+            ModeBehavior::Masked => write!(f, "~")?,
+            ModeBehavior::KeepExisting => write!(f, ":")?,
+        }
+        write!(f, "{:04o}", self.value)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Default)]
 pub enum ModeBehavior {
     #[default]
@@ -227,7 +377,201 @@ pub struct Line<'a> {
     pub(crate) argument: Spanned<'a, Option<OsString>>,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+/// Building lines in code, for tools that generate them rather than write configuration files. The
+/// fields start out omitted, as if written `-`, and every field points at an empty span of a file
+/// named `generated`. Unlike parsing, building doesn't check that the fields make sense together.
+#[allow(unused)]
+impl Line<'static> {
+    pub fn new(action: LineAction, path: impl AsRef<Path>) -> Self {
+        let line_type = LineType {
+            action,
+            recreate: false,
+            boot: false,
+            noerror: false,
+            force: false,
+            credential: false,
+        };
+        let path = SpecifierString::literal(path.as_ref().as_os_str().as_bytes());
+        Self {
+            line_type: generated(line_type),
+            path: generated(path),
+            mode: generated(None),
+            owner: generated(None),
+            group: generated(None),
+            age: generated(None),
+            argument: generated(None),
+        }
+    }
+    /// `d`, a directory
+    pub fn directory(path: impl AsRef<Path>) -> Self {
+        Self::new(LineAction::CreateAndCleanUpDirectory, path)
+    }
+    /// `f`, a file, empty unless it gets an `argument`
+    pub fn file(path: impl AsRef<Path>) -> Self {
+        Self::new(LineAction::CreateFile, path)
+    }
+    /// `L`, a symlink to `target`
+    pub fn symlink(path: impl AsRef<Path>, target: impl AsRef<Path>) -> Self {
+        Self::new(LineAction::CreateSymlink, path).argument(target.as_ref().as_os_str())
+    }
+    /// Add the `+` modifier
+    pub fn recreate(mut self) -> Self {
+        self.line_type.data.recreate = true;
+        self
+    }
+    /// Add the `!` modifier
+    pub fn boot(mut self) -> Self {
+        self.line_type.data.boot = true;
+        self
+    }
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode.data = Some(Mode {
+            value: mode,
+            mode_behavior: ModeBehavior::Default,
+        });
+        self
+    }
+    pub fn owner(mut self, owner: FileOwner) -> Self {
+        self.owner.data = Some(owner);
+        self
+    }
+    pub fn group(mut self, group: FileOwner) -> Self {
+        self.group.data = Some(group);
+        self
+    }
+    /// A cleanup age that considers the same times as an age written without a prefix
+    pub fn age(mut self, age: Duration) -> Self {
+        self.age.data = Some(CleanupAge {
+            age,
+            ..CleanupAge::EMPTY
+        });
+        self
+    }
+    pub fn argument(mut self, argument: impl Into<OsString>) -> Self {
+        self.argument.data = Some(argument.into());
+        self
+    }
+}
+
+#[allow(unused)]
+fn generated<T>(data: T) -> Spanned<'static, T> {
+    Spanned::new(data, Path::new("generated"), 0..0)
+}
+
+/// A line whose argument can't be written in configuration file syntax, since as it is it
+/// wouldn't read back the same and its type doesn't take a base64 argument
+#[allow(unused)]
+#[derive(Debug, PartialEq, Eq)]
+pub struct UnwritableArgument(pub Box<[u8]>);
+
+impl fmt::Display for UnwritableArgument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "argument \"{}\" can't be written for this line type",
+            self.0.escape_ascii()
+        )
+    }
+}
+
+impl Error for UnwritableArgument {}
+
+impl Line<'_> {
+    /// The line in configuration file syntax, which parses back to the same line, unlike its
+    /// `Display` when the argument can't be written for its type
+    #[allow(unused)]
+    pub fn to_config_line(&self) -> Result<String, UnwritableArgument> {
+        match self.argument_field() {
+            Argument::Unwritable(argument) => Err(UnwritableArgument(argument.into())),
+            _ => Ok(self.to_string()),
+        }
+    }
+
+    /// How the argument is written. It runs to the end of the line, so anything that wouldn't
+    /// survive being read back is written as base64 instead. That includes a literal `-` or `""`,
+    /// which as the whole argument mean none and an empty one.
+    fn argument_field(&self) -> Argument<'_> {
+        let action = self.line_type.data.action;
+        let Some(argument) = &self.argument.data else {
+            return Argument::None;
+        };
+        let argument = argument.as_bytes();
+        let plain = std::str::from_utf8(argument).is_ok()
+            && !argument.contains(&b'\n')
+            && !argument.starts_with(b" ")
+            && !argument.starts_with(b"\t")
+            && argument != b"-"
+            && argument != b"\"\"";
+        if argument.is_empty() {
+            Argument::Plain("\"\"".to_owned())
+        } else if plain && unescapes_argument(action) {
+            // Where escapes are decoded, backslashes are escaped too
+            Argument::Plain(String::from_utf8_lossy(argument).replace('\\', "\\\\"))
+        } else if plain {
+            Argument::Plain(String::from_utf8_lossy(argument).into_owned())
+        } else if decodes_base64(action) {
+            Argument::Base64(base64::prelude::BASE64_STANDARD.encode(argument))
+        } else {
+            Argument::Unwritable(argument)
+        }
+    }
+}
+
+enum Argument<'a> {
+    None,
+    Plain(String),
+    Base64(String),
+    Unwritable(&'a [u8]),
+}
+
+/// Writes the line back out in configuration file syntax. Omitted fields are written as `-`, and
+/// trailing omitted fields are left out entirely. An argument that can't be written for the type
+/// of the line is shown escaped, which doesn't read back the same, see `Line::to_config_line`.
+impl fmt::Display for Line<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (argument, base64) = match self.argument_field() {
+            Argument::None => (None, false),
+            Argument::Plain(argument) => (Some(argument), false),
+            Argument::Base64(argument) => (Some(argument), true),
+            Argument::Unwritable(argument) => (Some(escape_field(argument)), false),
+        };
+
+        let line_type = self.line_type.data;
+        write!(f, "{}", line_type.action.type_char())?;
+        for (set, ch) in [
+            (line_type.recreate, '+'),
+            (line_type.boot, '!'),
+            (line_type.noerror, '-'),
+            (line_type.force, '='),
+            (base64, '~'),
+            (line_type.credential, '^'),
+        ] {
+            if set {
+                write!(f, "{ch}")?;
+            }
+        }
+        write!(f, " {}", escape_field(&self.path.data.to_bytes()))?;
+
+        let owner_field = |owner: &FileOwner| escape_field(owner.to_string().as_bytes());
+        let trailing = [
+            self.mode.data.as_ref().map(Mode::to_string),
+            self.owner.data.as_ref().map(owner_field),
+            self.group.data.as_ref().map(owner_field),
+            self.age.data.as_ref().map(CleanupAge::to_string),
+            argument,
+        ];
+        let count = trailing
+            .iter()
+            .rposition(Option::is_some)
+            .map_or(0, |last| last + 1);
+        for field in &trailing[..count] {
+            write!(f, " {}", field.as_deref().unwrap_or("-"))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum Specifier {
     Architecture,      //%a
     ImageVersion,      //%A
@@ -274,8 +618,8 @@ impl Specifier {
             'M' => ImageID,
             'o' => OperatingSystemID,
             'S' => StateDir,
-            't' => TempDir,
-            'T' => RuntimeDir,
+            't' => RuntimeDir,
+            'T' => TempDir,
             'u' => Username,
             'U' => UserUID,
             'v' => KernelRelease,
@@ -286,7 +630,195 @@ impl Specifier {
             _ => return None,
         })
     }
+
+    /// Inverse of [`Specifier::parse`]
+    pub fn to_char(self) -> u8 {
+        use Specifier::*;
+        match self {
+            Architecture => b'a',
+            ImageVersion => b'A',
+            BootID => b'b',
+            BuildID => b'B',
+            CacheDir => b'C',
+            UserGroup => b'g',
+            UserGID => b'G',
+            UserHome => b'h',
+            Hostname => b'H',
+            ShortHostname => b'l',
+            LogDir => b'L',
+            MachineID => b'm',
+            ImageID => b'M',
+            OperatingSystemID => b'o',
+            StateDir => b'S',
+            RuntimeDir => b't',
+            TempDir => b'T',
+            Username => b'u',
+            UserUID => b'U',
+            KernelRelease => b'v',
+            PersistentTempDir => b'V',
+            VersionID => b'w',
+            VariantID => b'W',
+            PercentSign => b'%',
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(PartialEq, Eq, Clone)]
 pub struct SpecifierString(pub Vec<u8>, pub Box<[(Specifier, Vec<u8>)]>);
+
+/// Bytes shown as an escaped string rather than a list of numbers
+struct DebugBytes<'a>(&'a [u8]);
+
+impl fmt::Debug for DebugBytes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self.0.escape_ascii())
+    }
+}
+
+impl fmt::Debug for SpecifierString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sections = self
+            .1
+            .iter()
+            .map(|(specifier, bytes)| (specifier, DebugBytes(bytes)))
+            .collect::<Vec<_>>();
+        f.debug_tuple("SpecifierString")
+            .field(&DebugBytes(&self.0))
+            .field(&sections)
+            .finish()
+    }
+}
+
+impl SpecifierString {
+    /// A string standing for `bytes` as they are, with any `%` kept as `%%` rather than starting
+    /// a specifier
+    #[allow(unused)]
+    pub fn literal(bytes: &[u8]) -> Self {
+        let mut segments = bytes.split(|&ch| ch == b'%');
+        let prefix = segments.next().unwrap_or_default().to_vec();
+        let rest = segments
+            .map(|segment| (Specifier::PercentSign, segment.to_vec()))
+            .collect();
+        Self(prefix, rest)
+    }
+    /// Bytes of the string as written in a configuration file, with specifiers left unexpanded
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.0.clone();
+        for (specifier, segment) in self.1.iter() {
+            bytes.push(b'%');
+            bytes.push(specifier.to_char());
+            bytes.extend_from_slice(segment);
+        }
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::{
+        config_file::{Line, UnwritableArgument},
+        parser::{parse_line, FileSpan},
+    };
+
+    fn parse(input: &[u8]) -> Line<'static> {
+        parse_line(FileSpan::from_slice(input, Path::new(""))).unwrap()
+    }
+
+    /// Compare only the parsed data, the spans will differ after reformatting
+    fn assert_same_data(a: &Line, b: &Line) {
+        assert_eq!(a.line_type.data, b.line_type.data);
+        assert_eq!(a.path.data, b.path.data);
+        assert_eq!(a.mode.data, b.mode.data);
+        assert_eq!(a.owner.data, b.owner.data);
+        assert_eq!(a.group.data, b.group.data);
+        assert_eq!(a.age.data, b.age.data);
+        assert_eq!(a.argument.data, b.argument.data);
+    }
+
+    #[test]
+    fn test_display_canonical() {
+        let cases: &[(&[u8], &str)] = &[
+            (b"R! /etc/group.lock", "R! /etc/group.lock"),
+            (b"d /run/user 755 root root", "d /run/user 0755 root root"),
+            (b"d\t/tmp 1777 - - 10d", "d /tmp 1777 - - 10d"),
+            (b"d /tmp - - - -", "d /tmp - - - -"),
+            (b"F /x ~0644 0 0 1M", "f+ /x ~0644 0 0 30d10h30min"),
+            (
+                b"L+ /run/a - - - - /nix/store/target",
+                "L+ /run/a - - - - /nix/store/target",
+            ),
+            (b"e /var/%u/\\x20x :700", "e /var/%u/\\x20x :0700"),
+            (
+                b"w /x - - - ~aM:1s500ms hello  world",
+                "w /x - - - ~aM:1s500ms hello  world",
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(parse(input).to_string(), *expected);
+        }
+    }
+
+    #[test]
+    fn test_display_base64_argument() {
+        // A decoded argument with a newline can only be represented as base64
+        let line = parse(b"f~ /etc/thing - - - - aGVsbG8Kd29ybGQ=");
+        assert_eq!(line.to_string(), "f~ /etc/thing - - - - aGVsbG8Kd29ybGQ=");
+        let line = parse(b"f~ /etc/thing - - - - aGVsbG8=");
+        assert_eq!(line.to_string(), "f /etc/thing - - - - hello");
+    }
+
+    #[test]
+    fn test_display_without_base64() {
+        // These types reject ~, so an argument that could only be written as base64 can't be
+        // written at all, and is only shown escaped
+        for input in [&b"d /x"[..], b"x /x", b"z /x 0644"] {
+            let mut line = parse(input);
+            line.argument.data = Some("first\nsecond".into());
+            assert_eq!(
+                line.to_config_line(),
+                Err(UnwritableArgument(b"first\nsecond".as_slice().into()))
+            );
+            let shown = line.to_string();
+            assert!(shown.ends_with(" first\\nsecond"), "{shown}");
+        }
+        // An argument that reads back as it is stays
+        let line = parse(b"x /x - - - - kept");
+        assert_same_data(&line, &parse(line.to_config_line().unwrap().as_bytes()));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let corpus: &[&[u8]] = &[
+            b"L+ /run/gdm/.config/pulse/default.pa - - - - /nix/store/whibfps24g91fx9i63m2wdyl87dfadnn-default.pa",
+            b"R! /etc/group.lock",
+            b"d /run/lock 0755 root root -",
+            b"D /tmp/.X11-unix 1777 root root 10d",
+            b"q /var/tmp 1777 root root 30d",
+            b"x /tmp/systemd-private-%b-*",
+            b"z /var/log/journal 2755 root systemd-journal - -",
+            b"a+ /var/log/journal - - - - d:group::r-x,d:group:adm:r-x",
+            b"f /x ~0644 alice 1000 ~mM:1w2d3h4min5s6ms7us8ns",
+            b"w- /proc/sys/x - - - - a b\tc \\n\"'",
+            b"f~ /x - - - - AAEC/w==",
+            b"f~ /x - - - - IGxlYWRpbmcgc3BhY2U=",
+            b"c! /dev/fuse 0666 root root - 10:229",
+            b"e \"/%C/quoted\\x20path\" - \"user name\" '' 0",
+            b"C /etc/\\xff\\x01\\\\ - - - - /usr/share/factory/etc",
+            b"p= %t/%%fifo :0600 - -",
+            b"w /x - - - - \"\"",
+            b"w~ /x - - - - LQ==",
+            b"L /x - - - - /nix/\xff",
+        ];
+        for input in corpus {
+            let line = parse(input);
+            let emitted = line.to_config_line().unwrap();
+            let reparsed = parse(emitted.as_bytes());
+            assert_same_data(&line, &reparsed);
+            assert_eq!(reparsed.to_config_line().unwrap(), emitted);
+        }
+        // Bytes that aren't UTF-8 can only be written as base64, which d doesn't take
+        assert!(parse(b"d /x - - - - \xff").to_config_line().is_err());
+    }
+}
@@ -0,0 +1,262 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+/// How warnings and errors about the configuration are printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum DiagnosticFormat {
+    /// A line of text for people to read
+    #[default]
+    Human,
+    /// GitHub Actions workflow commands, which show up as annotations on the lines they're about
+    Github,
+    /// A JSON object per line with file, line, col, severity, and message
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl Severity {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Warning => "warning",
+            Self::Error => "error",
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Warning => write!(f, "Warning"),
+            Self::Error => write!(f, "Error"),
+        }
+    }
+}
+
+/// A warning or error, with where in the configuration it's about when that's known. Lines and
+/// columns count from 1, columns in bytes.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub file: Option<PathBuf>,
+    pub line: Option<usize>,
+    pub col: Option<usize>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: impl fmt::Display) -> Self {
+        Self {
+            severity,
+            message: message.to_string(),
+            file: None,
+            line: None,
+            col: None,
+        }
+    }
+
+    /// About line `line` of `file`, at column `col`
+    pub fn at_line(self, file: &Path, line: usize, col: usize) -> Self {
+        Self {
+            file: Some(file.to_owned()),
+            line: Some(line),
+            col: Some(col),
+            ..self
+        }
+    }
+
+    /// About the byte at `offset` in `file`. Spans only store offsets, so the file is read again
+    /// to count lines, and a file that can't be read, like `--add-line`, only gives its name.
+    pub fn at_offset(self, file: &Path, offset: usize) -> Self {
+        let Some(before) = fs::read(file)
+            .ok()
+            .and_then(|contents| contents.get(..offset).map(<[u8]>::to_vec))
+        else {
+            return Self {
+                file: Some(file.to_owned()),
+                ..self
+            };
+        };
+        let line = before.iter().filter(|&&ch| ch == b'\n').count() + 1;
+        let start = before
+            .iter()
+            .rposition(|&ch| ch == b'\n')
+            .map_or(0, |newline| newline + 1);
+        self.at_line(file, line, offset - start + 1)
+    }
+}
+
+impl DiagnosticFormat {
+    /// Print `diagnostic` to stderr
+    pub fn emit(self, diagnostic: &Diagnostic) {
+        eprintln!("{}", self.format(diagnostic));
+    }
+
+    pub fn format(self, diagnostic: &Diagnostic) -> String {
+        let Diagnostic {
+            severity,
+            message,
+            file,
+            line,
+            col,
+        } = diagnostic;
+        match self {
+            Self::Human => match (file, line) {
+                (Some(file), Some(line)) => {
+                    format!("{}:{line}: {severity}: {message}", file.display())
+                }
+                _ => format!("{severity}: {message}"),
+            },
+            Self::Github => {
+                let properties = [
+                    file.as_ref()
+                        .map(|file| format!("file={}", github_property(&file.to_string_lossy()))),
+                    line.map(|line| format!("line={line}")),
+                    col.map(|col| format!("col={col}")),
+                ]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>();
+                let separator = if properties.is_empty() { "" } else { " " };
+                format!(
+                    "::{}{separator}{}::{}",
+                    severity.name(),
+                    properties.join(","),
+                    github_data(message)
+                )
+            }
+            Self::Json => {
+                let number = |number: &Option<usize>| {
+                    number.map_or_else(|| "null".to_owned(), |number| number.to_string())
+                };
+                format!(
+                    "{{\"file\":{},\"line\":{},\"col\":{},\"severity\":\"{}\",\"message\":{}}}",
+                    file.as_ref().map_or_else(
+                        || "null".to_owned(),
+                        |file| json_string(&file.to_string_lossy())
+                    ),
+                    number(line),
+                    number(col),
+                    severity.name(),
+                    json_string(message)
+                )
+            }
+        }
+    }
+}
+
+/// Bytes from a configuration file, for a message: text as it is, with control characters and
+/// bytes that aren't UTF-8 escaped, so a line in another encoding stays on one line and can still
+/// be told apart from others
+#[allow(unused)]
+pub fn readable(bytes: &[u8]) -> String {
+    let mut readable = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for ch in chunk.valid().chars() {
+            if ch.is_control() {
+                readable.extend(ch.escape_default());
+            } else {
+                readable.push(ch);
+            }
+        }
+        for byte in chunk.invalid() {
+            readable.push_str(&format!("\\x{byte:02x}"));
+        }
+    }
+    readable
+}
+
+/// Escape the message of a workflow command, which ends at the line
+fn github_data(input: &str) -> String {
+    input
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Escape a property of a workflow command, which also ends at `,` and `:`
+fn github_property(input: &str) -> String {
+    github_data(input).replace(':', "%3A").replace(',', "%2C")
+}
+
+fn json_string(input: &str) -> String {
+    let mut quoted = String::with_capacity(input.len() + 2);
+    quoted.push('"');
+    for ch in input.chars() {
+        match ch {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            ch if ch.is_control() => quoted.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::Path};
+
+    use tempfile::TempDir;
+
+    use crate::diagnostic::{readable, Diagnostic, DiagnosticFormat, Severity};
+
+    #[test]
+    fn test_formats() {
+        let located = Diagnostic::new(Severity::Warning, "skipping d /x: 50% \"odd\"\nreally")
+            .at_line(Path::new("a,b:c.conf"), 3, 1);
+        assert_eq!(
+            DiagnosticFormat::Human.format(&located),
+            "a,b:c.conf:3: Warning: skipping d /x: 50% \"odd\"\nreally"
+        );
+        assert_eq!(
+            DiagnosticFormat::Github.format(&located),
+            "::warning file=a%2Cb%3Ac.conf,line=3,col=1::skipping d /x: 50%25 \"odd\"%0Areally"
+        );
+        assert_eq!(
+            DiagnosticFormat::Json.format(&located),
+            r#"{"file":"a,b:c.conf","line":3,"col":1,"severity":"warning","message":"skipping d /x: 50% \"odd\"\nreally"}"#
+        );
+
+        let unlocated = Diagnostic::new(Severity::Error, "failed");
+        assert_eq!(DiagnosticFormat::Human.format(&unlocated), "Error: failed");
+        assert_eq!(
+            DiagnosticFormat::Github.format(&unlocated),
+            "::error::failed"
+        );
+        assert_eq!(
+            DiagnosticFormat::Json.format(&unlocated),
+            r#"{"file":null,"line":null,"col":null,"severity":"error","message":"failed"}"#
+        );
+    }
+
+    #[test]
+    fn test_readable() {
+        assert_eq!(readable(b"d /caf\xc3\xa9 -"), "d /café -");
+        assert_eq!(readable(b"d /caf\xe9\t\\"), "d /caf\\xe9\\t\\");
+    }
+
+    #[test]
+    fn test_at_offset() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("a.conf");
+        fs::write(&file, b"# comment\nd /x\n  f /y\n").unwrap();
+        let diagnostic = Diagnostic::new(Severity::Warning, "").at_offset(&file, 17);
+        assert_eq!((diagnostic.line, diagnostic.col), (Some(3), Some(3)));
+
+        let diagnostic =
+            Diagnostic::new(Severity::Warning, "").at_offset(Path::new("--add-line"), 0);
+        assert_eq!(diagnostic.file.as_deref(), Some(Path::new("--add-line")));
+        assert_eq!((diagnostic.line, diagnostic.col), (None, None));
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    env,
+    error::Error,
+    ffi::{OsStr, OsString},
+    fmt,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+};
+
+use crate::config_file::Line;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EnvExpandError {
+    UnsetVariable(OsString),
+    UnterminatedReference,
+    EmptyVariableName,
+}
+
+impl fmt::Display for EnvExpandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsetVariable(name) => {
+                write!(f, "environment variable {name:?} is not set")
+            }
+            Self::UnterminatedReference => write!(f, "unterminated ${{...}} reference"),
+            Self::EmptyVariableName => write!(f, "empty variable name in ${{}} reference"),
+        }
+    }
+}
+
+impl Error for EnvExpandError {}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Expand `${VAR}` and `${VAR:-default}` references in `input`.
+///
+/// Like the shell, the default is used when the variable is unset or empty. Anything that isn't a
+/// braced reference, including a bare `$VAR`, is copied through untouched.
+fn expand_with(
+    input: &[u8],
+    lookup: &impl Fn(&OsStr) -> Option<OsString>,
+) -> Result<Vec<u8>, EnvExpandError> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut remaining = input;
+    while let Some(start) = find(remaining, b"${") {
+        output.extend_from_slice(&remaining[..start]);
+        let reference = &remaining[start + 2..];
+        let end = reference
+            .iter()
+            .position(|&ch| ch == b'}')
+            .ok_or(EnvExpandError::UnterminatedReference)?;
+        let (name, default) = match find(&reference[..end], b":-") {
+            Some(split) => (&reference[..split], Some(&reference[split + 2..end])),
+            None => (&reference[..end], None),
+        };
+        if name.is_empty() {
+            Err(EnvExpandError::EmptyVariableName)?
+        }
+        let name = OsStr::from_bytes(name);
+        match (lookup(name), default) {
+            (Some(value), None) => output.extend_from_slice(value.as_bytes()),
+            (Some(value), Some(_)) if !value.is_empty() => {
+                output.extend_from_slice(value.as_bytes())
+            }
+            (_, Some(default)) => output.extend_from_slice(default),
+            (None, None) => Err(EnvExpandError::UnsetVariable(name.to_owned()))?,
+        }
+        remaining = &reference[end + 1..];
+    }
+    output.extend_from_slice(remaining);
+    Ok(output)
+}
+
+fn expand_line_with(
+    line: &mut Line<'_>,
+    lookup: &impl Fn(&OsStr) -> Option<OsString>,
+) -> Result<(), EnvExpandError> {
+    // Specifiers were already split out by the parser, so each literal segment is expanded on its
+    // own and a reference can't span a specifier.
+    let path = &mut line.path.data;
+    path.0 = expand_with(&path.0, lookup)?;
+    for (_, segment) in path.1.iter_mut() {
+        *segment = expand_with(segment, lookup)?;
+    }
+    if let Some(argument) = &mut line.argument.data {
+        *argument = OsString::from_vec(expand_with(argument.as_bytes(), lookup)?);
+    }
+    Ok(())
+}
+
+/// Expand environment variable references in the path and argument of an already parsed line,
+/// reading values from the process environment.
+pub fn expand_line(line: &mut Line<'_>) -> Result<(), EnvExpandError> {
+    expand_line_with(line, &|name| env::var_os(name))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        ffi::{OsStr, OsString},
+        path::Path,
+    };
+
+    use crate::{
+        config_file::{Specifier, SpecifierString},
+        environment::{expand_line_with, expand_with, EnvExpandError},
+        parser::{parse_line, FileSpan},
+    };
+
+    fn lookup(name: &OsStr) -> Option<OsString> {
+        match name.to_str()? {
+            "USER" => Some("alice".into()),
+            "EMPTY" => Some("".into()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(
+            expand_with(b"/home/${USER}/x", &lookup),
+            Ok(b"/home/alice/x".to_vec())
+        );
+        assert_eq!(
+            expand_with(b"${USER}${USER}", &lookup),
+            Ok(b"alicealice".to_vec())
+        );
+        assert_eq!(expand_with(b"$USER", &lookup), Ok(b"$USER".to_vec()));
+    }
+
+    #[test]
+    fn test_expand_default() {
+        assert_eq!(
+            expand_with(b"${MISSING:-/tmp}", &lookup),
+            Ok(b"/tmp".to_vec())
+        );
+        assert_eq!(expand_with(b"${EMPTY:-x}", &lookup), Ok(b"x".to_vec()));
+        assert_eq!(expand_with(b"${EMPTY}", &lookup), Ok(b"".to_vec()));
+        assert_eq!(expand_with(b"${USER:-bob}", &lookup), Ok(b"alice".to_vec()));
+    }
+
+    #[test]
+    fn test_expand_errors() {
+        assert_eq!(
+            expand_with(b"${MISSING}", &lookup),
+            Err(EnvExpandError::UnsetVariable("MISSING".into()))
+        );
+        assert_eq!(
+            expand_with(b"/run/${USER", &lookup),
+            Err(EnvExpandError::UnterminatedReference)
+        );
+        assert_eq!(
+            expand_with(b"${:-x}", &lookup),
+            Err(EnvExpandError::EmptyVariableName)
+        );
+    }
+
+    #[test]
+    fn test_expand_line() {
+        let mut line = parse_line(FileSpan::from_slice(
+            b"L /run/${USER}/%m - - - - /home/${USER}",
+            Path::new(""),
+        ))
+        .unwrap();
+        expand_line_with(&mut line, &lookup).unwrap();
+        assert_eq!(
+            line.path.data,
+            SpecifierString(
+                b"/run/alice/".to_vec(),
+                [(Specifier::MachineID, Vec::new())].into()
+            )
+        );
+        assert_eq!(line.argument.data, Some(OsString::from("/home/alice")));
+    }
+}
@@ -0,0 +1,109 @@
+use std::{error::Error, fmt};
+
+/// Partition designators systemd accepts in an image policy, `=` sets the default for the rest
+const PARTITIONS: &[&str] = &[
+    "=",
+    "root",
+    "usr",
+    "home",
+    "srv",
+    "esp",
+    "xbootldr",
+    "swap",
+    "root-verity",
+    "root-verity-sig",
+    "usr-verity",
+    "usr-verity-sig",
+    "tmp",
+    "var",
+];
+
+const FLAGS: &[&str] = &[
+    "verity",
+    "signed",
+    "encrypted",
+    "unprotected",
+    "unused",
+    "absent",
+    "open",
+    "ignore",
+    "read-only-on",
+    "read-only-off",
+    "growfs-on",
+    "growfs-off",
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ImagePolicyError {
+    Malformed(String),
+    UnknownPartition(String),
+    UnknownFlag(String),
+}
+
+impl fmt::Display for ImagePolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed(rule) => write!(f, "expected partition=flags, got \"{rule}\""),
+            Self::UnknownPartition(name) => write!(f, "unknown partition \"{name}\""),
+            Self::UnknownFlag(flag) => write!(f, "unknown partition policy flag \"{flag}\""),
+        }
+    }
+}
+
+impl Error for ImagePolicyError {}
+
+/// Check that `policy` is written the way systemd's `--image-policy=` takes it: `*`, `-`, `~`, or
+/// rules like `root=verity+signed:=absent` separated by colons, with flags joined by `+`.
+pub fn validate_image_policy(policy: &str) -> Result<(), ImagePolicyError> {
+    if matches!(policy, "*" | "-" | "~") {
+        return Ok(());
+    }
+    for rule in policy.split(':').filter(|rule| !rule.is_empty()) {
+        let Some((partition, flags)) = rule.split_once('=') else {
+            Err(ImagePolicyError::Malformed(rule.to_owned()))?
+        };
+        // `=flags` sets the default for partitions without a rule of their own
+        let partition = if partition.is_empty() { "=" } else { partition };
+        if !PARTITIONS.contains(&partition) {
+            Err(ImagePolicyError::UnknownPartition(partition.to_owned()))?
+        }
+        if let Some(flag) = flags
+            .split('+')
+            .find(|flag| !flag.is_empty() && !FLAGS.contains(flag))
+        {
+            Err(ImagePolicyError::UnknownFlag(flag.to_owned()))?
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::image_policy::{validate_image_policy, ImagePolicyError};
+
+    #[test]
+    fn test_validate_image_policy() {
+        for policy in [
+            "*",
+            "-",
+            "~",
+            "root=verity+signed:usr=absent",
+            "=unused",
+            "",
+        ] {
+            assert_eq!(validate_image_policy(policy), Ok(()), "{policy}");
+        }
+        assert_eq!(
+            validate_image_policy("root"),
+            Err(ImagePolicyError::Malformed("root".to_owned()))
+        );
+        assert_eq!(
+            validate_image_policy("boot=verity"),
+            Err(ImagePolicyError::UnknownPartition("boot".to_owned()))
+        );
+        assert_eq!(
+            validate_image_policy("root=verity+fast"),
+            Err(ImagePolicyError::UnknownFlag("fast".to_owned()))
+        );
+    }
+}
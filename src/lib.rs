@@ -1,2 +1,14 @@
-mod config_file;
+mod acl;
+pub mod apply;
+mod attr;
+pub mod config_file;
+mod diagnostic;
 pub mod parser;
+mod quota;
+mod remove;
+mod selinux;
+pub mod specifier;
+pub mod statistics;
+pub mod syscalls;
+mod users;
+mod xattr;
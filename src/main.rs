@@ -1,19 +1,43 @@
+mod acl;
+mod apply;
+mod attr;
+mod check;
 mod config_file;
+mod diagnostic;
+mod environment;
+mod image_policy;
 mod parser;
+mod quota;
+mod remove;
+mod selinux;
+mod specifier;
+mod statistics;
+mod syscalls;
+mod users;
+mod xattr;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use config_file::Line;
+use eyre::eyre;
 use std::{
+    cell::RefCell,
     collections::BTreeMap,
+    env,
     error::Error,
-    ffi::{OsStr, OsString},
+    ffi::OsString,
     fs,
-    io::{self, Write},
+    io::{self, IsTerminal, Read, Write},
     os::unix::ffi::OsStrExt,
     path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use crate::parser::{parse_line, FileSpan};
+use crate::{
+    diagnostic::{Diagnostic, DiagnosticFormat, Severity},
+    parser::{parse_line_with, FileSpan, ParseOptions},
+    specifier::{SpecifierContext, SpecifierSource},
+    statistics::Statistics,
+};
 
 #[derive(Parser, Debug)]
 #[command(version, about = "Standalone replacement for systemd-tmpfiles", long_about = None)]
@@ -27,162 +51,806 @@ struct Args {
     /// Remove directories and files, unless they are locked
     #[arg(long)]
     remove: bool,
-    /// Also execute lines meant only to be run on boot
+    /// Only correct the mode, ownership, and other metadata of paths that already exist, without
+    /// creating, cleaning, or removing anything
+    #[arg(long, conflicts_with_all = ["create", "clean", "remove"])]
+    repair: bool,
+    /// Also execute lines meant only to be run on boot, including appending with w+
     #[arg(long)]
     boot: bool,
+    /// Keep applying the remaining lines after one fails, and exit with an error at the end
+    #[arg(long)]
+    keep_going: bool,
+    /// Skip lines using os-release fields that aren't set, like %B without BUILD_ID, instead of
+    /// failing
+    #[arg(long)]
+    graceful: bool,
+    /// Let z and Z change the target of a symlink at the path of a line instead of leaving it
+    /// alone. Symlinks below a Z path are still never followed.
+    #[arg(long)]
+    dereference: bool,
+    /// Ask before removing anything while cleaning or removing
+    #[arg(long)]
+    interactive: bool,
+    /// With --interactive, remove without asking when stdin isn't a terminal instead of keeping
+    #[arg(long, requires = "interactive")]
+    force: bool,
+    /// Remove everything cleaning or removing would, without asking, even with --interactive
+    #[arg(long, conflicts_with = "assume_no")]
+    assume_yes: bool,
+    /// Keep everything cleaning or removing would remove, without asking, and report each path
+    /// kept
+    #[arg(long)]
+    assume_no: bool,
+    /// Don't print warnings, only errors
+    #[arg(long, short)]
+    quiet: bool,
+    /// Print more about what's happening, like configuration files that are skipped and what
+    /// applying each line did
+    #[arg(long, short, conflicts_with = "quiet")]
+    verbose: bool,
     /// Print the contents of files to apply
     #[arg(long)]
     cat_config: bool,
+    /// Print the path of each configuration file that would be applied, in order
+    #[arg(long, conflicts_with_all = ["create", "clean", "remove", "cat_config"])]
+    list_files: bool,
+    /// Print every path the configuration manages with the file and line defining it, sorted by
+    /// path, without applying anything
+    #[arg(long, conflicts_with_all = ["create", "clean", "remove", "cat_config", "list_files"])]
+    catalog: bool,
+    /// How --catalog prints each path
+    #[arg(long, value_enum, default_value_t = CatalogFormat::Tsv, requires = "catalog")]
+    format: CatalogFormat,
+    /// Print how each line was parsed, with where each field came from, without applying anything
+    #[arg(long, conflicts_with_all = ["create", "clean", "remove", "cat_config", "list_files"])]
+    dump_parsed: bool,
+    /// Print whether each managed path matches the configuration, without changing anything
+    #[arg(long, conflicts_with_all = ["create", "clean", "remove", "cat_config", "list_files"])]
+    merge_check: bool,
+    /// Print the path and type of each line after resolving its specifiers, users, and groups,
+    /// without applying anything, and report every line that would fail to resolve
+    #[arg(long, conflicts_with_all = ["create", "clean", "remove", "cat_config", "list_files"])]
+    dry_run: bool,
+    /// Expand ${VAR} and ${VAR:-default} in paths and arguments from the environment
+    #[arg(long)]
+    expand_env: bool,
+
+    /// Leave unrecognized specifiers like %y in paths as literal text instead of failing
+    #[arg(long)]
+    keep_unknown_specifiers: bool,
+    /// Only apply lines from the configuration file with this name or path
+    #[arg(long, value_name = "FILE")]
+    source_filter: Option<PathBuf>,
+    /// Apply a configuration line given on the command line after the configuration files, can
+    /// be repeated
+    #[arg(long, value_name = "LINE")]
+    add_line: Vec<OsString>,
+
+    /// Operate on an alternate root directory
+    ///
+    /// The path of every line and the source of copies are taken to be below the root, symlink
+    /// targets are written as given.
+    #[arg(long)]
+    root: Option<PathBuf>,
+    /// Accepted for compatibility with systemd-tmpfiles, but disk images aren't supported, so any
+    /// valid policy is rejected as unsupported rather than silently not enforced
+    #[arg(long, value_name = "POLICY")]
+    image_policy: Option<String>,
+    /// With --root, copy C sources from the running system instead of from inside the root
+    #[arg(long, requires = "root")]
+    copy_from_host: bool,
+    /// Where to read specifier values such as the machine ID from when using --root
+    ///
+    /// With `target`, the machine ID (%m), os-release fields (%o, %w, %W, %A, %B, %M) and user and
+    /// group names (%u, %g, %h) are read from files inside the root. The boot ID (%b), hostname
+    /// (%H, %l), kernel release (%v) and architecture (%a) are not stored in the tree, so they
+    /// always describe the running host.
+    #[arg(long, value_enum, default_value_t = SpecifierSource::Host, requires = "root")]
+    resolve_specifiers: SpecifierSource,
+    /// How many directory levels below a line's direct entries recursive actions and cleaning go
+    ///
+    /// Cleaning leaves anything deeper alone, so 0 only cleans the direct entries of a directory.
+    /// Recursive actions like `Z` fail on deeper trees instead.
+    #[arg(long, value_name = "LEVELS", default_value_t = apply::DEFAULT_MAX_DEPTH)]
+    max_depth: usize,
+    /// How many lines may be applied at once
+    ///
+    /// Lines are always applied one at a time in the order they're configured, so this changes
+    /// nothing, and only 1 is accepted for now. Scripts can pass it already, and their output will
+    /// be in the same order whatever it's set to.
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = parse_jobs)]
+    jobs: usize,
+    /// Only let cleaning and removing delete things below these paths, separated by commas
+    ///
+    /// A line whose path, with specifiers expanded, is outside all of them fails instead. Without
+    /// this, any path is allowed.
+    #[arg(long, value_name = "PATHS", value_delimiter = ',')]
+    safe_roots: Vec<PathBuf>,
+    /// Apply lines whose path is the root directory itself, which are refused otherwise
+    #[arg(long)]
+    allow_root_path: bool,
+    /// Give what's created or adjusted the default SELinux context for its path, when SELinux is
+    /// enforcing. Does nothing when built without the `selinux` feature.
+    #[arg(long)]
+    relabel: bool,
+    /// How warnings and errors about lines are printed to stderr, `github` and `json` give the
+    /// file, line, and column of each for tools to pick up
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = DiagnosticFormat::Human)]
+    diagnostic_format: DiagnosticFormat,
+    /// Print how long applying took and the slowest lines to stderr when done
+    #[arg(long)]
+    statistics: bool,
+    /// Seconds since the epoch to give files and nodes that are created or written, instead of the
+    /// current time. Defaults to $SOURCE_DATE_EPOCH when that's set.
+    #[arg(long, value_name = "SECONDS")]
+    timestamp: Option<u64>,
+    /// Seconds since the epoch to use as the current time when cleaning
+    #[arg(long, hide = true)]
+    now: Option<u64>,
+
+    /// Apply this file whatever its name, after the files found in the positional sources, can be
+    /// repeated
+    ///
+    /// Files given this way are applied in the order given, and each one is applied even if a file
+    /// from the positional sources has the same name.
+    #[arg(long, value_name = "PATH")]
+    config_file: Vec<PathBuf>,
 
-    /// Files or directories to apply
-    #[arg(default_value = "/etc/tmpfiles.d")]
+    /// Print the directories searched for configuration files when no sources are given, most
+    /// important first, then exit
+    #[arg(long, conflicts_with_all = ["create", "clean", "remove", "cat_config", "list_files"])]
+    print_config_dirs: bool,
+    /// Search the per-user configuration directories instead of the system ones
+    #[arg(long)]
+    user: bool,
+
+    /// Files or directories to apply, ordered together by file name. `-` reads a file from stdin.
+    /// Defaults to the directories shown by --print-config-dirs unless --config-file is given.
     config_sources: Vec<PathBuf>,
+    /// Also find configuration files in subdirectories of the directories searched, at any depth
+    ///
+    /// They're ordered and replace each other by file name like the rest, wherever they are.
+    #[arg(long)]
+    recursive_sources: bool,
+    /// Files or directories given after `--`, where `-` is a file by that name rather than stdin
+    #[arg(last = true, value_name = "SOURCES")]
+    literal_sources: Vec<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Output formats of `--catalog`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum CatalogFormat {
+    /// Path, file:line, and type, tab separated, marking paths claimed by more than one line
+    Tsv,
+    /// A header, then path, type, mode, owner, group, age, source file, and line number
+    Csv,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Parse one configuration line and print its path, mode, owner, and group with specifiers
+    /// and names resolved, without applying it
+    Expand { line: OsString },
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
 
-    let config_files = find_config_files(&args.config_sources)?;
+    if let Some(policy) = &args.image_policy {
+        image_policy::validate_image_policy(policy)
+            .map_err(|e| eyre!("Invalid image policy {policy}: {e}"))?;
+        Err(eyre!(
+            "Unsupported policy {policy}: disk images aren't supported, so it can't be enforced"
+        ))?
+    }
+
+    let options = ParseOptions {
+        keep_unknown_specifiers: args.keep_unknown_specifiers,
+    };
+    if let Some(Command::Expand { line }) = &args.command {
+        let root = args.root.clone().unwrap_or_else(|| PathBuf::from("/"));
+        let context = apply::Context::new(root, args.resolve_specifiers);
+        expand(line, options, &context)?;
+        return Ok(());
+    }
+
+    let root = args.root.clone().unwrap_or_else(|| PathBuf::from("/"));
+    let config_dirs = config_dirs(&root, args.user, &|name| env::var_os(name));
+    if args.print_config_dirs {
+        let mut stdout = io::stdout().lock();
+        for dir in &config_dirs {
+            stdout.write_all(dir.as_os_str().as_bytes())?;
+            stdout.write_all(b"\n")?;
+        }
+        return Ok(());
+    }
+
+    let config_files = config_files(
+        &config_sources(&args.config_sources, &args.literal_sources),
+        &config_dirs,
+        &args.config_file,
+        args.recursive_sources,
+        args.verbose,
+    )?;
+
+    if args.list_files {
+        list_files(&config_files)?;
+        return Ok(());
+    }
 
     if args.cat_config {
         if args.remove || args.clean || args.create {
             todo!("--cat-config cannot be used with create, remove, or clean")
         }
-        cat_config(&config_files)?;
+        cat_config(&config_files, args.quiet, args.diagnostic_format)?;
+        return Ok(());
+    }
+
+    let mut config = parsed_config(&config_files, options, args.diagnostic_format)?;
+    if let Some(filter) = &args.source_filter {
+        config.retain(|line| {
+            let file = line.path.file();
+            file == filter || file.file_name() == Some(filter.as_os_str())
+        });
+    }
+    let (added, errors) = parse_added_lines(&args.add_line, options);
+    report_parse_errors(&errors, args.diagnostic_format)?;
+    config.extend(added);
+    if args.expand_env {
+        for line in &mut config {
+            environment::expand_line(line)?;
+        }
+    }
+
+    if args.dump_parsed {
+        println!("{config:#?}");
+        return Ok(());
+    }
+
+    let mut context = apply::Context::new(root, args.resolve_specifiers);
+    if args.statistics {
+        context.statistics = Some(RefCell::new(Statistics::new()));
+    }
+    context.boot = args.boot;
+    context.quiet = args.quiet;
+    context.max_depth = args.max_depth;
+    context.keep_going = args.keep_going;
+    context.graceful = args.graceful;
+    context.dereference = args.dereference;
+    context.safe_roots = args.safe_roots;
+    context.allow_root_path = args.allow_root_path;
+    context.copy_from_host = args.copy_from_host;
+    context.diagnostics = args.diagnostic_format;
+    context.credentials = env::var_os("CREDENTIALS_DIRECTORY").map(PathBuf::from);
+    if args.relabel {
+        context.labels = selinux::Labels::open()?;
+    }
+    let timestamp = match (args.timestamp, env::var_os("SOURCE_DATE_EPOCH")) {
+        (Some(timestamp), _) => Some(timestamp),
+        (None, Some(epoch)) => Some(
+            epoch
+                .to_str()
+                .and_then(|epoch| epoch.parse().ok())
+                .ok_or_else(|| eyre!("Invalid SOURCE_DATE_EPOCH {epoch:?}"))?,
+        ),
+        (None, None) => None,
+    };
+    context.timestamp = timestamp.map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+    // Confirming everything is the same as not asking
+    if args.assume_no {
+        context.confirm = Some(decline(io::stderr()));
+    } else if args.interactive && !args.assume_yes {
+        context.confirm = Some(prompt(args.force));
+    }
+
+    // Before owners are expanded, since a line failing that is what this reports
+    if args.dry_run {
+        return Ok(dry_run(&config, &context, &mut io::stdout().lock())?);
+    }
+
+    let mut expanded = Vec::with_capacity(config.len());
+    for mut line in config {
+        match context.expand_owners(&mut line) {
+            Ok(()) => expanded.push(line),
+            Err(e) => context.skip_gracefully(&line, e)?,
+        }
+    }
+    let config = expanded;
+
+    if args.catalog {
+        catalog(
+            &config,
+            &context.specifiers,
+            args.format,
+            &mut io::stdout().lock(),
+        )?;
         return Ok(());
     }
 
-    let config = parsed_config(&config_files)?;
+    if args.merge_check {
+        let mut stdout = io::stdout().lock();
+        for (path, status) in check::merge_check(&config, &context)? {
+            stdout.write_all(path.as_os_str().as_bytes())?;
+            writeln!(stdout, "\t{status}")?;
+        }
+        return Ok(());
+    }
 
+    let mut outcomes = Vec::new();
     if args.remove {
-        todo!("Removal is not yet implemented")
+        outcomes.extend(apply::remove(&config, &context)?);
     }
     if args.clean {
-        todo!("Cleaning is not yet implemented")
+        let now = args
+            .now
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap_or_else(SystemTime::now);
+        outcomes.extend(apply::clean(&config, &context, now)?);
     }
     if args.create {
-        create(&config)?;
+        outcomes.extend(apply::create(&config, &context)?);
+    }
+    if args.repair {
+        outcomes.extend(apply::repair(&config, &context)?);
+    }
+
+    if args.verbose {
+        for outcome in &outcomes {
+            eprintln!("{outcome}");
+        }
+    }
+    if let Some(statistics) = context.statistics {
+        eprint!("{}", statistics.into_inner());
+    }
+    let failures = outcomes
+        .iter()
+        .filter(|line| matches!(line.outcome, apply::Outcome::Failed(_)))
+        .count();
+    match failures {
+        0 => {}
+        1 => Err(eyre!("1 line failed to apply"))?,
+        failures => Err(eyre!("{failures} lines failed to apply"))?,
     }
 
     Ok(())
 }
 
-fn parsed_config(config_files: &BTreeMap<OsString, PathBuf>) -> eyre::Result<Vec<Line>> {
-    let mut config = Vec::new();
-    for file_path in config_files.values() {
-        let file = fs::read(file_path)?;
-        let span = FileSpan::from_slice(&file, file_path);
-        for line in span.lines() {
-            if line.bytes().starts_with(b"#") || line.bytes().is_empty() {
-                continue;
-            } else {
-                let line = parse_line(line.clone()).unwrap_or_else(|e| {
-                    todo!(
-                        "Error parsing line: {e:#?} ({})",
-                        line.bytes().escape_ascii()
-                    )
-                });
-                config.push(line);
+/// Parse `--jobs`, refusing anything other than 1 until lines can be applied in parallel
+fn parse_jobs(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(1) => Ok(1),
+        Ok(0) => Err("must be at least 1".into()),
+        Ok(_) => Err("lines are applied one at a time, so only 1 is supported".into()),
+        Err(e) => Err(format!("{e}")),
+    }
+}
+
+/// Print the path and type of every line that resolves, and report each line that doesn't with
+/// why like other diagnostics, failing at the end if any didn't
+fn dry_run(config: &[Line], context: &apply::Context, out: &mut impl Write) -> eyre::Result<()> {
+    let mut failures = 0;
+    for line in config {
+        let action = line.line_type.data.action.type_char();
+        match check::preflight(line, context) {
+            Ok(path) => {
+                out.write_all(path.as_os_str().as_bytes())?;
+                writeln!(out, "\t{action}")?;
             }
+            Err(e) => {
+                failures += 1;
+                context.report(line, format_args!("{line} would fail: {e:#}"));
+            }
+        }
+    }
+    match failures {
+        0 => Ok(()),
+        1 => Err(eyre!("1 line would fail to apply")),
+        failures => Err(eyre!("{failures} lines would fail to apply")),
+    }
+}
+
+/// The source that stands for stdin when given before `--`
+const STDIN: &str = "-";
+
+/// The sources to apply, with those given after `--` taken as paths, so a `-` there is a file in
+/// the current directory
+fn config_sources(sources: &[PathBuf], literal: &[PathBuf]) -> Vec<PathBuf> {
+    let literal = literal.iter().map(|source| {
+        if source.as_os_str() == STDIN {
+            Path::new(".").join(source)
+        } else {
+            source.clone()
         }
+    });
+    sources.iter().cloned().chain(literal).collect()
+}
+
+/// Open a configuration file to read, or stdin for `-`
+fn open_config(path: &Path) -> io::Result<Box<dyn io::Read>> {
+    if path.as_os_str() == STDIN {
+        Ok(Box::new(io::stdin().lock()))
+    } else {
+        Ok(Box::new(fs::File::open(path)?))
     }
+}
+
+/// Parse every line of the configuration files, reporting each line that fails to parse before
+/// failing
+fn parsed_config(
+    config_files: &[PathBuf],
+    options: ParseOptions,
+    diagnostics: DiagnosticFormat,
+) -> eyre::Result<Vec<Line<'_>>> {
+    let mut config = Vec::new();
+    let mut all_errors = Vec::new();
+    for file_path in config_files {
+        let mut file = Vec::new();
+        open_config(file_path)?.read_to_end(&mut file)?;
+        let (lines, errors) = parse_config_file(&file, file_path, options);
+        config.extend(lines);
+        all_errors.extend(errors);
+    }
+    report_parse_errors(&all_errors, diagnostics)?;
     Ok(config)
 }
 
-fn create(config: &[Line]) -> eyre::Result<()> {
+/// Print every error from parsing, then fail if there were any
+fn report_parse_errors(errors: &[Diagnostic], diagnostics: DiagnosticFormat) -> eyre::Result<()> {
+    for error in errors {
+        diagnostics.emit(error);
+    }
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(eyre!("1 line failed to parse")),
+        failures => Err(eyre!("{failures} lines failed to parse")),
+    }
+}
+
+/// Parse the lines given with `--add-line`, along with an error for each one that fails, located
+/// by its position among them like a line in a file
+fn parse_added_lines(
+    lines: &[OsString],
+    options: ParseOptions,
+) -> (Vec<Line<'static>>, Vec<Diagnostic>) {
+    let file_path = Path::new("--add-line");
+    let mut config = Vec::new();
+    let mut errors = Vec::new();
+    for (index, line) in lines.iter().enumerate() {
+        match parse_line_with(FileSpan::from_slice(line.as_bytes(), file_path), options) {
+            Ok(line) => config.push(line),
+            Err(e) => {
+                let message = format!("{e} ({})", diagnostic::readable(line.as_bytes()));
+                errors.push(Diagnostic::new(Severity::Error, message).at_line(
+                    file_path,
+                    index + 1,
+                    1,
+                ));
+            }
+        }
+    }
+    (config, errors)
+}
+
+/// Parse the data lines of one configuration file, along with an error for each line that fails
+fn parse_config_file<'b>(
+    file: &[u8],
+    file_path: &'b Path,
+    options: ParseOptions,
+) -> (Vec<Line<'b>>, Vec<Diagnostic>) {
+    let mut config = Vec::new();
+    let mut errors = Vec::new();
+    let span = FileSpan::from_slice(file, file_path);
+    for (number, line) in span.lines().filter(|(_, line)| line.is_data_line()) {
+        match parse_line_with(line.clone(), options) {
+            Ok(line) => config.push(line),
+            Err(e) => {
+                let message = format!("{e} ({})", diagnostic::readable(line.bytes()));
+                errors
+                    .push(Diagnostic::new(Severity::Error, message).at_line(file_path, number, 1));
+            }
+        }
+    }
+    (config, errors)
+}
+
+/// Print the absolute path of each configuration file, one per line in application order
+fn list_files(config_files: &[PathBuf]) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    for path in config_files {
+        stdout.write_all(std::path::absolute(path)?.as_os_str().as_bytes())?;
+        stdout.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Keep every path, reporting each one to `report`
+fn decline(report: impl Write + 'static) -> apply::Confirm {
+    let report = RefCell::new(report);
+    Box::new(move |path, reason| {
+        // Failing to report shouldn't make us remove anything
+        let _ = writeln!(
+            report.borrow_mut(),
+            "Keeping {} ({reason}), --assume-no declines removing it",
+            path.display()
+        );
+        false
+    })
+}
+
+/// Ask on the terminal whether to remove each path. Without a terminal to ask, everything is kept
+/// unless `force` is set.
+fn prompt(force: bool) -> apply::Confirm {
+    let terminal = io::stdin().is_terminal();
+    Box::new(move |path, reason| {
+        if !terminal {
+            return force;
+        }
+        eprint!("Remove {} ({reason})? [y/N] ", path.display());
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer).is_ok() && matches!(answer.trim(), "y" | "Y" | "yes")
+    })
+}
+
+/// Print the fields of one line, tab separated, with specifiers expanded and owners looked up
+fn expand(line: &OsString, options: ParseOptions, context: &apply::Context) -> eyre::Result<()> {
+    let span = FileSpan::from_slice(line.as_bytes(), Path::new("expand"));
+    let mut line = parse_line_with(span, options).map_err(|e| {
+        eyre!(
+            "Error parsing line: {e} ({})",
+            diagnostic::readable(line.as_bytes())
+        )
+    })?;
+    context.expand_owners(&mut line)?;
+    let path = context.line_path(&line)?;
+    let owner = line
+        .owner
+        .data
+        .as_ref()
+        .map(|owner| -> eyre::Result<String> {
+            let uid = users::resolve_user(owner)?;
+            let name = users::user_by_uid(uid).map_or_else(|_| Vec::new(), |(name, _)| name);
+            Ok(format!("{} ({uid})", name.escape_ascii()))
+        })
+        .transpose()?;
+    let group = line
+        .group
+        .data
+        .as_ref()
+        .map(|group| -> eyre::Result<String> {
+            let gid = users::resolve_group(group)?;
+            let name = users::group_name_by_gid(gid).unwrap_or_default();
+            Ok(format!("{} ({gid})", name.escape_ascii()))
+        })
+        .transpose()?;
+
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "type\t{}", line.line_type.data.action.type_char())?;
+    stdout.write_all(b"path\t")?;
+    stdout.write_all(path.as_os_str().as_bytes())?;
+    writeln!(stdout)?;
+    let dash = || "-".to_owned();
+    let mode = line.mode.data.as_ref().map(|mode| mode.to_string());
+    writeln!(stdout, "mode\t{}", mode.unwrap_or_else(dash))?;
+    writeln!(stdout, "owner\t{}", owner.unwrap_or_else(dash))?;
+    writeln!(stdout, "group\t{}", group.unwrap_or_else(dash))?;
+    let age = line.age.data.as_ref().map(|age| age.to_string());
+    writeln!(stdout, "age\t{}", age.unwrap_or_else(dash))?;
+    if let Some(argument) = &line.argument.data {
+        stdout.write_all(b"argument\t")?;
+        stdout.write_all(argument.as_bytes())?;
+        writeln!(stdout)?;
+    }
+    Ok(())
+}
+
+/// Print each managed path, the file and line that defines it, and its type, sorted by path. As
+/// TSV, paths claimed by more than one line are marked as conflicts.
+fn catalog(
+    config: &[Line],
+    specifiers: &SpecifierContext,
+    format: CatalogFormat,
+    out: &mut impl Write,
+) -> eyre::Result<()> {
+    // Spans only store byte offsets, so count lines in the files again
+    let mut contents = BTreeMap::new();
+    let mut entries = Vec::new();
     for line in config {
-        let line_type = line.line_type.data;
-        match line_type.action {
-            config_file::LineAction::CreateFile => todo!(),
-            config_file::LineAction::WriteFile => todo!(),
-            config_file::LineAction::CreateAndCleanUpDirectory => todo!(),
-            config_file::LineAction::CreateAndRemoveDirectory => todo!(),
-            config_file::LineAction::CleanUpDirectory => todo!(),
-            config_file::LineAction::CreateFifo => todo!(),
-            config_file::LineAction::CreateSymlink => {
-                if line_type.boot || line_type.force || line_type.noerror || !line_type.recreate {
-                    todo!()
-                }
-                let target = line.argument.data.as_ref().unwrap();
-                let link = Path::new(OsStr::from_bytes(&line.path.data.0));
-                if target.as_bytes().contains(&b'%') {
-                    todo!("Specifiers in symlink target not yet implemented")
-                } else if !line.path.data.1.is_empty() {
-                    todo!("Specifiers in symlink path not yet implemented")
-                }
-                let target = Path::new(target);
-                match fs::symlink_metadata(link) {
-                    Ok(meta) => {
-                        if meta.is_dir() {
-                            // fs::remove_dir_all(target);
-                            todo!("Currently won't clobber directories to create symlinks")
-                        } else if meta.is_file() {
-                            fs::remove_file(link)?;
-                        } else if meta.is_symlink() {
-                            let existing_target = fs::read_link(link)?;
-                            if existing_target != target {
-                                fs::remove_file(link)?;
-                            } else {
-                                continue;
-                            }
-                        } else {
-                            todo!("Won't clobber things other than files, directories, or symlinks")
-                        }
-                    }
-                    Err(e) => match e.kind() {
-                        io::ErrorKind::NotFound => {}
-                        _ => todo!(),
-                    },
-                }
-                std::os::unix::fs::symlink(Path::new(target), link)?;
+        let path = line.path.data.expand(specifiers)?;
+        let file = line.path.file();
+        let offset = line.line_type.characters().start;
+        // Lines from --add-line have no file to read, and stdin can't be read again
+        let number = contents
+            .entry(file)
+            .or_insert_with(|| {
+                (file.as_os_str() != STDIN)
+                    .then(|| fs::read(file).ok())
+                    .flatten()
+            })
+            .as_ref()
+            .map(|contents| contents[..offset].iter().filter(|&&ch| ch == b'\n').count() + 1);
+        entries.push((path, number, line));
+    }
+    // Stable, so lines claiming the same path stay in application order
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    if format == CatalogFormat::Csv {
+        writeln!(out, "path,type,mode,owner,group,age,source_file,line")?;
+    }
+    for claims in entries.chunk_by(|a, b| a.0 == b.0) {
+        for (path, number, line) in claims {
+            let file = line.path.file();
+            let action = line.line_type.data.action.type_char();
+            if format == CatalogFormat::Csv {
+                let field = |field: Option<String>| csv_field(field.unwrap_or_default().as_bytes());
+                let fields = [
+                    csv_field(path),
+                    csv_field(action.to_string().as_bytes()),
+                    field(line.mode.data.as_ref().map(ToString::to_string)),
+                    field(line.owner.data.as_ref().map(ToString::to_string)),
+                    field(line.group.data.as_ref().map(ToString::to_string)),
+                    field(line.age.data.as_ref().map(ToString::to_string)),
+                    csv_field(file.as_os_str().as_bytes()),
+                    field(number.map(|number| number.to_string())),
+                ];
+                out.write_all(&fields.join(&b',')[..])?;
+                writeln!(out)?;
+                continue;
+            }
+            out.write_all(path)?;
+            match number {
+                Some(number) => write!(out, "\t{}:{number}", file.display())?,
+                None => write!(out, "\t{}", file.display())?,
             }
-            config_file::LineAction::CreateCharDevice => todo!(),
-            config_file::LineAction::CreateBlockDevice => todo!(),
-            config_file::LineAction::Copy => todo!(),
-            config_file::LineAction::Ignore => todo!(),
-            config_file::LineAction::IgnoreNonRecursive => todo!(),
-            config_file::LineAction::Remove => todo!(),
-            config_file::LineAction::RemoveRecursive => todo!(),
-            config_file::LineAction::SetMode => todo!(),
-            config_file::LineAction::SetModeRecursive => todo!(),
-            config_file::LineAction::SetXattr => todo!(),
-            config_file::LineAction::SetXattrRecursive => todo!(),
-            config_file::LineAction::SetAttr => todo!(),
-            config_file::LineAction::SetAttrRecursive => todo!(),
-            config_file::LineAction::SetAcl => todo!(),
-            config_file::LineAction::SetAclRecursive => todo!(),
+            write!(out, "\t{action}")?;
+            if claims.len() > 1 {
+                write!(out, "\tconflict")?;
+            }
+            writeln!(out)?;
         }
     }
     Ok(())
 }
 
+/// Quote a CSV field if it has a comma, quote, or line break in it, doubling any quotes
+fn csv_field(field: &[u8]) -> Vec<u8> {
+    if !field
+        .iter()
+        .any(|ch| matches!(ch, b',' | b'"' | b'\n' | b'\r'))
+    {
+        return field.to_vec();
+    }
+    let mut quoted = vec![b'"'];
+    for &ch in field {
+        if ch == b'"' {
+            quoted.push(b'"');
+        }
+        quoted.push(ch);
+    }
+    quoted.push(b'"');
+    quoted
+}
+
 /// Print the output of each configuration file, without reencoding
-fn cat_config(config_files: &BTreeMap<OsString, PathBuf>) -> io::Result<()> {
-    println!("# WARNING: --cat-config is vulnerable to a TOCTOU attack, do not use for security purposes");
+fn cat_config(
+    config_files: &[PathBuf],
+    quiet: bool,
+    diagnostics: DiagnosticFormat,
+) -> io::Result<()> {
+    // On stderr, so the output can be piped somewhere as is
+    if !quiet {
+        diagnostics.emit(&Diagnostic::new(
+            Severity::Warning,
+            "--cat-config is vulnerable to a TOCTOU attack, do not use for security purposes",
+        ));
+    }
 
     // We need to write raw bytes. This is somewhat unsafe due to delete escape codes but I don't
     // want to unescape then escape to fix it.
     let mut stdout = io::stdout().lock();
 
-    for (_, path) in config_files.iter() {
+    for path in config_files {
+        // Streamed rather than read whole, and opened first so a file we can't read doesn't
+        // leave its header behind
+        let mut file = open_config(path)?;
         stdout.write_all(b"# ")?;
         stdout.write_all(path.as_os_str().as_encoded_bytes())?;
         stdout.write_all(b"\n")?;
-        stdout.write_all(&fs::read(path)?)?
+        io::copy(&mut file, &mut stdout)?;
     }
     stdout.write_all(b"\n")?;
 
     Ok(())
 }
 
-fn find_config_files(config_sources: &[PathBuf]) -> io::Result<BTreeMap<OsString, PathBuf>> {
-    // We have to apply in lexographic order, so use a BTreeMap to stay sorted
+/// The directories searched for configuration files by default below `root`, most important
+/// first. A file in one of them hides files with the same name in the ones after it. User
+/// directories follow the XDG base directory variables, read through `lookup`.
+fn config_dirs(root: &Path, user: bool, lookup: &dyn Fn(&str) -> Option<OsString>) -> Vec<PathBuf> {
+    let dirs = if user {
+        // Relative values are invalid per the XDG spec and ignored
+        let xdg = |name: &str| {
+            lookup(name)
+                .map(PathBuf::from)
+                .filter(|dir| dir.is_absolute())
+        };
+        let home = lookup("HOME").map(PathBuf::from);
+        let config_home = xdg("XDG_CONFIG_HOME").or_else(|| Some(home.as_ref()?.join(".config")));
+        let data_home = xdg("XDG_DATA_HOME").or_else(|| Some(home.as_ref()?.join(".local/share")));
+        [config_home, xdg("XDG_RUNTIME_DIR"), data_home]
+            .into_iter()
+            .flatten()
+            .chain(["/usr/local/share".into(), "/usr/share".into()])
+            .map(|dir| dir.join("user-tmpfiles.d"))
+            .collect::<Vec<_>>()
+    } else {
+        [
+            "/etc/tmpfiles.d",
+            "/run/tmpfiles.d",
+            "/usr/local/lib/tmpfiles.d",
+            "/usr/lib/tmpfiles.d",
+        ]
+        .into_iter()
+        .map(PathBuf::from)
+        .collect()
+    };
+    dirs.into_iter()
+        .map(|dir| root.join(dir.strip_prefix("/").unwrap()))
+        .collect()
+}
+
+/// Every configuration file to apply, in order: the files found in `config_sources`, or in the
+/// existing `config_dirs` if there are no sources at all, then each of `explicit` as given
+fn config_files(
+    config_sources: &[PathBuf],
+    config_dirs: &[PathBuf],
+    explicit: &[PathBuf],
+    recursive: bool,
+    verbose: bool,
+) -> io::Result<Vec<PathBuf>> {
+    let defaults;
+    let config_sources = if config_sources.is_empty() && explicit.is_empty() {
+        // Later sources replace files with the same name, so the most important directory goes
+        // last
+        defaults = config_dirs
+            .iter()
+            .rev()
+            .filter(|dir| dir.is_dir())
+            .cloned()
+            .collect::<Vec<_>>();
+        &defaults
+    } else {
+        config_sources
+    };
+    Ok(find_config_files(config_sources, recursive, verbose)?
+        .into_values()
+        .chain(explicit.iter().cloned())
+        .collect())
+}
+
+/// Find the configuration files to apply, keyed by file name in the order they're applied.
+///
+/// Like systemd, which compares file names with `strcmp`, this orders them by their raw bytes, not
+/// by locale or version numbers, so `10-a.conf` comes before `9-a.conf`.
+///
+/// Symlinks that don't lead to a file are skipped, and with `verbose` reported on stderr. With
+/// `recursive`, files in subdirectories of a source are found too and ordered by their name just
+/// the same, while systemd only looks at the files directly in each directory.
+fn find_config_files(
+    config_sources: &[PathBuf],
+    recursive: bool,
+    verbose: bool,
+) -> io::Result<BTreeMap<OsString, PathBuf>> {
+    // OsString compares raw bytes on Unix, so a BTreeMap keeps the order systemd uses
     let mut config_files = BTreeMap::new();
 
     for config_source in config_sources {
-        if config_source.is_file() {
-            // We already know it exists and is a file, the kernel would have told us if it ended
-            // in `..`, so just unwrap
+        if config_source.as_os_str() == STDIN || config_source.is_file() {
+            // Stdin goes by `-`, and otherwise we already know it exists and is a file, the kernel
+            // would have told us if it ended in `..`, so just unwrap
             config_files.insert(
                 config_source.file_name().unwrap().to_os_string(),
                 config_source.clone(),
@@ -190,22 +858,440 @@ fn find_config_files(config_sources: &[PathBuf]) -> io::Result<BTreeMap<OsString
             continue;
         }
 
-        for maybe_entry in fs::read_dir(config_source)? {
-            let entry = maybe_entry?;
-            let path = entry.path();
-            if path
-                .extension()
-                .map(|ext| ext.as_bytes() != b"conf")
-                .unwrap_or(true)
-            {
-                continue;
-            }
+        let mut found = BTreeMap::new();
+        read_config_dir(config_source, recursive, verbose, &mut found)?;
+        config_files.extend(found);
+    }
+
+    Ok(config_files)
+}
+
+/// Add the configuration files in `dir` to `found`, and with `recursive` the ones in its
+/// subdirectories too. Of files with the same name, the first one found is kept, and a
+/// directory's own files are found before those in its subdirectories, each in order of name.
+fn read_config_dir(
+    dir: &Path,
+    recursive: bool,
+    verbose: bool,
+    found: &mut BTreeMap<OsString, PathBuf>,
+) -> io::Result<()> {
+    let mut subdirs = Vec::new();
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        // Symlinks to directories aren't followed, so a link can't lead around in a loop
+        if recursive && file_type.is_dir() {
+            subdirs.push(path);
+            continue;
+        }
+        if path
+            .extension()
+            .map(|ext| ext.as_bytes() != b"conf")
+            .unwrap_or(true)
+        {
+            continue;
+        }
 
-            if entry.file_type()?.is_file() || entry.file_type()?.is_symlink() && path.is_file() {
-                config_files.insert(entry.file_name(), path);
+        if file_type.is_file() || file_type.is_symlink() && path.is_file() {
+            found.entry(entry.file_name()).or_insert(path);
+        } else if file_type.is_symlink() && verbose {
+            // Usually a package that was removed or a typo, so say why it's not applied
+            match fs::metadata(&path) {
+                Err(e) => eprintln!("Skipping {}, a broken symlink: {e}", path.display()),
+                Ok(_) => eprintln!(
+                    "Skipping {}, a symlink to something other than a file",
+                    path.display()
+                ),
             }
         }
     }
+    for subdir in subdirs {
+        read_config_dir(&subdir, recursive, verbose, found)?;
+    }
+    Ok(())
+}
 
-    Ok(config_files)
+#[cfg(test)]
+mod test {
+    use std::{
+        cell::RefCell,
+        ffi::OsStr,
+        fs,
+        io::{self, Write},
+        os::unix::ffi::OsStrExt,
+        path::{Path, PathBuf},
+        rc::Rc,
+    };
+
+    use clap::Parser;
+    use tempfile::TempDir;
+
+    use crate::{
+        apply, catalog, config_dirs, config_files, config_sources, decline,
+        diagnostic::{Diagnostic, DiagnosticFormat, Severity},
+        dry_run, find_config_files, parse_added_lines, parse_config_file, parsed_config,
+        parser::{parse_line, FileSpan},
+        specifier::SpecifierSource,
+        Args, CatalogFormat,
+    };
+
+    /// A buffer to write to that stays readable after it's handed off
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_assume_no() {
+        let dir = TempDir::new().unwrap();
+        let removed = dir.path().join("removed");
+        fs::create_dir_all(removed.join("sub")).unwrap();
+        let file = dir.path().join("a.conf");
+        fs::write(&file, format!("D {}\n", removed.display())).unwrap();
+        let files = [file];
+        let config = parsed_config(&files, Default::default(), Default::default()).unwrap();
+        let report = SharedBuffer::default();
+        let context = apply::Context {
+            confirm: Some(decline(report.clone())),
+            ..apply::Context::new(PathBuf::from("/"), SpecifierSource::Host)
+        };
+        apply::remove(&config, &context).unwrap();
+
+        assert!(removed.join("sub").exists());
+        assert_eq!(
+            String::from_utf8(report.0.take()).unwrap(),
+            format!(
+                "Keeping {} (D directory), --assume-no declines removing it\n",
+                removed.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_catalog_csv() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("a.conf");
+        fs::write(&file, "d /tmp/a,\\x22b\\x22 0755 root - 10d\nf /tmp/c\n").unwrap();
+        let files = [file.clone()];
+        let config = parsed_config(&files, Default::default(), Default::default()).unwrap();
+        let context = apply::Context::new(PathBuf::from("/"), SpecifierSource::Host);
+        let mut out = Vec::new();
+        catalog(&config, &context.specifiers, CatalogFormat::Csv, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "path,type,mode,owner,group,age,source_file,line\n\
+                 \"/tmp/a,\"\"b\"\"\",d,0755,root,,10d,{0},1\n\
+                 /tmp/c,f,,,,,{0},2\n",
+                file.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_config_file_order() {
+        let dir = TempDir::new().unwrap();
+        for name in [
+            "9-a.conf",
+            "10-a.conf",
+            "a.conf",
+            "B.conf",
+            "\u{e9}.conf",
+            "_.conf",
+        ] {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+        fs::write(dir.path().join("ignored.txt"), b"").unwrap();
+        let names = find_config_files(&[dir.path().to_owned()], false, false)
+            .unwrap()
+            .into_keys()
+            .collect::<Vec<_>>();
+        // Byte order, so digits sort one at a time and uppercase comes before lowercase
+        assert_eq!(
+            names,
+            [
+                "10-a.conf",
+                "9-a.conf",
+                "B.conf",
+                "_.conf",
+                "a.conf",
+                "\u{e9}.conf"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_skipped_symlinks() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("target"), b"").unwrap();
+        std::os::unix::fs::symlink("target", dir.path().join("linked.conf")).unwrap();
+        std::os::unix::fs::symlink("missing", dir.path().join("dangling.conf")).unwrap();
+        std::os::unix::fs::symlink(".", dir.path().join("directory.conf")).unwrap();
+        let names = find_config_files(&[dir.path().to_owned()], false, true)
+            .unwrap()
+            .into_keys()
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["linked.conf"]);
+    }
+
+    #[test]
+    fn test_recursive_sources() {
+        let dir = TempDir::new().unwrap();
+        let nested = dir.path().join("vendor/app");
+        fs::create_dir_all(&nested).unwrap();
+        fs::create_dir(dir.path().join("other")).unwrap();
+        for path in [
+            "b.conf",
+            "vendor/a.conf",
+            "vendor/app/c.conf",
+            "vendor/app/b.conf",
+            "vendor/app/ignored.txt",
+            "other/c.conf",
+        ] {
+            fs::write(dir.path().join(path), b"").unwrap();
+        }
+        let sources = [dir.path().to_owned()];
+        assert_eq!(
+            config_files(&sources, &[], &[], false, false).unwrap(),
+            [dir.path().join("b.conf")]
+        );
+        // Ordered by file name wherever they are, the file nearest the top of the source wins
+        // over one of the same name below it, and the first subdirectory over later ones
+        assert_eq!(
+            config_files(&sources, &[], &[], true, false).unwrap(),
+            [
+                dir.path().join("vendor/a.conf"),
+                dir.path().join("b.conf"),
+                dir.path().join("other/c.conf"),
+            ]
+        );
+        // A later source still replaces files with the same name
+        let later = TempDir::new().unwrap();
+        fs::create_dir(later.path().join("sub")).unwrap();
+        fs::write(later.path().join("sub/b.conf"), b"").unwrap();
+        assert_eq!(
+            config_files(
+                &[dir.path().to_owned(), later.path().to_owned()],
+                &[],
+                &[],
+                true,
+                false
+            )
+            .unwrap()[1],
+            later.path().join("sub/b.conf")
+        );
+    }
+
+    #[test]
+    fn test_explicit_config_files() {
+        let dir = TempDir::new().unwrap();
+        let explicit = TempDir::new().unwrap();
+        for name in ["a.conf", "b.conf"] {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+        let z = explicit.path().join("z.tmpfiles");
+        let a = explicit.path().join("a.conf");
+        fs::write(&z, b"").unwrap();
+        fs::write(&a, b"").unwrap();
+        // Explicit files keep their order after the sources, and don't replace same-named files
+        assert_eq!(
+            config_files(
+                &[dir.path().to_owned()],
+                &[],
+                &[z.clone(), a.clone()],
+                false,
+                false
+            )
+            .unwrap(),
+            [dir.path().join("a.conf"), dir.path().join("b.conf"), z, a]
+        );
+    }
+
+    #[test]
+    fn test_stdin_source() {
+        let sources = |args: &[&str]| {
+            let args = Args::try_parse_from(args).unwrap();
+            config_sources(&args.config_sources, &args.literal_sources)
+        };
+        // `-` is stdin unless it comes after `--`, where it's a file with that name
+        assert_eq!(
+            sources(&["mini-tmpfiles", "-", "a.conf", "--", "-", "--b"]),
+            [
+                PathBuf::from("-"),
+                PathBuf::from("a.conf"),
+                PathBuf::from("./-"),
+                PathBuf::from("--b")
+            ]
+        );
+        assert!(sources(&["mini-tmpfiles", "--create"]).is_empty());
+
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("a.conf"), b"").unwrap();
+        assert_eq!(
+            config_files(&[dir.path().to_owned(), "-".into()], &[], &[], false, false).unwrap(),
+            [PathBuf::from("-"), dir.path().join("a.conf")]
+        );
+    }
+
+    #[test]
+    fn test_diagnostic_lines() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("a.conf");
+        let contents = b"d /a\nq /b 07z9\nf /c\n";
+        fs::write(&file, contents).unwrap();
+
+        let (config, errors) = parse_config_file(contents, &file, Default::default());
+        assert_eq!(config.len(), 2);
+        let [error] = errors.as_slice() else {
+            panic!("{errors:?}")
+        };
+        assert_eq!((error.line, error.col), (Some(2), Some(1)));
+        assert!(DiagnosticFormat::Human
+            .format(error)
+            .starts_with(&format!("{}:2: Error: ", file.display())));
+
+        // Warnings while applying find the line again from where it starts in the file
+        let line = parse_line(FileSpan::nth_line(contents, &file, 3)).unwrap();
+        let warning = Diagnostic::new(Severity::Warning, "")
+            .at_offset(&file, line.line_type.characters().start);
+        assert_eq!((warning.line, warning.col), (Some(3), Some(1)));
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let config = [
+            parse_line(FileSpan::from_slice(b"d /run/ok", Path::new(""))).unwrap(),
+            parse_line(FileSpan::from_slice(
+                b"d /run/bad - no-such-user-here",
+                Path::new(""),
+            ))
+            .unwrap(),
+        ];
+        let context = apply::Context {
+            quiet: true,
+            ..apply::Context::new("/".into(), SpecifierSource::Host)
+        };
+        let mut out = Vec::new();
+        let error = dry_run(&config, &context, &mut out).unwrap_err();
+        assert_eq!(error.to_string(), "1 line would fail to apply");
+        assert_eq!(out, b"/run/ok\td\n");
+    }
+
+    #[test]
+    fn test_added_line_errors() {
+        let lines = ["d /a".into(), "q /b 07z9".into(), "f /c".into(), "x".into()];
+        let (config, errors) = parse_added_lines(&lines, Default::default());
+        assert_eq!(config.len(), 2);
+        let [first, second] = errors.as_slice() else {
+            panic!("{errors:?}")
+        };
+        assert_eq!((first.line, second.line), (Some(2), Some(4)));
+        assert!(DiagnosticFormat::Human
+            .format(first)
+            .starts_with("--add-line:2: Error: "));
+    }
+
+    #[test]
+    fn test_non_utf8_config() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join(OsStr::from_bytes(b"caf\xe9.conf"));
+        let created = dir.path().join(OsStr::from_bytes(b"caf\xe9"));
+        let mut contents = b"d ".to_vec();
+        contents.extend_from_slice(created.as_os_str().as_bytes());
+        contents.extend_from_slice(b" 0755\nd /\xe9t\xe9 07z9\n");
+        fs::write(&file, &contents).unwrap();
+
+        // The path is used byte for byte
+        let (config, errors) = parse_config_file(&contents, &file, Default::default());
+        apply::create(
+            &config,
+            &apply::Context::new(PathBuf::from("/"), SpecifierSource::Host),
+        )
+        .unwrap();
+        assert!(created.is_dir());
+
+        // and shown as well as it can be
+        let [error] = errors.as_slice() else {
+            panic!("{errors:?}")
+        };
+        let human = DiagnosticFormat::Human.format(error);
+        assert!(human.starts_with(&format!("{}:2: Error: ", file.display())));
+        assert!(human.contains("caf\u{fffd}.conf"));
+        assert!(human.ends_with(r"(d /\xe9t\xe9 07z9)"));
+        assert!(DiagnosticFormat::Json
+            .format(error)
+            .starts_with(&format!(r#"{{"file":"{}","#, file.display())));
+    }
+
+    #[test]
+    fn test_config_dirs() {
+        let root = TempDir::new().unwrap();
+        let dirs = config_dirs(root.path(), false, &|_| None);
+        assert_eq!(dirs[0], root.path().join("etc/tmpfiles.d"));
+        assert_eq!(dirs[3], root.path().join("usr/lib/tmpfiles.d"));
+        // The same name in /etc hides the file in /usr/lib
+        for (dir, name) in [
+            (&dirs[0], "a.conf"),
+            (&dirs[3], "a.conf"),
+            (&dirs[3], "b.conf"),
+        ] {
+            fs::create_dir_all(dir).unwrap();
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        assert_eq!(
+            config_files(&[], &dirs, &[], false, false).unwrap(),
+            [dirs[0].join("a.conf"), dirs[3].join("b.conf")]
+        );
+        // Any source given replaces the whole search path, even one that's only a file
+        let source = TempDir::new().unwrap();
+        fs::write(source.path().join("c.conf"), b"").unwrap();
+        assert_eq!(
+            config_files(&[source.path().to_owned()], &dirs, &[], false, false).unwrap(),
+            [source.path().join("c.conf")]
+        );
+        assert_eq!(
+            config_files(&[], &dirs, &[source.path().join("c.conf")], false, false).unwrap(),
+            [source.path().join("c.conf")]
+        );
+
+        let env = |name: &str| match name {
+            "HOME" => Some("/home/alice".into()),
+            "XDG_RUNTIME_DIR" => Some("/run/user/1000".into()),
+            "XDG_DATA_HOME" => Some("relative".into()),
+            _ => None,
+        };
+        assert_eq!(
+            config_dirs(Path::new("/"), true, &env),
+            [
+                "/home/alice/.config/user-tmpfiles.d",
+                "/run/user/1000/user-tmpfiles.d",
+                "/home/alice/.local/share/user-tmpfiles.d",
+                "/usr/local/share/user-tmpfiles.d",
+                "/usr/share/user-tmpfiles.d",
+            ]
+            .map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn test_jobs() {
+        let jobs = |value: &str| {
+            Args::try_parse_from(["mini-tmpfiles", "--jobs", value]).map(|args| args.jobs)
+        };
+        assert_eq!(Args::try_parse_from(["mini-tmpfiles"]).unwrap().jobs, 1);
+        assert_eq!(jobs("1").unwrap(), 1);
+        assert!(jobs("0").is_err());
+        assert!(jobs("4").is_err());
+        assert!(jobs("many").is_err());
+    }
 }
@@ -1,7 +1,9 @@
+use std::error::Error;
 use std::ffi::OsString;
+use std::fmt;
 use std::num::{IntErrorKind, ParseIntError};
 use std::ops::Range;
-use std::os::unix::ffi::OsStringExt;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::path::Path;
 use std::str::FromStr;
 use std::time::Duration;
@@ -11,8 +13,8 @@ use base64::DecodeError;
 use phf::phf_map;
 
 use crate::config_file::{
-    CleanupAge, FileOwner, Line, LineAction, LineType, Mode, ModeBehavior, Spanned, Specifier,
-    SpecifierString,
+    CleanupAge, DeviceNumbers, FileOwner, Line, LineAction, LineType, Mode, ModeBehavior, Spanned,
+    Specifier, SpecifierString,
 };
 
 // Saturating_mul here because const trait isn't stable at time of writing
@@ -34,7 +36,9 @@ static YEAR: Duration = DAY
     .saturating_mul(365)
     .saturating_add(HOUR.saturating_mul(6));
 
-static DURATION_KEYWORDS: phf::Map<&'static [u8], Duration> = phf_map! {
+/// Every unit systemd accepts in a time span, with the length of one of it. Units are matched by
+/// their bytes, so `µs` is the UTF-8 encoding of the micro sign.
+pub static DURATION_KEYWORDS: phf::Map<&'static [u8], Duration> = phf_map! {
     b"nsec" => NANOSECOND,
     b"ns" => NANOSECOND,
     b"usec" => MICROSECOND,
@@ -72,25 +76,98 @@ static DURATION_KEYWORDS: phf::Map<&'static [u8], Duration> = phf_map! {
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum ParseError {
-    IllegalParseType(u8),
+    /// Unknown type character, and the whole type field
+    IllegalParseType(u8, Box<[u8]>),
     LeadingWhitespace,
     EmptyParseType,
     InvalidTypeCombination(u8, u8),
-    InvalidTypeModifier(u8),
+    /// Unknown modifier character, and the whole type field
+    InvalidTypeModifier(u8, Box<[u8]>),
     InvalidMode,
     DuplicateTypeModifier(u8),
-    IDKWhatAServiceCredentialIs,
     InvalidCleanupAge(CleanupParseError),
     InvalidUsername,
     NullInPath,
     Field(FieldParseError),
     NonabsolutePath,
-    InvalidSpecifier(u8),
+    /// Unknown specifier character, and the whole field it appeared in
+    InvalidSpecifier(u8, Box<[u8]>),
     EmptyPath,
     IncompleteSpecifier,
     Base64Decode(DecodeError),
+    InvalidDeviceNumbers(Box<[u8]>),
+    Base64NotAllowed(LineAction),
+    /// Length of a line longer than `MAX_LINE_LENGTH`
+    LineTooLong(usize),
 }
 
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IllegalParseType(ch, field) => write!(
+                f,
+                "invalid type '{}' in \"{}\"",
+                ch.escape_ascii(),
+                field.escape_ascii()
+            ),
+            Self::LeadingWhitespace => write!(f, "line starts with whitespace"),
+            Self::EmptyParseType => write!(f, "missing type"),
+            Self::InvalidTypeCombination(ch, modifier) => write!(
+                f,
+                "type '{}' can't have the '{}' modifier",
+                ch.escape_ascii(),
+                modifier.escape_ascii()
+            ),
+            Self::InvalidTypeModifier(ch, field) => write!(
+                f,
+                "invalid type modifier '{}' in \"{}\"",
+                ch.escape_ascii(),
+                field.escape_ascii()
+            ),
+            Self::InvalidMode => write!(f, "invalid mode"),
+            Self::DuplicateTypeModifier(ch) => {
+                write!(f, "duplicate type modifier '{}'", ch.escape_ascii())
+            }
+            Self::InvalidCleanupAge(e) => write!(f, "invalid cleanup age: {e}"),
+            Self::InvalidUsername => write!(f, "invalid user or group"),
+            Self::NullInPath => write!(f, "path contains a null byte"),
+            Self::Field(e) => write!(f, "{e}"),
+            Self::NonabsolutePath => {
+                write!(f, "path is not absolute, it must start with / or one of")?;
+                for specifier in LEADING_SPECIFIERS {
+                    write!(f, " %{}", char::from(specifier.to_char()))?;
+                }
+                Ok(())
+            }
+            Self::InvalidSpecifier(ch, field) => write!(
+                f,
+                "invalid specifier '%{}' in \"{}\"",
+                ch.escape_ascii(),
+                field.escape_ascii()
+            ),
+            Self::EmptyPath => write!(f, "missing path"),
+            Self::IncompleteSpecifier => write!(f, "'%' at the end of a field"),
+            Self::Base64Decode(e) => write!(f, "invalid base64 argument: {e}"),
+            Self::InvalidDeviceNumbers(argument) => write!(
+                f,
+                "invalid device numbers \"{}\", expected major:minor",
+                argument.escape_ascii()
+            ),
+            Self::Base64NotAllowed(action) => write!(
+                f,
+                "type '{}' takes no argument to base64 decode",
+                action.type_char()
+            ),
+            Self::LineTooLong(length) => write!(
+                f,
+                "line is {length} bytes long, more than the limit of {MAX_LINE_LENGTH}"
+            ),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
 impl From<DecodeError> for ParseError {
     fn from(value: DecodeError) -> Self {
         Self::Base64Decode(value)
@@ -109,6 +186,23 @@ pub enum FieldParseError {
     UnfinishedQuote,
 }
 
+impl fmt::Display for FieldParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnrecognizedEscape(ch) => {
+                write!(f, "unrecognized escape '\\{}'", ch.escape_ascii())
+            }
+            Self::TrailingBackslash => write!(f, "backslash at the end of the line"),
+            Self::UnfinishedHexEscape => write!(f, "unfinished hex escape"),
+            Self::UnsupportedOctalEscape => write!(f, "octal escapes are not supported"),
+            Self::QuoteInUnquotedField => write!(f, "quote inside an unquoted field"),
+            Self::InvalidHexEscape => write!(f, "invalid hex escape"),
+            Self::JunkAfterQuotes => write!(f, "unexpected characters after closing quote"),
+            Self::UnfinishedQuote => write!(f, "unfinished quote"),
+        }
+    }
+}
+
 impl From<CleanupParseError> for ParseError {
     fn from(value: CleanupParseError) -> Self {
         Self::InvalidCleanupAge(value)
@@ -123,9 +217,37 @@ pub enum CleanupParseError {
     InvalidCleanupSpecifier(u8),
     Malformed(Box<[u8]>),
     OverflowedDuration(Box<[u8]>),
+    OverlongDurationInt(Box<[u8]>),
     EmptyCleanupSpecifierList,
 }
 
+impl fmt::Display for CleanupParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidDurationInt(e) => write!(f, "invalid number: {e}"),
+            Self::InvalidDurationKeyword(unit) => {
+                write!(f, "unknown time unit \"{}\"", unit.escape_ascii())
+            }
+            Self::DuplicateCleanupSpecifier(ch) => {
+                write!(f, "duplicate time type '{}'", ch.escape_ascii())
+            }
+            Self::InvalidCleanupSpecifier(ch) => {
+                write!(f, "unknown time type '{}'", ch.escape_ascii())
+            }
+            Self::Malformed(age) => write!(f, "malformed \"{}\"", age.escape_ascii()),
+            Self::OverflowedDuration(age) => write!(f, "\"{}\" is too long", age.escape_ascii()),
+            Self::OverlongDurationInt(digits) => {
+                write!(
+                    f,
+                    "number \"{}\" has too many digits",
+                    digits.escape_ascii()
+                )
+            }
+            Self::EmptyCleanupSpecifierList => write!(f, "empty list of time types"),
+        }
+    }
+}
+
 impl From<FieldParseError> for ParseError {
     fn from(value: FieldParseError) -> Self {
         Self::Field(value)
@@ -147,14 +269,29 @@ fn take_from_slice_while<'a>(
 
 fn take_string_from_slice<'a>(slice: &mut &'a [u8], s: &str) -> Option<&'a [u8]> {
     let remaining = slice.strip_prefix(s.as_bytes())?;
-    let taken = &slice[..s.as_bytes().len()];
+    let taken = &slice[..s.len()];
     *slice = remaining;
     Some(taken)
 }
 
+/// Longest digit run accepted for a duration count, enough for u64::MAX with leading zeros to
+/// spare. Stops us from scanning huge digit runs only to find they overflow.
+const MAX_DURATION_DIGITS: usize = 32;
+
 fn parse_duration_part(input: &mut &[u8]) -> Result<Duration, CleanupParseError> {
     let original_input = *input;
-    let count = take_from_slice_while(input, u8::is_ascii_digit);
+    let digits = input
+        .iter()
+        .take(MAX_DURATION_DIGITS + 1)
+        .take_while(|c| c.is_ascii_digit())
+        .count();
+    if digits > MAX_DURATION_DIGITS {
+        Err(CleanupParseError::OverlongDurationInt(
+            input[..digits].into(),
+        ))?
+    }
+    let (count, remaining) = input.split_at(digits);
+    *input = remaining;
     let count = u64::from_str(std::str::from_utf8(count).unwrap())
         .map_err(CleanupParseError::InvalidDurationInt)?;
     let key = take_from_slice_while(input, |c| c.is_ascii_alphabetic() || !c.is_ascii());
@@ -175,7 +312,9 @@ fn parse_duration_part(input: &mut &[u8]) -> Result<Duration, CleanupParseError>
     Ok(Duration::new(secs, nanos))
 }
 
-fn parse_duration(mut input: &[u8]) -> Result<Duration, CleanupParseError> {
+/// Parse a systemd time span like `1h30min` or `2d`. Every part needs a unit, the parts add up,
+/// and like in systemd a month is 30 days and 10.5 hours and a year is 365.25 days.
+pub fn parse_duration(mut input: &[u8]) -> Result<Duration, CleanupParseError> {
     let original_input = input;
     let mut acc = parse_duration_part(&mut input)?;
     while !input.is_empty() {
@@ -228,7 +367,7 @@ fn optional<B: AsRef<[u8]>, T, F: FnOnce(B) -> T>(f: F) -> impl FnOnce(B) -> Opt
     |input| (input.as_ref() != b"-").then(|| f(input))
 }
 
-fn parse_cleanup_age(input: &[u8]) -> Result<CleanupAge, CleanupParseError> {
+pub fn parse_cleanup_age(input: &[u8]) -> Result<CleanupAge, CleanupParseError> {
     let (mut cleanup_age, duration) =
         match input.split(|&c| c == b':').collect::<Vec<_>>().as_slice() {
             [] => unreachable!(),
@@ -242,9 +381,13 @@ fn parse_cleanup_age(input: &[u8]) -> Result<CleanupAge, CleanupParseError> {
     Ok(cleanup_age)
 }
 
-fn parse_specifiers(input: Box<[u8]>) -> Result<SpecifierString, ParseError> {
-    Ok(if input.contains(&b'%') {
-        let mut input = &*input;
+pub(crate) fn parse_specifiers(
+    input: Box<[u8]>,
+    options: ParseOptions,
+) -> Result<SpecifierString, ParseError> {
+    let field = &*input;
+    Ok(if field.contains(&b'%') {
+        let mut input = field;
         let leading = take_from_slice_while(&mut input, |&ch| ch != b'%');
         let mut sections = Vec::new();
         while !input.is_empty() {
@@ -252,10 +395,17 @@ fn parse_specifiers(input: Box<[u8]>) -> Result<SpecifierString, ParseError> {
             let Some((&head, tail)) = input.split_first() else {
                 Err(ParseError::IncompleteSpecifier)?
             };
-            let specifier =
-                Specifier::parse(head).ok_or_else(|| ParseError::InvalidSpecifier(head))?;
-            input = tail;
-            let next_segment = take_from_slice_while(&mut input, |&ch| ch != b'%').into();
+            let (specifier, mut next_segment) = match Specifier::parse(head) {
+                Some(specifier) => {
+                    input = tail;
+                    (specifier, Vec::new())
+                }
+                // An escaped percent sign keeps the unknown specifier literal through expansion
+                // and formatting
+                None if options.keep_unknown_specifiers => (Specifier::PercentSign, Vec::new()),
+                None => Err(ParseError::InvalidSpecifier(head, field.into()))?,
+            };
+            next_segment.extend_from_slice(take_from_slice_while(&mut input, |&ch| ch != b'%'));
             sections.push((specifier, next_segment));
         }
         SpecifierString(leading.to_owned(), sections.into_boxed_slice())
@@ -264,8 +414,19 @@ fn parse_specifiers(input: Box<[u8]>) -> Result<SpecifierString, ParseError> {
     })
 }
 
-fn parse_path(input: Box<[u8]>) -> Result<SpecifierString, ParseError> {
-    let string = parse_specifiers(input)?;
+/// Specifiers that expand to an absolute directory, so a path may start with them instead of `/`
+const LEADING_SPECIFIERS: &[Specifier] = &[
+    Specifier::CacheDir,
+    Specifier::UserHome,
+    Specifier::LogDir,
+    Specifier::StateDir,
+    Specifier::RuntimeDir,
+    Specifier::TempDir,
+    Specifier::PersistentTempDir,
+];
+
+fn parse_path(input: Box<[u8]>, options: ParseOptions) -> Result<SpecifierString, ParseError> {
+    let string = parse_specifiers(input, options)?;
     if string.0.contains(&b'\0') || string.1.iter().any(|(_, segment)| segment.contains(&b'\0')) {
         Err(ParseError::NullInPath)?
     } else if string.0.starts_with(b"/") {
@@ -276,16 +437,7 @@ fn parse_path(input: Box<[u8]>) -> Result<SpecifierString, ParseError> {
         let Some(initial_specifier) = string.1.first() else {
             Err(ParseError::EmptyPath)?
         };
-        if matches!(
-            initial_specifier.0,
-            Specifier::CacheDir
-                | Specifier::UserHome
-                | Specifier::LogDir
-                | Specifier::StateDir
-                | Specifier::RuntimeDir
-                | Specifier::TempDir
-                | Specifier::PersistentTempDir
-        ) {
+        if LEADING_SPECIFIERS.contains(&initial_specifier.0) {
             Ok(string)
         } else {
             Err(ParseError::NonabsolutePath)?
@@ -293,8 +445,32 @@ fn parse_path(input: Box<[u8]>) -> Result<SpecifierString, ParseError> {
     }
 }
 
+/// Settings that loosen how lines are parsed
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParseOptions {
+    /// Leave unrecognized `%X` sequences in paths as literal text instead of failing
+    pub keep_unknown_specifiers: bool,
+}
+
+/// Longest line accepted, like systemd's `LONG_LINE_MAX`. Parsing takes time linear in the length
+/// of a line, this bounds the memory a single line can take.
+pub const MAX_LINE_LENGTH: usize = 1024 * 1024;
+
 #[allow(unused)]
-pub fn parse_line<'b>(mut input: FileSpan<'_, 'b>) -> Result<Line<'b>, ParseError> {
+pub fn parse_line<'b>(input: FileSpan<'_, 'b>) -> Result<Line<'b>, ParseError> {
+    parse_line_with(input, ParseOptions::default())
+}
+
+/// Parse one line that isn't a comment. Like systemd, only a `#` starting a line, after any
+/// indentation, makes a comment, which callers skip with `FileSpan::is_data_line` before getting
+/// here. Anywhere else `#` is an ordinary character, so `f /a - - - - x # y` writes `x # y`.
+pub fn parse_line_with<'b>(
+    mut input: FileSpan<'_, 'b>,
+    options: ParseOptions,
+) -> Result<Line<'b>, ParseError> {
+    if input.bytes.len() > MAX_LINE_LENGTH {
+        return Err(ParseError::LineTooLong(input.bytes.len()));
+    }
     if matches!(input.bytes.first(), Some(b' ' | b'\t')) {
         return Err(ParseError::LeadingWhitespace);
     }
@@ -306,23 +482,31 @@ pub fn parse_line<'b>(mut input: FileSpan<'_, 'b>) -> Result<Line<'b>, ParseErro
     take_inline_whitespace(&mut input);
     let path = take_field(&mut input)?
         .map(Option::unwrap_or_default)
-        .try_map(parse_path)?;
+        .try_map(|path| parse_path(path, options))?;
     take_inline_whitespace(&mut input);
     let mode = take_field(&mut input)?
         .as_opt_deref()
         .try_then(try_optional(parse_mode))?;
     take_inline_whitespace(&mut input);
-    let owner = take_field(&mut input)?.try_then(try_optional(parse_user))?;
+    let owner =
+        take_field(&mut input)?.try_then(try_optional(|owner| parse_owner(owner, options)))?;
     take_inline_whitespace(&mut input);
-    let group = take_field(&mut input)?.try_then(try_optional(parse_user))?;
+    let group =
+        take_field(&mut input)?.try_then(try_optional(|group| parse_owner(group, options)))?;
     take_inline_whitespace(&mut input);
     let age = take_field(&mut input)?
         .as_opt_deref()
         .try_opt_map(try_optional(parse_cleanup_age))?
         .opt_map(|age| age.unwrap_or(CleanupAge::EMPTY));
     take_inline_whitespace(&mut input);
+    let unescape = unescapes_argument(line_type.data.action);
     let argument = Spanned::new(input.bytes, input.file, input.char_range)
-        .try_map(|input| parse_argument(input, base64_decode.data))?;
+        .try_map(|input| parse_argument(input, base64_decode.data, unescape))?;
+    if let (LineAction::CreateCharDevice | LineAction::CreateBlockDevice, Some(argument)) =
+        (line_type.data.action, &argument.data)
+    {
+        parse_device_numbers(argument.as_bytes())?;
+    }
 
     Ok(Line {
         line_type,
@@ -335,11 +519,23 @@ pub fn parse_line<'b>(mut input: FileSpan<'_, 'b>) -> Result<Line<'b>, ParseErro
     })
 }
 
-fn parse_argument(input: &[u8], base64_decode: bool) -> Result<Option<OsString>, ParseError> {
-    Ok(if !input.is_empty() {
+/// Parse the argument, which is the rest of the line taken literally, apart from escapes where
+/// they're decoded. Only as the whole argument, `-` means there is none like in other fields, and
+/// `""` means an empty one, so `w /x - - - - ""` writes empty content while `w /x - - - - -` has
+/// nothing to write.
+fn parse_argument(
+    input: &[u8],
+    base64_decode: bool,
+    unescape: bool,
+) -> Result<Option<OsString>, ParseError> {
+    Ok(if input == b"\"\"" {
+        Some(OsString::new())
+    } else if !input.is_empty() && input != b"-" {
         Some(if base64_decode {
             let decoded = base64::prelude::BASE64_STANDARD.decode(input)?;
             OsString::from_vec(decoded)
+        } else if unescape {
+            OsString::from_vec(unescape_argument(input)?)
         } else {
             OsString::from_vec(input.to_vec())
         })
@@ -348,6 +544,36 @@ fn parse_argument(input: &[u8], base64_decode: bool) -> Result<Option<OsString>,
     })
 }
 
+// Linux splits dev_t into a 12 bit major and 20 bit minor number
+const MAX_DEVICE_MAJOR: u32 = (1 << 12) - 1;
+const MAX_DEVICE_MINOR: u32 = (1 << 20) - 1;
+
+/// Parse the `major:minor` argument of `c` and `b` lines, with each number in decimal or
+/// `0x`-prefixed hex
+pub(crate) fn parse_device_numbers(input: &[u8]) -> Result<DeviceNumbers, ParseError> {
+    let invalid = || ParseError::InvalidDeviceNumbers(input.into());
+    let number = |input: &[u8], max: u32| {
+        let input = std::str::from_utf8(input).map_err(|_| invalid())?;
+        if input.starts_with('+') {
+            // from_str_radix would accept a sign
+            Err(invalid())?
+        }
+        let value = match input.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => u32::from_str(input),
+        };
+        value.ok().filter(|&value| value <= max).ok_or_else(invalid)
+    };
+    let (major, minor) = match input.split(|&ch| ch == b':').collect::<Vec<_>>()[..] {
+        [major, minor] => (major, minor),
+        _ => Err(invalid())?,
+    };
+    Ok(DeviceNumbers {
+        major: number(major, MAX_DEVICE_MAJOR)?,
+        minor: number(minor, MAX_DEVICE_MINOR)?,
+    })
+}
+
 #[derive(Clone)]
 pub struct FileSpan<'a, 'b> {
     bytes: &'a [u8],
@@ -372,41 +598,55 @@ impl<'a, 'b> FileSpan<'a, 'b> {
     pub fn bytes(&self) -> &'a [u8] {
         self.bytes
     }
-    pub(crate) fn take_while(&mut self, mut predicate: impl FnMut(&'a u8) -> bool) -> Self {
-        let split_idx = self
-            .bytes
-            .iter()
-            .position(|b| !predicate(b))
-            .unwrap_or(self.bytes.len());
-        let mut cursor = self.cursor();
-        cursor.advance_n(split_idx);
-        cursor.split_off_beginning()
+    /// Whether a line has anything to parse, rather than being blank or a comment. Like in systemd,
+    /// a comment may be indented.
+    pub fn is_data_line(&self) -> bool {
+        !matches!(
+            self.bytes.iter().find(|&&ch| !matches!(ch, b' ' | b'\t')),
+            None | Some(b'#')
+        )
     }
-    pub(crate) fn lines(&self) -> Lines<'a, 'b> {
-        Lines(self.clone())
+    /// Line `number` of `bytes`, counting from 1, with its characters where they are in the whole
+    #[cfg(test)]
+    pub(crate) fn nth_line(bytes: &'a [u8], file: &'b Path, number: usize) -> Self {
+        Self::from_slice(bytes, file)
+            .lines()
+            .find_map(|(n, line)| (n == number).then_some(line))
+            .unwrap_or_else(|| panic!("no line {number}"))
+    }
+    pub fn lines(&self) -> Lines<'a, 'b> {
+        Lines {
+            remaining: self.clone(),
+            line_number: 0,
+        }
     }
 }
 
-pub(crate) struct Lines<'a, 'b>(FileSpan<'a, 'b>);
+/// Lines of a span along with their 1-based line number
+pub struct Lines<'a, 'b> {
+    remaining: FileSpan<'a, 'b>,
+    line_number: usize,
+}
 
 impl<'a, 'b> Iterator for Lines<'a, 'b> {
-    type Item = FileSpan<'a, 'b>;
+    type Item = (usize, FileSpan<'a, 'b>);
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.0.bytes().is_empty() {
+        if self.remaining.bytes().is_empty() {
             return None;
         }
-        let mut cursor = self.0.cursor();
+        let mut cursor = self.remaining.cursor();
         while cursor.peek().is_some_and(|ch| ch != b'\n') {
             cursor.advance();
         }
         let line = cursor.split_off_beginning();
-        if !self.0.bytes().is_empty() {
-            let mut cursor = self.0.cursor();
+        if !self.remaining.bytes().is_empty() {
+            let mut cursor = self.remaining.cursor();
             cursor.advance();
             cursor.split_off_beginning();
         }
-        Some(line)
+        self.line_number += 1;
+        Some((self.line_number, line))
     }
 }
 
@@ -422,9 +662,6 @@ impl<'b, 'c> SpanCursor<'_, 'b, 'c> {
     fn advance(&mut self) {
         self.cursor += 1;
     }
-    fn advance_n(&mut self, count: usize) {
-        self.cursor += count;
-    }
     pub fn split_off_beginning(self) -> FileSpan<'b, 'c> {
         let split = FileSpan {
             bytes: &self.span.bytes[..self.cursor],
@@ -484,34 +721,11 @@ fn take_field<'a>(
             }
             Some(b'\\') => {
                 cursor.advance();
-                let Some(character) = cursor.peek() else {
-                    // End of line parsing escape
-                    Err(FieldParseError::TrailingBackslash)?
-                };
-                cursor.advance();
-                match character {
-                    b'x' => {
-                        // Hexadecimal: \xhh
-                        let Some(digits) = cursor.as_bytes().get(..2) else {
-                            Err(FieldParseError::UnfinishedHexEscape)?
-                        };
-                        cursor.advance();
-                        cursor.advance();
-                        let s = std::str::from_utf8(digits)
-                            .map_err(|_| FieldParseError::InvalidHexEscape)?;
-                        let byte = u8::from_str_radix(s, 16).map_err(|e| {
-                            assert_eq!(*e.kind(), IntErrorKind::InvalidDigit);
-                            FieldParseError::InvalidHexEscape
-                        })?;
-                        field.push(byte);
-                    }
-                    b'0'..=b'7' => Err(FieldParseError::UnsupportedOctalEscape)?, // Octal: \OOO
-                    b'n' => field.push(b'\n'),
-                    b'r' => field.push(b'\r'),
-                    b't' => field.push(b'\t'),
-                    b'\'' | b'"' | b'\\' => field.push(character),
-                    _ => Err(FieldParseError::UnrecognizedEscape(character))?,
+                let (byte, length) = decode_escape(cursor.as_bytes())?;
+                for _ in 0..length {
+                    cursor.advance();
                 }
+                field.push(byte);
             }
             Some(c) => {
                 cursor.advance();
@@ -526,6 +740,110 @@ fn take_field<'a>(
     ))
 }
 
+/// Decode the escape sequence at the start of `input`, which follows a backslash, returning the
+/// byte it stands for and how many bytes of `input` it took
+fn decode_escape(input: &[u8]) -> Result<(u8, usize), FieldParseError> {
+    let Some(&character) = input.first() else {
+        // End of line parsing escape
+        Err(FieldParseError::TrailingBackslash)?
+    };
+    let byte = match character {
+        b'x' => {
+            // Hexadecimal: \xhh
+            let Some(digits) = input.get(1..3) else {
+                Err(FieldParseError::UnfinishedHexEscape)?
+            };
+            let s = std::str::from_utf8(digits).map_err(|_| FieldParseError::InvalidHexEscape)?;
+            let byte = u8::from_str_radix(s, 16).map_err(|e| {
+                assert_eq!(*e.kind(), IntErrorKind::InvalidDigit);
+                FieldParseError::InvalidHexEscape
+            })?;
+            return Ok((byte, 3));
+        }
+        b'0'..=b'7' => Err(FieldParseError::UnsupportedOctalEscape)?, // Octal: \OOO
+        b'n' => b'\n',
+        b'r' => b'\r',
+        b't' => b'\t',
+        b'\'' | b'"' | b'\\' => character,
+        _ => Err(FieldParseError::UnrecognizedEscape(character))?,
+    };
+    Ok((byte, 1))
+}
+
+/// Decode the escapes in an argument, which unlike a field runs to the end of the line, so quotes
+/// and whitespace are taken as they are
+fn unescape_argument(input: &[u8]) -> Result<Vec<u8>, FieldParseError> {
+    let mut unescaped = Vec::with_capacity(input.len());
+    let mut rest = input;
+    while let Some((&ch, after)) = rest.split_first() {
+        if ch == b'\\' {
+            let (byte, length) = decode_escape(after)?;
+            unescaped.push(byte);
+            rest = &after[length..];
+        } else {
+            unescaped.push(ch);
+            rest = after;
+        }
+    }
+    Ok(unescaped)
+}
+
+/// Whether the argument of a line of this type has its escapes decoded, like in systemd, where
+/// only the contents `f` and `w` write do
+pub(crate) fn unescapes_argument(action: LineAction) -> bool {
+    matches!(action, LineAction::CreateFile | LineAction::WriteFile)
+}
+
+/// Whether the argument of a line of this type may be base64 encoded with `~`. Types that never
+/// take an argument have nothing to decode.
+pub(crate) fn decodes_base64(action: LineAction) -> bool {
+    !matches!(
+        action,
+        LineAction::CreateAndCleanUpDirectory
+            | LineAction::CreateSubvolume
+            | LineAction::CreateSubvolumeInheritQuota
+            | LineAction::CreateSubvolumeNewQuota
+            | LineAction::CreateAndRemoveDirectory
+            | LineAction::CleanUpDirectory
+            | LineAction::CreateFifo
+            | LineAction::Ignore
+            | LineAction::IgnoreNonRecursive
+            | LineAction::Remove
+            | LineAction::RemoveRecursive
+            | LineAction::SetMode
+            | LineAction::SetModeRecursive
+    )
+}
+
+/// Escape a field so that `take_field` reads back exactly `input`. Bytes which aren't valid UTF-8
+/// are written as hex escapes so the result can be displayed.
+pub(crate) fn escape_field(input: &[u8]) -> String {
+    if input.is_empty() {
+        return "\"\"".to_owned();
+    }
+    let mut escaped = String::with_capacity(input.len());
+    for chunk in input.utf8_chunks() {
+        for ch in chunk.valid().chars() {
+            match ch {
+                '\\' | '\'' | '"' => {
+                    escaped.push('\\');
+                    escaped.push(ch);
+                }
+                '\n' => escaped.push_str("\\n"),
+                '\r' => escaped.push_str("\\r"),
+                '\t' => escaped.push_str("\\t"),
+                ' ' => escaped.push_str("\\x20"),
+                _ if ch.is_ascii_control() => escaped.push_str(&format!("\\x{:02x}", ch as u8)),
+                _ => escaped.push(ch),
+            }
+        }
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("\\x{byte:02x}"));
+        }
+    }
+    escaped
+}
+
 fn parse_mode(mut input: &[u8]) -> Result<Mode, ParseError> {
     let mode_behavior = match input.first() {
         Some(b':') => ModeBehavior::KeepExisting,
@@ -549,7 +867,18 @@ fn parse_mode(mut input: &[u8]) -> Result<Mode, ParseError> {
         mode_behavior,
     })
 }
-fn parse_user(input: Box<[u8]>) -> Result<FileOwner, ParseError> {
+/// An owner or group field, which may contain specifiers to expand when the line is applied
+fn parse_owner(input: Box<[u8]>, options: ParseOptions) -> Result<FileOwner, ParseError> {
+    if !input.contains(&b'%') {
+        return parse_user(input);
+    }
+    if std::str::from_utf8(&input).is_err() {
+        return Err(ParseError::InvalidUsername);
+    }
+    Ok(FileOwner::Specifiers(parse_specifiers(input, options)?))
+}
+
+pub(crate) fn parse_user(input: Box<[u8]>) -> Result<FileOwner, ParseError> {
     let Ok(s) = std::str::from_utf8(&input) else {
         return Err(ParseError::InvalidUsername);
     };
@@ -576,7 +905,10 @@ fn parse_type(input: &[u8]) -> Result<(LineType, bool), ParseError> {
             LineAction::CreateFile
         }
         'w' => LineAction::WriteFile,
-        'd' | 'v' | 'q' | 'Q' => LineAction::CreateAndCleanUpDirectory,
+        'd' => LineAction::CreateAndCleanUpDirectory,
+        'v' => LineAction::CreateSubvolume,
+        'q' => LineAction::CreateSubvolumeInheritQuota,
+        'Q' => LineAction::CreateSubvolumeNewQuota,
         'D' => LineAction::CreateAndRemoveDirectory,
         'e' => LineAction::CleanUpDirectory,
         'p' => LineAction::CreateFifo,
@@ -596,7 +928,7 @@ fn parse_type(input: &[u8]) -> Result<(LineType, bool), ParseError> {
         'H' => LineAction::SetAttrRecursive,
         'a' => LineAction::SetAcl,
         'A' => LineAction::SetAclRecursive,
-        _ => return Err(ParseError::IllegalParseType(char)),
+        _ => return Err(ParseError::IllegalParseType(char, input.into())),
     };
     let mut minus = false;
     let mut exclamation = false;
@@ -611,7 +943,7 @@ fn parse_type(input: &[u8]) -> Result<(LineType, bool), ParseError> {
             b'=' => &mut equals,
             b'~' => &mut tilde,
             b'^' => &mut caret,
-            _ => return Err(ParseError::InvalidTypeModifier(c)),
+            _ => return Err(ParseError::InvalidTypeModifier(c, input.into())),
         };
         if *var {
             return Err(ParseError::DuplicateTypeModifier(c));
@@ -619,24 +951,26 @@ fn parse_type(input: &[u8]) -> Result<(LineType, bool), ParseError> {
             *var = true;
         }
     }
-    let recreate = if plus {
-        if matches!(
-            char.into(),
-            'f' | 'w' | 'p' | 'L' | 'c' | 'b' | 'C' | 'a' | 'A'
-        ) {
-            true
-        } else {
-            return Err(ParseError::InvalidTypeCombination(char, b'+'));
+    // ! and - make sense for every type, the others only for some
+    for (set, modifier, allowed) in [
+        (plus, b'+', &b"fwpLcbCaA"[..]),
+        // Replacing what's in the way only means something for types that create
+        (equals, b'=', b"fdDvqQpLcbC"),
+        // Credentials can only stand in for file contents
+        (caret, b'^', b"fw"),
+    ] {
+        if set && !allowed.contains(&char) {
+            return Err(ParseError::InvalidTypeCombination(char, modifier));
         }
-    } else {
-        false
-    };
+    }
+    let recreate = plus;
     let boot = exclamation;
     let noerror = minus;
     let force = equals;
+    let credential = caret;
     let base64_decode = tilde;
-    if caret {
-        return Err(ParseError::IDKWhatAServiceCredentialIs);
+    if base64_decode && !decodes_base64(action) {
+        return Err(ParseError::Base64NotAllowed(action));
     }
     Ok((
         LineType {
@@ -645,6 +979,7 @@ fn parse_type(input: &[u8]) -> Result<(LineType, bool), ParseError> {
             boot,
             noerror,
             force,
+            credential,
         },
         base64_decode,
     ))
@@ -652,13 +987,17 @@ fn parse_type(input: &[u8]) -> Result<(LineType, bool), ParseError> {
 
 #[cfg(test)]
 mod test {
-    use std::{ffi::OsString, path::Path, str::FromStr};
+    use std::{ffi::OsString, path::Path, str::FromStr, time::Duration};
 
     use crate::{
-        config_file::{CleanupAge, Line, LineAction, LineType, Spanned, SpecifierString},
+        config_file::{
+            CleanupAge, DeviceNumbers, FileOwner, Line, LineAction, LineType, Spanned, Specifier,
+            SpecifierString,
+        },
         parser::{
-            parse_cleanup_age, parse_duration, parse_duration_part, parse_line, CleanupParseError,
-            FieldParseError, FileSpan, ParseError, MICROSECOND, SECOND, WEEK,
+            parse_cleanup_age, parse_device_numbers, parse_duration, parse_duration_part,
+            parse_line, parse_line_with, CleanupParseError, FieldParseError, FileSpan, ParseError,
+            ParseOptions, MAX_LINE_LENGTH, MICROSECOND, SECOND, WEEK,
         },
     };
 
@@ -701,7 +1040,7 @@ mod test {
         assert_eq!(
             parse_line(FileSpan::from_slice(b"L+ /run/gdm/.config/pulse/default.pa - - - - /nix/store/whibfps24g91fx9i63m2wdyl87dfadnn-default.pa", dummy_file)),
             Ok(Line {
-                line_type: Spanned::new(LineType { action: LineAction::CreateSymlink, recreate: true, boot: false, noerror: false, force: false }, dummy_file, 0..2 ),
+                line_type: Spanned::new(LineType { action: LineAction::CreateSymlink, recreate: true, boot: false, noerror: false, force: false, credential: false }, dummy_file, 0..2 ),
                 path: Spanned::new(SpecifierString(b"/run/gdm/.config/pulse/default.pa".to_vec(), [].into()), dummy_file, 3..36),
                 mode: Spanned::new(None, dummy_file, 37..38),
                 owner: Spanned::new(None, dummy_file, 39..40),
@@ -731,7 +1070,7 @@ mod test {
     fn test_illegal_parse_type() {
         assert_eq!(
             parse_line(FileSpan::from_slice(b"B", Path::new(""))),
-            Err(ParseError::IllegalParseType(b'B'))
+            Err(ParseError::IllegalParseType(b'B', b"B".as_slice().into()))
         )
     }
     #[test]
@@ -774,13 +1113,40 @@ mod test {
         assert_eq!(
             parse_line(FileSpan::from_slice(b"Z+", Path::new(""))),
             Err(ParseError::InvalidTypeCombination(b'Z', b'+'))
-        )
+        );
+        for (input, ch, modifier) in [
+            (b"x= /tmp/a".as_slice(), b'x', b'='),
+            (b"z= /tmp/a", b'z', b'='),
+            (b"d^ /tmp/a", b'd', b'^'),
+        ] {
+            assert_eq!(
+                parse_line(FileSpan::from_slice(input, Path::new(""))),
+                Err(ParseError::InvalidTypeCombination(ch, modifier))
+            );
+        }
+    }
+    #[test]
+    fn test_modifier_combinations() {
+        let line_type = |input: &[u8]| {
+            parse_line(FileSpan::from_slice(input, Path::new("")))
+                .unwrap()
+                .line_type
+                .data
+        };
+        let file = line_type(b"f+!-=^ /etc/a - - - - cred");
+        assert!(file.recreate && file.boot && file.noerror && file.force && file.credential);
+        assert!(line_type(b"F^ /etc/a - - - - cred").credential);
+        assert!(line_type(b"x!- /tmp/a").boot);
+        assert!(line_type(b"L= /tmp/a - - - - /b").force);
     }
     #[test]
     fn test_invalid_type_modifier() {
         assert_eq!(
             parse_line(FileSpan::from_slice(b"Z\0", Path::new(""))),
-            Err(ParseError::InvalidTypeModifier(b'\0'))
+            Err(ParseError::InvalidTypeModifier(
+                b'\0',
+                b"Z\0".as_slice().into()
+            ))
         )
     }
     #[test]
@@ -897,6 +1263,24 @@ mod test {
         )
     }
     #[test]
+    fn test_overlong_cleanup_duration_int() {
+        // As long as a line may be
+        let mut line = b"Z / - - - 1s".to_vec();
+        line.extend(std::iter::repeat_n(b'9', MAX_LINE_LENGTH - line.len() - 2));
+        line.extend_from_slice(b"ms");
+        assert_eq!(
+            parse_line(FileSpan::from_slice(&line, Path::new(""))),
+            Err(ParseError::InvalidCleanupAge(
+                CleanupParseError::OverlongDurationInt([b'9'; 33].as_slice().into())
+            ))
+        );
+        // Leading zeros still fit
+        assert_eq!(
+            parse_cleanup_age(b"00000000000000000000000000000001s").map(|age| age.age),
+            Ok(Duration::from_secs(1))
+        );
+    }
+    #[test]
     fn test_empty_cleanup_specifiers() {
         assert_eq!(
             parse_line(FileSpan::from_slice(b"Z	/ - - - :1s", Path::new(""))),
@@ -913,6 +1297,26 @@ mod test {
         )
     }
     #[test]
+    fn test_hash_mid_line() {
+        let line = parse_line(FileSpan::from_slice(
+            b"f /a#b - - - - text # not a comment",
+            Path::new(""),
+        ))
+        .unwrap();
+        assert_eq!(line.path.data.0, b"/a#b");
+        assert_eq!(
+            line.argument.data,
+            Some(OsString::from("text # not a comment"))
+        );
+    }
+    #[test]
+    fn test_nonabsolute_path_message() {
+        assert_eq!(
+            ParseError::NonabsolutePath.to_string(),
+            "path is not absolute, it must start with / or one of %C %h %L %S %t %T %V"
+        );
+    }
+    #[test]
     fn test_empty_path() {
         assert_eq!(
             parse_line(FileSpan::from_slice(b"Z	\"\"", Path::new(""))),
@@ -943,7 +1347,7 @@ mod test {
             let mut slice = b"Z %".to_vec();
             slice.push(*fail);
             assert_eq!(
-                parse_line(FileSpan::from_slice(b"Z	%b", path)),
+                parse_line(FileSpan::from_slice(&slice, path)),
                 Err(ParseError::NonabsolutePath)
             )
         }
@@ -961,6 +1365,7 @@ mod test {
                         boot: true,
                         noerror: false,
                         force: false,
+                        credential: false,
                     },
                     file,
                     0..2
@@ -978,4 +1383,254 @@ mod test {
             })
         )
     }
+    #[test]
+    fn test_lines_numbered() {
+        let span = FileSpan::from_slice(b"d /a\n\n# comment\nf /b", Path::new(""));
+        let lines = span
+            .lines()
+            .map(|(number, line)| (number, line.bytes().to_vec()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                (1, b"d /a".to_vec()),
+                (2, b"".to_vec()),
+                (3, b"# comment".to_vec()),
+                (4, b"f /b".to_vec()),
+            ]
+        );
+        let line = FileSpan::nth_line(span.bytes(), Path::new(""), 4);
+        assert_eq!(
+            (line.bytes(), line.char_range),
+            (b"f /b".as_slice(), 16..20)
+        );
+    }
+    #[test]
+    fn test_data_lines() {
+        let span = FileSpan::from_slice(
+            b"d /a\n\n  # indented\n\t#\n \t\n#d /b\nf /c # d",
+            Path::new(""),
+        );
+        let data = span
+            .lines()
+            .filter(|(_, line)| line.is_data_line())
+            .map(|(number, _)| number)
+            .collect::<Vec<_>>();
+        assert_eq!(data, [1, 7]);
+    }
+    #[test]
+    fn test_owner_specifiers() {
+        let line = parse_line(FileSpan::from_slice(
+            b"z /run/user 0700 %u %g -",
+            Path::new(""),
+        ))
+        .unwrap();
+        assert_eq!(
+            line.owner.data,
+            Some(FileOwner::Specifiers(SpecifierString(
+                Vec::new(),
+                [(Specifier::Username, Vec::new())].into()
+            )))
+        );
+        assert_eq!(
+            parse_line(FileSpan::from_slice(b"z /run/user 0700 %y", Path::new(""))),
+            Err(ParseError::InvalidSpecifier(b'y', b"%y".as_slice().into()))
+        );
+    }
+    #[test]
+    fn test_keep_unknown_specifiers() {
+        let options = ParseOptions {
+            keep_unknown_specifiers: true,
+        };
+        let input = b"d /srv/%y/%%/%m";
+        assert_eq!(
+            parse_line(FileSpan::from_slice(input, Path::new(""))),
+            Err(ParseError::InvalidSpecifier(
+                b'y',
+                b"/srv/%y/%%/%m".as_slice().into()
+            ))
+        );
+        let line = parse_line_with(FileSpan::from_slice(input, Path::new("")), options).unwrap();
+        assert_eq!(
+            line.path.data,
+            SpecifierString(
+                b"/srv/".to_vec(),
+                [
+                    (Specifier::PercentSign, b"y/".to_vec()),
+                    (Specifier::PercentSign, b"/".to_vec()),
+                    (Specifier::MachineID, b"".to_vec()),
+                ]
+                .into()
+            )
+        );
+    }
+    #[test]
+    fn test_empty_argument() {
+        let argument = |input: &[u8]| {
+            parse_line(FileSpan::from_slice(input, Path::new("")))
+                .unwrap()
+                .argument
+                .data
+        };
+        assert_eq!(argument(b"w /x - - - - \"\""), Some(OsString::new()));
+        assert_eq!(argument(b"w /x - - - - -"), None);
+        assert_eq!(argument(b"w /x"), None);
+        // Anywhere else quotes and dashes are part of the argument
+        assert_eq!(
+            argument(b"w /x - - - - \"\"-"),
+            Some(OsString::from("\"\"-"))
+        );
+        assert_eq!(argument(b"w /x - - - - - -"), Some(OsString::from("- -")));
+    }
+    #[test]
+    fn test_argument_escapes() {
+        let argument = |input: &[u8]| {
+            parse_line(FileSpan::from_slice(input, Path::new(""))).map(|line| line.argument.data)
+        };
+        assert_eq!(
+            argument(b"w /x - - - - line1\\nline2\\t\\x41\\\\"),
+            Ok(Some(OsString::from("line1\nline2\tA\\")))
+        );
+        // Quotes don't end the argument, and escaped ones are the same as plain ones
+        assert_eq!(
+            argument(b"f /x - - - - say \"hi\" \\\"again\\\""),
+            Ok(Some(OsString::from("say \"hi\" \"again\"")))
+        );
+        assert_eq!(
+            argument(b"w /x - - - - \\x2d"),
+            Ok(Some(OsString::from("-")))
+        );
+        assert!(matches!(
+            argument(b"w /x - - - - trailing\\"),
+            Err(ParseError::Field(FieldParseError::TrailingBackslash))
+        ));
+        // Other arguments are paths or values that are taken as they are
+        assert_eq!(
+            argument(b"L /x - - - - C:\\new"),
+            Ok(Some(OsString::from("C:\\new")))
+        );
+    }
+    #[test]
+    fn test_base64_only_with_arguments() {
+        assert_eq!(
+            parse_line(FileSpan::from_slice(b"d~ /tmp/a", Path::new(""))),
+            Err(ParseError::Base64NotAllowed(
+                LineAction::CreateAndCleanUpDirectory
+            ))
+        );
+        assert!(parse_line(FileSpan::from_slice(
+            b"f~ /tmp/a - - - - aGk=",
+            Path::new("")
+        ))
+        .is_ok());
+    }
+    #[test]
+    fn test_long_lines() {
+        // Lines the fuzzer could make, at the limit, which a parser quadratic in the length of a
+        // line would take hours on
+        let line_of = |prefix: &[u8], repeated: &[u8], suffix: &[u8]| {
+            let count = (MAX_LINE_LENGTH - prefix.len() - suffix.len()) / repeated.len();
+            [prefix, &repeated.repeat(count), suffix].concat()
+        };
+        for line in [
+            line_of(b"d /", b"%%", b""),
+            line_of(b"f \"/", b"\\x41", b"\""),
+            line_of(b"w /x - - - - ", b"\\n", b""),
+            line_of(b"d /x - - - ", b"1s", b""),
+        ] {
+            assert!(line.len() <= MAX_LINE_LENGTH);
+            let result = parse_line(FileSpan::from_slice(&line, Path::new("")));
+            assert!(
+                !matches!(result, Err(ParseError::LineTooLong(_))),
+                "{:?}",
+                &line[..16]
+            );
+        }
+
+        let line = [b"d /".as_slice(), &b"a".repeat(MAX_LINE_LENGTH - 2)].concat();
+        assert!(matches!(
+            parse_line(FileSpan::from_slice(&line, Path::new(""))),
+            Err(ParseError::LineTooLong(length)) if length == MAX_LINE_LENGTH + 1
+        ));
+    }
+    #[test]
+    fn test_error_shows_field() {
+        let error = parse_line(FileSpan::from_slice(b"d /run/%q/x", Path::new(""))).unwrap_err();
+        assert_eq!(error.to_string(), "invalid specifier '%q' in \"/run/%q/x\"");
+        let error = parse_line(FileSpan::from_slice(b"d+? /run", Path::new(""))).unwrap_err();
+        assert_eq!(error.to_string(), "invalid type modifier '?' in \"d+?\"");
+    }
+    #[test]
+    fn test_device_numbers() {
+        assert_eq!(
+            parse_device_numbers(b"1:3"),
+            Ok(DeviceNumbers { major: 1, minor: 3 })
+        );
+        assert_eq!(
+            parse_device_numbers(b"0xa:0xb"),
+            Ok(DeviceNumbers {
+                major: 10,
+                minor: 11
+            })
+        );
+        for invalid in [
+            b":3".as_slice(),
+            b"1:",
+            b"13",
+            b"1:2:3",
+            b"4096:0",
+            b"0:0x100000",
+            b"+1:3",
+        ] {
+            assert_eq!(
+                parse_device_numbers(invalid),
+                Err(ParseError::InvalidDeviceNumbers(invalid.into()))
+            );
+        }
+        assert_eq!(
+            parse_line(FileSpan::from_slice(
+                b"c /dev/null - - - - 1",
+                Path::new("")
+            )),
+            Err(ParseError::InvalidDeviceNumbers(b"1".as_slice().into()))
+        );
+    }
+    #[test]
+    fn test_subvolume_types() {
+        for (input, action) in [
+            (
+                b"v /var/lib/machines".as_slice(),
+                LineAction::CreateSubvolume,
+            ),
+            (
+                b"q /var/lib/portables",
+                LineAction::CreateSubvolumeInheritQuota,
+            ),
+            (b"Q /var/lib/machines", LineAction::CreateSubvolumeNewQuota),
+        ] {
+            assert_eq!(
+                parse_line(FileSpan::from_slice(input, Path::new("")))
+                    .unwrap()
+                    .line_type
+                    .data
+                    .action,
+                action
+            );
+        }
+    }
+
+    #[test]
+    fn test_upper_f_is_f_plus() {
+        let line_type = |input: &[u8]| {
+            parse_line(FileSpan::from_slice(input, Path::new("")))
+                .unwrap()
+                .line_type
+                .data
+        };
+        let upper = line_type(b"F /run/file");
+        assert_eq!(upper, line_type(b"f+ /run/file"));
+        assert_eq!(upper.action, LineAction::CreateFile);
+        assert!(upper.recreate);
+        assert!(!line_type(b"f /run/file").recreate);
+    }
 }
@@ -0,0 +1,454 @@
+use std::{fs::File, io, os::unix::fs::MetadataExt};
+
+/// `FS_XFLAG_PROJINHERIT`, new files in a directory with it get the directory's project id
+const PROJECT_INHERIT: u32 = 0x200;
+
+/// A directory's project, which XFS and ext4 count quota by
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Project {
+    pub id: u32,
+    /// Whether what's created inside gets the same project
+    pub inherit: bool,
+}
+
+/// Whether `file` is on btrfs, where quota is tracked per subvolume instead
+#[cfg(target_os = "linux")]
+pub fn on_btrfs(file: &File) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    let mut stat = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    if unsafe { libc::fstatfs(file.as_raw_fd(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    Ok(stat.f_type as u32 == libc::BTRFS_SUPER_MAGIC as u32)
+}
+
+/// `struct fsxattr` from `linux/fs.h`
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default)]
+struct FsXattr {
+    xflags: u32,
+    extsize: u32,
+    nextents: u32,
+    projid: u32,
+    cowextsize: u32,
+    pad: [u8; 8],
+}
+
+/// An ioctl number the way `_IOR`, `_IOW`, and `_IOWR` make them, which differs between
+/// architectures
+#[cfg(target_os = "linux")]
+const fn ioctl_number(read: bool, write: bool, kind: u8, nr: u32, size: usize) -> libc::c_ulong {
+    let (read_bit, write_bit) = if cfg!(any(
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "sparc",
+        target_arch = "sparc64"
+    )) {
+        (2, 4)
+    } else {
+        (2, 1)
+    };
+    let direction = if read { read_bit } else { 0 } | if write { write_bit } else { 0 };
+    (direction << 30 | (size as u32) << 16 | (kind as u32) << 8 | nr) as libc::c_ulong
+}
+
+#[cfg(target_os = "linux")]
+const FS_IOC_FSGETXATTR: libc::c_ulong =
+    ioctl_number(true, false, b'X', 31, std::mem::size_of::<FsXattr>());
+#[cfg(target_os = "linux")]
+const FS_IOC_FSSETXATTR: libc::c_ulong =
+    ioctl_number(false, true, b'X', 32, std::mem::size_of::<FsXattr>());
+
+#[cfg(target_os = "linux")]
+fn get_fsxattr(file: &File) -> io::Result<FsXattr> {
+    use std::os::fd::AsRawFd;
+
+    let mut attr = FsXattr::default();
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FSGETXATTR as _, &mut attr) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(attr)
+}
+
+#[cfg(target_os = "linux")]
+pub fn get_project(file: &File) -> io::Result<Project> {
+    let attr = get_fsxattr(file)?;
+    Ok(Project {
+        id: attr.projid,
+        inherit: attr.xflags & PROJECT_INHERIT != 0,
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn set_fsxattr(file: &File, attr: &FsXattr) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FSSETXATTR as _, attr) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Give an open directory `project`, leaving its other attributes as they are
+#[cfg(target_os = "linux")]
+pub fn set_project(file: &File, project: Project) -> io::Result<()> {
+    let mut attr = get_fsxattr(file)?;
+    // ext4 without project quotas changes the flags before refusing the id, so the id goes first
+    // and a filesystem that can't have it is left as it was
+    if attr.projid != project.id {
+        attr.projid = project.id;
+        set_fsxattr(file, &attr)?;
+    }
+    let xflags = if project.inherit {
+        attr.xflags | PROJECT_INHERIT
+    } else {
+        attr.xflags & !PROJECT_INHERIT
+    };
+    if xflags != attr.xflags {
+        attr.xflags = xflags;
+        set_fsxattr(file, &attr)?;
+    }
+    Ok(())
+}
+
+/// `struct btrfs_ioctl_vol_args` from `linux/btrfs.h`
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct VolumeArgs {
+    fd: i64,
+    name: [u8; 4088],
+}
+
+/// `struct btrfs_ioctl_ino_lookup_args`
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct InodeLookupArgs {
+    tree_id: u64,
+    object_id: u64,
+    name: [u8; 4080],
+}
+
+/// `struct btrfs_ioctl_qgroup_create_args`
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct QgroupCreateArgs {
+    create: u64,
+    qgroup: u64,
+}
+
+/// `struct btrfs_ioctl_qgroup_assign_args`
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct QgroupAssignArgs {
+    assign: u64,
+    source: u64,
+    destination: u64,
+}
+
+/// `struct btrfs_ioctl_search_key`
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default)]
+struct SearchKey {
+    tree_id: u64,
+    min_object_id: u64,
+    max_object_id: u64,
+    min_offset: u64,
+    max_offset: u64,
+    min_transaction: u64,
+    max_transaction: u64,
+    min_type: u32,
+    max_type: u32,
+    items: u32,
+    unused: [u32; 1],
+    unused_more: [u64; 4],
+}
+
+/// `struct btrfs_ioctl_search_args`, the key followed by the items found
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct SearchArgs {
+    key: SearchKey,
+    buffer: [u8; 4096 - std::mem::size_of::<SearchKey>()],
+}
+
+/// `struct btrfs_ioctl_search_header`, in front of every item found
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct SearchHeader {
+    transaction: u64,
+    object_id: u64,
+    offset: u64,
+    item_type: u32,
+    length: u32,
+}
+
+#[cfg(target_os = "linux")]
+const BTRFS_IOC_SUBVOL_CREATE: libc::c_ulong =
+    ioctl_number(false, true, 0x94, 14, std::mem::size_of::<VolumeArgs>());
+#[cfg(target_os = "linux")]
+const BTRFS_IOC_TREE_SEARCH: libc::c_ulong =
+    ioctl_number(true, true, 0x94, 17, std::mem::size_of::<SearchArgs>());
+#[cfg(target_os = "linux")]
+const BTRFS_IOC_INO_LOOKUP: libc::c_ulong =
+    ioctl_number(true, true, 0x94, 18, std::mem::size_of::<InodeLookupArgs>());
+#[cfg(target_os = "linux")]
+const BTRFS_IOC_QGROUP_ASSIGN: libc::c_ulong = ioctl_number(
+    false,
+    true,
+    0x94,
+    41,
+    std::mem::size_of::<QgroupAssignArgs>(),
+);
+#[cfg(target_os = "linux")]
+const BTRFS_IOC_QGROUP_CREATE: libc::c_ulong = ioctl_number(
+    false,
+    true,
+    0x94,
+    42,
+    std::mem::size_of::<QgroupCreateArgs>(),
+);
+
+/// The tree quota groups and the relations between them are kept in
+#[cfg(target_os = "linux")]
+const QUOTA_TREE: u64 = 8;
+/// The type of the item saying one quota group is in another
+#[cfg(target_os = "linux")]
+const QGROUP_RELATION: u32 = 246;
+/// The object id of the root directory of every subvolume
+#[cfg(target_os = "linux")]
+const FIRST_FREE_OBJECT_ID: u64 = 256;
+
+/// A btrfs quota group id, like `1/256`, with the level in the top 16 bits
+pub fn qgroup_id(level: u16, id: u64) -> u64 {
+    u64::from(level) << 48 | id
+}
+
+#[cfg(target_os = "linux")]
+fn btrfs_ioctl<T>(file: &File, request: libc::c_ulong, args: &mut T) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // Assigning returns 1 when the numbers need a rescan to be right, which isn't an error
+    if unsafe { libc::ioctl(file.as_raw_fd(), request as _, args as *mut T) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Create a btrfs subvolume called `name` in the open directory `parent`
+#[cfg(target_os = "linux")]
+pub fn create_subvolume(parent: &File, name: &std::ffi::OsStr) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut args = VolumeArgs {
+        fd: 0,
+        name: [0; 4088],
+    };
+    let name = name.as_bytes();
+    // The last byte stays 0 to end the name
+    if name.len() >= args.name.len() || name.contains(&0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a valid subvolume name",
+        ));
+    }
+    args.name[..name.len()].copy_from_slice(name);
+    btrfs_ioctl(parent, BTRFS_IOC_SUBVOL_CREATE, &mut args)
+}
+
+/// The id of the subvolume an open file is in
+#[cfg(target_os = "linux")]
+pub fn subvolume_id(file: &File) -> io::Result<u64> {
+    let mut args = InodeLookupArgs {
+        tree_id: 0,
+        object_id: FIRST_FREE_OBJECT_ID,
+        name: [0; 4080],
+    };
+    btrfs_ioctl(file, BTRFS_IOC_INO_LOOKUP, &mut args)?;
+    Ok(args.tree_id)
+}
+
+/// The quota groups `qgroup` is directly in, searching the filesystem `file` is on
+#[cfg(target_os = "linux")]
+pub fn parent_qgroups(file: &File, qgroup: u64) -> io::Result<Vec<u64>> {
+    let mut parents = Vec::new();
+    let mut args = SearchArgs {
+        key: SearchKey::default(),
+        buffer: [0; 4096 - std::mem::size_of::<SearchKey>()],
+    };
+    // Relations are kept both ways, the ones keyed by `qgroup` with a larger offset lead up
+    let mut min_offset = qgroup + 1;
+    loop {
+        args.key = SearchKey {
+            tree_id: QUOTA_TREE,
+            min_object_id: qgroup,
+            max_object_id: qgroup,
+            min_offset,
+            max_offset: u64::MAX,
+            max_transaction: u64::MAX,
+            min_type: QGROUP_RELATION,
+            max_type: QGROUP_RELATION,
+            items: u32::MAX,
+            ..SearchKey::default()
+        };
+        btrfs_ioctl(file, BTRFS_IOC_TREE_SEARCH, &mut args)?;
+        if args.key.items == 0 {
+            return Ok(parents);
+        }
+        let mut position = 0;
+        for _ in 0..args.key.items {
+            let header = unsafe {
+                std::ptr::read_unaligned(args.buffer[position..].as_ptr().cast::<SearchHeader>())
+            };
+            position += std::mem::size_of::<SearchHeader>() + header.length as usize;
+            if header.object_id == qgroup && header.item_type == QGROUP_RELATION {
+                parents.push(header.offset);
+            }
+            min_offset = header.offset;
+        }
+        let Some(next) = min_offset.checked_add(1) else {
+            return Ok(parents);
+        };
+        min_offset = next;
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn create_qgroup(file: &File, qgroup: u64) -> io::Result<()> {
+    let mut args = QgroupCreateArgs { create: 1, qgroup };
+    match btrfs_ioctl(file, BTRFS_IOC_QGROUP_CREATE, &mut args) {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        result => result,
+    }
+}
+
+/// Put the quota group `child` in `parent`, which has to be of a higher level
+#[cfg(target_os = "linux")]
+pub fn assign_qgroup(file: &File, child: u64, parent: u64) -> io::Result<()> {
+    let mut args = QgroupAssignArgs {
+        assign: 1,
+        source: child,
+        destination: parent,
+    };
+    match btrfs_ioctl(file, BTRFS_IOC_QGROUP_ASSIGN, &mut args) {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn on_btrfs(_file: &File) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn get_project(_file: &File) -> io::Result<Project> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_project(_file: &File, _project: Project) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn create_subvolume(_parent: &File, _name: &std::ffi::OsStr) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn subvolume_id(_file: &File) -> io::Result<u64> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn parent_qgroups(_file: &File, _qgroup: u64) -> io::Result<Vec<u64>> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn create_qgroup(_file: &File, _qgroup: u64) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn assign_qgroup(_file: &File, _child: u64, _parent: u64) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "quotas and subvolumes are only supported on Linux",
+    )
+}
+
+/// Put an open directory in a project of its own, numbered after its inode, that what's created
+/// inside joins, unless it's in a project already. Directories keep their inode while they exist,
+/// so no two of them share a project this way.
+pub fn new_project(dir: &File) -> io::Result<()> {
+    let project = get_project(dir)?;
+    let id = match project.id {
+        0 => u32::try_from(dir.metadata()?.ino()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the inode number doesn't fit in a project id",
+            )
+        })?,
+        id => id,
+    };
+    set_project(dir, Project { id, inherit: true })
+}
+
+/// Add a new subvolume to the quota groups of the subvolume `parent` is in, like systemd does for
+/// `q`. With `intermediate`, for `Q`, it joins them through a new quota group of its own at level
+/// 1, numbered after the subvolume, that other subvolumes can be added to later.
+pub fn join_parent_qgroups(parent: &File, subvolume: &File, intermediate: bool) -> io::Result<()> {
+    let id = subvolume_id(subvolume)?;
+    let parents = parent_qgroups(parent, qgroup_id(0, subvolume_id(parent)?))?;
+    let mut member = qgroup_id(0, id);
+    if intermediate {
+        let own = qgroup_id(1, id);
+        create_qgroup(subvolume, own)?;
+        assign_qgroup(subvolume, member, own)?;
+        member = own;
+    }
+    // A quota group can only be in ones of a higher level
+    for parent in parents
+        .into_iter()
+        .filter(|&parent| parent >> 48 > member >> 48)
+    {
+        assign_qgroup(subvolume, member, parent)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn test_ioctl_numbers() {
+        use super::*;
+
+        // As the kernel headers have them
+        assert_eq!(FS_IOC_FSGETXATTR, 0x801c581f);
+        assert_eq!(FS_IOC_FSSETXATTR, 0x401c5820);
+        assert_eq!(BTRFS_IOC_SUBVOL_CREATE, 0x5000940e);
+        assert_eq!(BTRFS_IOC_TREE_SEARCH, 0xd0009411);
+        assert_eq!(BTRFS_IOC_INO_LOOKUP, 0xd0009412);
+        assert_eq!(BTRFS_IOC_QGROUP_ASSIGN, 0x40189429);
+        assert_eq!(BTRFS_IOC_QGROUP_CREATE, 0x4010942a);
+    }
+
+    #[test]
+    fn test_qgroup_id() {
+        assert_eq!(super::qgroup_id(0, 256), 256);
+        assert_eq!(super::qgroup_id(1, 256), 0x0001_0000_0000_0100);
+    }
+}
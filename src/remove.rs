@@ -0,0 +1,514 @@
+use std::{
+    collections::HashSet,
+    ffi::{CStr, CString, OsStr},
+    fs::{self, File},
+    io, mem,
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+        unix::ffi::OsStrExt,
+    },
+    path::Path,
+};
+
+use crate::apply::{too_deep, Ignored, DEFAULT_MAX_DEPTH};
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RemoveOptions<'a> {
+    /// Only remove what's inside the directory, keeping the directory itself
+    pub keep_root: bool,
+    /// Paths that `x` and `X` lines protect, which are left in place along with the directories
+    /// they're in
+    pub ignored: Option<&'a Ignored>,
+    /// How many levels of directories below the path are removed before giving up
+    pub max_depth: usize,
+}
+
+impl Default for RemoveOptions<'_> {
+    fn default() -> Self {
+        Self {
+            keep_root: false,
+            ignored: None,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+}
+
+impl RemoveOptions<'_> {
+    fn covers(&self, path: &Path) -> bool {
+        self.ignored.is_some_and(|ignored| ignored.covers(path))
+    }
+
+    fn keeps(&self, path: &Path) -> bool {
+        self.ignored.is_some_and(|ignored| ignored.keeps(path))
+    }
+}
+
+/// Remove `path` and everything below it. Only the parent of `path` is opened by path; the rest is
+/// opened and removed through `*at` calls with `O_NOFOLLOW`, so symlinks are removed rather than
+/// followed, even if one is swapped in while we're working. Other filesystems mounted below are
+/// left alone, along with the directories they're in.
+pub(crate) fn remove_tree_guarded(path: &Path, options: RemoveOptions) -> io::Result<()> {
+    if options.covers(path) {
+        return Ok(());
+    }
+    let (parent, name) = open_parent(path)?;
+    let keep_root = options.keep_root || options.keeps(path);
+    let stat = stat_at(parent.as_fd(), &name)?;
+    if !is_dir(&stat) {
+        if keep_root {
+            return Ok(());
+        }
+        return unlink_at(parent.as_fd(), &name, 0);
+    }
+    let dir = open_subdir(parent.as_fd(), &name, &stat)?;
+    let emptied = remove_contents(dir, path, stat.st_dev, options, options.max_depth)?;
+    if emptied && !keep_root {
+        unlink_at(parent.as_fd(), &name, libc::AT_REMOVEDIR)?;
+    }
+    Ok(())
+}
+
+/// Remove `path` itself, like `remove_tree_guarded` through the directory it's in, and only if
+/// it's empty when it's a directory
+pub(crate) fn remove_guarded(path: &Path) -> io::Result<()> {
+    let (parent, name) = open_parent(path)?;
+    let stat = stat_at(parent.as_fd(), &name)?;
+    let flags = if is_dir(&stat) { libc::AT_REMOVEDIR } else { 0 };
+    unlink_at(parent.as_fd(), &name, flags)
+}
+
+/// Open the directory `path` is in, and the name of `path` in it
+fn open_parent(path: &Path) -> io::Result<(OwnedFd, CString)> {
+    let Some(name) = path.file_name() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("refusing to remove {}", path.display()),
+        ));
+    };
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let parent = open_dir(libc::AT_FDCWD, &c_path(parent.as_os_str().as_bytes())?, 0)?;
+    Ok((parent, c_path(name.as_bytes())?))
+}
+
+/// Remove what's in `dir`, which is at `path`, returning whether all of it is gone. Directories
+/// more than `depth_left` levels down are refused before anything in `dir` is removed.
+fn remove_contents(
+    dir: OwnedFd,
+    path: &Path,
+    device: libc::dev_t,
+    options: RemoveOptions,
+    depth_left: usize,
+) -> io::Result<bool> {
+    let Some(depth_left) = depth_left.checked_sub(1) else {
+        return Err(too_deep(path));
+    };
+    let mut emptied = true;
+    for name in read_names(path)? {
+        let child_path = path.join(OsStr::from_bytes(name.to_bytes()));
+        if options.covers(&child_path) {
+            emptied = false;
+            continue;
+        }
+        // Entries that disappear under us are already gone, which is what we wanted
+        let stat = match stat_at(dir.as_fd(), &name) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            result => result?,
+        };
+        // Another filesystem mounted here is left alone, like walking skips it
+        if stat.st_dev != device {
+            emptied = false;
+            continue;
+        }
+        let result = if is_dir(&stat) {
+            let child = open_subdir(dir.as_fd(), &name, &stat)?;
+            if !remove_contents(child, &child_path, device, options, depth_left)?
+                || options.keeps(&child_path)
+            {
+                emptied = false;
+                continue;
+            }
+            unlink_at(dir.as_fd(), &name, libc::AT_REMOVEDIR)
+        } else if options.keeps(&child_path) {
+            emptied = false;
+            continue;
+        } else {
+            unlink_at(dir.as_fd(), &name, 0)
+        };
+        match result {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+    }
+    Ok(emptied)
+}
+
+/// Call `f` on everything below the directory at `path`, along with a handle to it, without
+/// following symlinks or crossing into other filesystems. Only `path` is opened by path, following
+/// a symlink there if we `follow`; the rest is opened through `*at` calls with `O_NOFOLLOW`, so a
+/// directory swapped for a symlink while we're walking fails the walk instead of leading out of the
+/// tree. Directories come open for reading, anything else with `O_PATH`. Each inode is visited
+/// once, and trees nested deeper than `max_depth` are an error.
+pub(crate) fn walk_entries<E: From<io::Error>>(
+    path: &Path,
+    follow: bool,
+    max_depth: usize,
+    f: &mut impl FnMut(&Path, &File) -> Result<(), E>,
+) -> Result<(), E> {
+    let dir = open_tree(path, follow)?;
+    let device = fstat(dir.as_fd())?.st_dev;
+    let mut visited = HashSet::new();
+    walk_contents(dir.as_fd(), path, device, max_depth, &mut visited, f)
+}
+
+fn walk_contents<E: From<io::Error>>(
+    dir: BorrowedFd,
+    path: &Path,
+    device: libc::dev_t,
+    depth_left: usize,
+    visited: &mut HashSet<libc::ino_t>,
+    f: &mut impl FnMut(&Path, &File) -> Result<(), E>,
+) -> Result<(), E> {
+    let Some(depth_left) = depth_left.checked_sub(1) else {
+        return Err(too_deep(path).into());
+    };
+    for name in read_names(path)? {
+        let stat = match stat_at(dir, &name) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            result => result?,
+        };
+        // Everything is on one device, so the inode alone identifies an entry
+        if stat.st_dev != device || !visited.insert(stat.st_ino) {
+            continue;
+        }
+        let child_path = path.join(OsStr::from_bytes(name.to_bytes()));
+        if is_dir(&stat) {
+            let child = File::from(open_subdir(dir, &name, &stat)?);
+            f(&child_path, &child)?;
+            walk_contents(child.as_fd(), &child_path, device, depth_left, visited, f)?;
+        } else {
+            f(&child_path, &open_entry(dir, &name, &stat)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// Open the directory at `path` to work on what's below it, following a symlink there if we
+/// `follow`
+pub(crate) fn open_tree(path: &Path, follow: bool) -> io::Result<OwnedFd> {
+    let flags = if follow { 0 } else { libc::O_NOFOLLOW };
+    open_dir(libc::AT_FDCWD, &c_path(path.as_os_str().as_bytes())?, flags)
+}
+
+/// Open the directory `name` in `parent`, failing if it's no longer the one `expected` describes
+pub(crate) fn open_subdir(
+    parent: BorrowedFd,
+    name: &CStr,
+    expected: &libc::stat,
+) -> io::Result<OwnedFd> {
+    let dir = open_dir(parent.as_raw_fd(), name, libc::O_NOFOLLOW)?;
+    check_same(&dir, name, expected)?;
+    Ok(dir)
+}
+
+/// Open `name` in `parent` with `O_PATH`, without following it if it's a symlink, failing if it's
+/// no longer what `expected` describes
+pub(crate) fn open_entry(
+    parent: BorrowedFd,
+    name: &CStr,
+    expected: &libc::stat,
+) -> io::Result<File> {
+    let flags = libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    let fd = check(unsafe { libc::openat(parent.as_raw_fd(), name.as_ptr(), flags) })?;
+    let entry = unsafe { OwnedFd::from_raw_fd(fd) };
+    check_same(&entry, name, expected)?;
+    Ok(entry.into())
+}
+
+fn check_same(fd: &OwnedFd, name: &CStr, expected: &libc::stat) -> io::Result<()> {
+    let stat = fstat(fd.as_fd())?;
+    if (stat.st_dev, stat.st_ino) != (expected.st_dev, expected.st_ino) {
+        return Err(io::Error::other(format!(
+            "{} was replaced while we were working on it",
+            name.to_bytes().escape_ascii()
+        )));
+    }
+    Ok(())
+}
+
+fn c_path(bytes: &[u8]) -> io::Result<CString> {
+    CString::new(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+pub(crate) fn is_dir(stat: &libc::stat) -> bool {
+    stat.st_mode & libc::S_IFMT == libc::S_IFDIR
+}
+
+fn open_dir(dir: RawFd, path: &CStr, flags: libc::c_int) -> io::Result<OwnedFd> {
+    let flags = flags | libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC;
+    let fd = check(unsafe { libc::openat(dir, path.as_ptr(), flags) })?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+pub(crate) fn stat_at(dir: BorrowedFd, name: &CStr) -> io::Result<libc::stat> {
+    let mut stat = unsafe { mem::zeroed() };
+    check(unsafe {
+        libc::fstatat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            &mut stat,
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    })?;
+    Ok(stat)
+}
+
+pub(crate) fn fstat(fd: BorrowedFd) -> io::Result<libc::stat> {
+    let mut stat = unsafe { mem::zeroed() };
+    check(unsafe { libc::fstat(fd.as_raw_fd(), &mut stat) })?;
+    Ok(stat)
+}
+
+pub(crate) fn unlink_at(dir: BorrowedFd, name: &CStr, flags: libc::c_int) -> io::Result<()> {
+    check(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), flags) })?;
+    Ok(())
+}
+
+/// Names in the directory at `path`, read up front so removing entries can't confuse the
+/// iteration. They're read by path, but only ever used relative to the directory we hold open, so
+/// if `path` leads somewhere else by now, the worst that happens is that names from there aren't
+/// found, and the directory isn't emptied.
+pub(crate) fn read_names(path: &Path) -> io::Result<Vec<CString>> {
+    fs::read_dir(path)?
+        .map(|entry| c_path(entry?.file_name().as_bytes()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs, io,
+        os::{
+            fd::{AsFd, OwnedFd},
+            unix::{ffi::OsStrExt, fs::symlink},
+        },
+        path::Path,
+    };
+
+    use tempfile::TempDir;
+
+    use crate::{
+        apply::DEFAULT_MAX_DEPTH,
+        remove::{
+            c_path, fstat, open_dir, open_subdir, remove_contents, remove_guarded,
+            remove_tree_guarded, stat_at, walk_entries, RemoveOptions,
+        },
+    };
+
+    fn open(path: &Path) -> OwnedFd {
+        open_dir(
+            libc::AT_FDCWD,
+            &c_path(path.as_os_str().as_bytes()).unwrap(),
+            0,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_remove_tree_guarded() {
+        let tmp = TempDir::new().unwrap();
+        let outside = tmp.path().join("outside");
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("keep"), b"").unwrap();
+        let tree = tmp.path().join("tree");
+        fs::create_dir_all(tree.join("a/b")).unwrap();
+        fs::write(tree.join("a/b/file"), b"").unwrap();
+        symlink(&outside, tree.join("a/link")).unwrap();
+
+        remove_tree_guarded(
+            &tree,
+            RemoveOptions {
+                keep_root: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(tree.is_dir());
+        assert_eq!(fs::read_dir(&tree).unwrap().count(), 0);
+
+        fs::create_dir(tree.join("c")).unwrap();
+        remove_tree_guarded(&tree, RemoveOptions::default()).unwrap();
+        assert!(!tree.exists());
+        assert!(outside.join("keep").exists());
+
+        // A symlink to a directory is removed itself, never followed
+        let link = tmp.path().join("link");
+        symlink(&outside, &link).unwrap();
+        remove_tree_guarded(&link, RemoveOptions::default()).unwrap();
+        assert!(!link.exists());
+        assert!(outside.join("keep").exists());
+    }
+
+    #[test]
+    fn test_remove_guarded() {
+        let tmp = TempDir::new().unwrap();
+        let full = tmp.path().join("full");
+        fs::create_dir_all(full.join("empty")).unwrap();
+        fs::write(full.join("file"), b"").unwrap();
+        let link = tmp.path().join("link");
+        symlink(&full, &link).unwrap();
+
+        remove_guarded(&full.join("file")).unwrap();
+        remove_guarded(&full.join("empty")).unwrap();
+        // The symlink goes, never what it points to
+        fs::create_dir(full.join("empty")).unwrap();
+        remove_guarded(&link).unwrap();
+        assert!(!link.exists());
+        assert!(full.join("empty").is_dir());
+        let error = remove_guarded(&full).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::DirectoryNotEmpty);
+    }
+
+    #[test]
+    fn test_remove_too_deep() {
+        let tmp = TempDir::new().unwrap();
+        let tree = tmp.path().join("tree");
+        fs::create_dir_all(tree.join("a/b/c")).unwrap();
+        fs::write(tree.join("file"), b"").unwrap();
+
+        let options = |max_depth| RemoveOptions {
+            max_depth,
+            ..Default::default()
+        };
+        // The tree itself and three directories below it
+        let error = remove_tree_guarded(&tree, options(3)).unwrap_err();
+        assert!(error.to_string().contains("nested too deeply"));
+        assert!(tree.join("a/b/c").is_dir());
+
+        remove_tree_guarded(&tree, options(4)).unwrap();
+        assert!(!tree.exists());
+    }
+
+    #[test]
+    fn test_symlink_swap_refused() {
+        let tmp = TempDir::new().unwrap();
+        let parent = open(tmp.path());
+        fs::create_dir(tmp.path().join("dir")).unwrap();
+        fs::create_dir(tmp.path().join("other")).unwrap();
+        fs::write(tmp.path().join("other/keep"), b"").unwrap();
+        let stat = stat_at(parent.as_fd(), c"dir").unwrap();
+
+        // Swapped for a symlink after we looked at it
+        fs::remove_dir(tmp.path().join("dir")).unwrap();
+        symlink(tmp.path().join("other"), tmp.path().join("dir")).unwrap();
+        assert!(open_subdir(parent.as_fd(), c"dir", &stat).is_err());
+
+        // Swapped for a different directory
+        fs::remove_file(tmp.path().join("dir")).unwrap();
+        fs::create_dir(tmp.path().join("dir")).unwrap();
+        let other = stat_at(parent.as_fd(), c"other").unwrap();
+        assert!(open_subdir(parent.as_fd(), c"dir", &other).is_err());
+        assert!(tmp.path().join("other/keep").exists());
+    }
+
+    #[test]
+    fn test_mount_crossing_skipped() {
+        let tmp = TempDir::new().unwrap();
+        fs::write(tmp.path().join("file"), b"").unwrap();
+        let dir = open(tmp.path());
+        let stat = fstat(dir.as_fd()).unwrap();
+
+        // Pretend the walk started on another filesystem, since tests can't mount one
+        let emptied = remove_contents(
+            dir,
+            tmp.path(),
+            stat.st_dev.wrapping_add(1),
+            RemoveOptions::default(),
+            DEFAULT_MAX_DEPTH,
+        )
+        .unwrap();
+        assert!(!emptied);
+        assert!(tmp.path().join("file").exists());
+    }
+
+    #[test]
+    fn test_walk_entries_loops_and_depth() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path().join("dir");
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        symlink("..", dir.join("a/b/up")).unwrap();
+        symlink(&dir, dir.join("a/root")).unwrap();
+        fs::write(dir.join("a/file"), b"").unwrap();
+        fs::hard_link(dir.join("a/file"), dir.join("a/b/hardlink")).unwrap();
+
+        let mut seen = Vec::new();
+        walk_entries(
+            &dir,
+            false,
+            DEFAULT_MAX_DEPTH,
+            &mut |path, _| -> io::Result<()> {
+                seen.push(path.strip_prefix(&dir).unwrap().to_owned());
+                Ok(())
+            },
+        )
+        .unwrap();
+        // Symlinks are visited but never followed, and the hardlink only once
+        assert_eq!(seen.len(), 5);
+        assert!(seen.contains(&Path::new("a/b/up").to_owned()));
+        assert!(seen.contains(&Path::new("a/root").to_owned()));
+
+        let error: io::Error = walk_entries(&dir, false, 1, &mut |_, _| Ok(())).unwrap_err();
+        assert!(error.to_string().contains("nested too deeply"));
+    }
+
+    #[test]
+    fn test_walk_entries_symlink_swap() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path().join("dir");
+        let outside = tmp.path().join("outside");
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::create_dir(&outside).unwrap();
+        fs::write(outside.join("secret"), b"").unwrap();
+        let swap = |path: &Path| -> io::Result<()> {
+            fs::rename(path, tmp.path().join("moved"))?;
+            symlink(&outside, path)
+        };
+
+        // A directory swapped before we get to it is only a symlink to us
+        let mut seen = Vec::new();
+        walk_entries(&dir, false, DEFAULT_MAX_DEPTH, &mut |path, _| {
+            if path == dir.join("a") {
+                swap(&dir.join("a/b"))?;
+            }
+            seen.push(path.to_owned());
+            io::Result::Ok(())
+        })
+        .unwrap();
+        assert_eq!(seen, [dir.join("a"), dir.join("a/b")]);
+
+        // One swapped once we've opened it is only looked at through the handle we have, so
+        // nothing outside is visited
+        fs::remove_dir_all(tmp.path().join("moved")).unwrap();
+        fs::remove_file(dir.join("a/b")).unwrap();
+        let mut seen = Vec::new();
+        walk_entries(&dir, false, DEFAULT_MAX_DEPTH, &mut |path, _| {
+            if path == dir.join("a") {
+                swap(&dir.join("a"))?;
+            }
+            seen.push(path.to_owned());
+            io::Result::Ok(())
+        })
+        .unwrap();
+        assert_eq!(seen, [dir.join("a")]);
+    }
+}
@@ -0,0 +1,94 @@
+use std::{io, path::Path};
+
+/// The default SELinux contexts the loaded policy gives files, by path. Only available with the
+/// `selinux` feature, which links against libselinux.
+pub struct Labels {
+    #[cfg_attr(not(feature = "selinux"), allow(dead_code))]
+    handle: *mut libc::c_void,
+}
+
+#[cfg(feature = "selinux")]
+mod ffi {
+    use std::ffi::{c_char, c_int, c_uint, c_void};
+
+    /// The file contexts backend of `selabel_open`
+    pub const SELABEL_CTX_FILE: c_uint = 0;
+
+    #[link(name = "selinux")]
+    extern "C" {
+        pub fn is_selinux_enabled() -> c_int;
+        pub fn security_getenforce() -> c_int;
+        pub fn selabel_open(backend: c_uint, options: *const c_void, count: c_uint) -> *mut c_void;
+        pub fn selabel_close(handle: *mut c_void);
+        pub fn selabel_lookup(
+            handle: *mut c_void,
+            context: *mut *mut c_char,
+            key: *const c_char,
+            mode: c_int,
+        ) -> c_int;
+        pub fn lsetfilecon(path: *const c_char, context: *const c_char) -> c_int;
+        pub fn freecon(context: *mut c_char);
+    }
+}
+
+#[cfg(feature = "selinux")]
+impl Labels {
+    /// Load the file contexts of the policy, or `None` if SELinux isn't enforcing, in which case
+    /// nothing gets relabeled
+    pub fn open() -> io::Result<Option<Self>> {
+        if unsafe { ffi::is_selinux_enabled() } <= 0 || unsafe { ffi::security_getenforce() } != 1 {
+            return Ok(None);
+        }
+        let handle = unsafe { ffi::selabel_open(ffi::SELABEL_CTX_FILE, std::ptr::null(), 0) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Some(Self { handle }))
+    }
+
+    /// Set the context of `path`, without following a symlink there, to the default for `key`,
+    /// the path it has once the tree is in use. `mode` has the type of the file. Paths the policy
+    /// has no context for are left alone.
+    pub fn relabel(&self, path: &Path, key: &Path, mode: u32) -> io::Result<()> {
+        use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+        let c_path = |path: &Path| {
+            CString::new(path.as_os_str().as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+        };
+        let (path, key) = (c_path(path)?, c_path(key)?);
+        let mut context = std::ptr::null_mut();
+        if unsafe { ffi::selabel_lookup(self.handle, &mut context, key.as_ptr(), mode as _) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::NotFound {
+                return Ok(());
+            }
+            return Err(e);
+        }
+        let result = unsafe { ffi::lsetfilecon(path.as_ptr(), context) };
+        let e = io::Error::last_os_error();
+        unsafe { ffi::freecon(context) };
+        if result != 0 {
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "selinux")]
+impl Drop for Labels {
+    fn drop(&mut self) {
+        unsafe { ffi::selabel_close(self.handle) }
+    }
+}
+
+#[cfg(not(feature = "selinux"))]
+impl Labels {
+    pub fn open() -> io::Result<Option<Self>> {
+        Ok(None)
+    }
+
+    pub fn relabel(&self, _path: &Path, _key: &Path, _mode: u32) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,530 @@
+use std::{
+    env,
+    error::Error,
+    ffi::CStr,
+    fmt, fs, io, mem,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config_file::{Specifier, SpecifierString},
+    users,
+};
+
+/// Why a specifier couldn't be expanded, each naming the specifier
+#[derive(Debug)]
+pub enum ExpandError {
+    /// There's no way to get the value on this platform, like the boot ID outside Linux
+    Unsupported(Specifier),
+    /// Where the value comes from couldn't be read, like a missing `/etc/machine-id` or passwd
+    /// entry
+    Unavailable(Specifier, io::Error),
+    /// The value was read but can't be used, like one containing a NUL
+    Malformed(Specifier, io::Error),
+    /// The file the value comes from was read but doesn't set it, like an os-release without
+    /// `BUILD_ID`, which is common enough on minimal systems that `--graceful` skips the line
+    SpecifierSourceMissingField {
+        specifier: Specifier,
+        field: &'static str,
+    },
+}
+
+/// Wrapped in the `io::Error` of a resolver when a field it looks for isn't set
+#[derive(Debug)]
+struct MissingField(&'static str);
+
+impl fmt::Display for MissingField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is not set", self.0)
+    }
+}
+
+impl Error for MissingField {}
+
+impl ExpandError {
+    /// Sort a resolver's error by its kind
+    fn new(specifier: Specifier, source: io::Error) -> Self {
+        if let Some(MissingField(field)) = source.get_ref().and_then(|e| e.downcast_ref()) {
+            return Self::SpecifierSourceMissingField { specifier, field };
+        }
+        match source.kind() {
+            io::ErrorKind::Unsupported => Self::Unsupported(specifier),
+            io::ErrorKind::InvalidData => Self::Malformed(specifier, source),
+            _ => Self::Unavailable(specifier, source),
+        }
+    }
+
+    pub fn specifier(&self) -> Specifier {
+        match *self {
+            Self::Unsupported(specifier)
+            | Self::Unavailable(specifier, _)
+            | Self::Malformed(specifier, _)
+            | Self::SpecifierSourceMissingField { specifier, .. } => specifier,
+        }
+    }
+}
+
+impl fmt::Display for ExpandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let specifier = char::from(self.specifier().to_char());
+        match self {
+            Self::Unsupported(_) => {
+                write!(
+                    f,
+                    "specifier %{specifier} is not supported on this platform"
+                )
+            }
+            Self::Unavailable(_, source) => {
+                write!(f, "failed to resolve specifier %{specifier}: {source}")
+            }
+            Self::Malformed(_, source) => {
+                write!(f, "specifier %{specifier} has a malformed value: {source}")
+            }
+            Self::SpecifierSourceMissingField { field, .. } => {
+                write!(f, "specifier %{specifier} needs {field}, which is not set")
+            }
+        }
+    }
+}
+
+impl Error for ExpandError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Unsupported(_) | Self::SpecifierSourceMissingField { .. } => None,
+            Self::Unavailable(_, source) | Self::Malformed(_, source) => Some(source),
+        }
+    }
+}
+
+/// Provides the values that specifiers expand to
+pub trait Resolve {
+    /// Value of `specifier`, never called for `%%`
+    fn resolve(&self, specifier: Specifier) -> io::Result<Vec<u8>>;
+}
+
+impl SpecifierString {
+    /// Replace every specifier with its value from `resolver`
+    pub fn expand(&self, resolver: &impl Resolve) -> Result<Vec<u8>, ExpandError> {
+        let mut expanded = self.0.clone();
+        for &(specifier, ref segment) in self.1.iter() {
+            if specifier == Specifier::PercentSign {
+                expanded.push(b'%');
+            } else {
+                let value = resolver
+                    .resolve(specifier)
+                    .map_err(|source| ExpandError::new(specifier, source))?;
+                // The parser already rejected NULs in the literal parts, but a value read from a
+                // file could still have one, which would silently cut the path short in syscalls
+                if value.contains(&0) {
+                    return Err(ExpandError::Malformed(
+                        specifier,
+                        io::Error::new(io::ErrorKind::InvalidData, "value contains a NUL"),
+                    ));
+                }
+                expanded.extend_from_slice(&value);
+            }
+            expanded.extend_from_slice(segment);
+        }
+        Ok(expanded)
+    }
+}
+
+/// Where specifiers describing the installation are resolved from when using `--root`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SpecifierSource {
+    /// The running system, like systemd-tmpfiles
+    Host,
+    /// Files inside the root directory
+    Target,
+}
+
+/// Resolves specifiers for a system, either the host or a tree below `--root`.
+///
+/// In target mode the machine ID, os-release fields and user and group names are read from files
+/// in the tree. The boot ID (%b), hostname (%H, %l), kernel release (%v) and architecture (%a)
+/// aren't stored in the tree, so they always describe the running host.
+pub struct SpecifierContext {
+    root: PathBuf,
+    source: SpecifierSource,
+}
+
+impl SpecifierContext {
+    pub fn new(root: &Path, source: SpecifierSource) -> Self {
+        let root = match source {
+            SpecifierSource::Host => PathBuf::from("/"),
+            SpecifierSource::Target => root.to_owned(),
+        };
+        Self { root, source }
+    }
+
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.root.join(path))
+    }
+
+    fn os_release(&self, key: &'static str) -> io::Result<Vec<u8>> {
+        let contents = match self.read("etc/os-release") {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => self.read("usr/lib/os-release"),
+            result => result,
+        }?;
+        os_release_field(&contents, key)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, MissingField(key)))
+    }
+
+    fn machine_id(&self) -> io::Result<Vec<u8>> {
+        Ok(self.read("etc/machine-id")?.trim_ascii().to_vec())
+    }
+
+    /// Name and home directory of the user running us
+    fn user(&self) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let uid = unsafe { libc::getuid() };
+        match self.source {
+            SpecifierSource::Host => users::user_by_uid(uid),
+            SpecifierSource::Target => {
+                let passwd = self.read("etc/passwd")?;
+                let entry = database_entry(&passwd, uid).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("no passwd entry for {uid}"),
+                    )
+                })?;
+                let home = entry.get(5).copied().unwrap_or_default();
+                Ok((entry[0].to_vec(), home.to_vec()))
+            }
+        }
+    }
+
+    fn group_name(&self) -> io::Result<Vec<u8>> {
+        let gid = unsafe { libc::getgid() };
+        match self.source {
+            SpecifierSource::Host => users::group_name_by_gid(gid),
+            SpecifierSource::Target => {
+                let group = self.read("etc/group")?;
+                let entry = database_entry(&group, gid).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, format!("no group entry for {gid}"))
+                })?;
+                Ok(entry[0].to_vec())
+            }
+        }
+    }
+}
+
+impl Resolve for SpecifierContext {
+    fn resolve(&self, specifier: Specifier) -> io::Result<Vec<u8>> {
+        use Specifier::*;
+        Ok(match specifier {
+            Architecture => architecture().into(),
+            ImageVersion => self.os_release("IMAGE_VERSION")?,
+            BootID => boot_id()?,
+            BuildID => self.os_release("BUILD_ID")?,
+            CacheDir => b"/var/cache".to_vec(),
+            UserGroup => self.group_name()?,
+            UserGID => unsafe { libc::getgid() }.to_string().into_bytes(),
+            UserHome => self.user()?.1,
+            Hostname => hostname()?,
+            ShortHostname => short_hostname(&hostname()?).to_vec(),
+            LogDir => b"/var/log".to_vec(),
+            MachineID => self.machine_id()?,
+            ImageID => self.os_release("IMAGE_ID")?,
+            OperatingSystemID => self.os_release("ID")?,
+            StateDir => b"/var/lib".to_vec(),
+            RuntimeDir => b"/run".to_vec(),
+            TempDir => temp_dir("/tmp"),
+            Username => self.user()?.0,
+            UserUID => unsafe { libc::getuid() }.to_string().into_bytes(),
+            KernelRelease => kernel_release()?,
+            PersistentTempDir => temp_dir("/var/tmp"),
+            VersionID => self.os_release("VERSION_ID")?,
+            VariantID => self.os_release("VARIANT_ID")?,
+            PercentSign => b"%".to_vec(),
+        })
+    }
+}
+
+/// Look up `key` in an os-release file, the last assignment wins like in a shell
+fn os_release_field(contents: &[u8], key: &str) -> Option<Vec<u8>> {
+    contents
+        .split(|&ch| ch == b'\n')
+        .rev()
+        .find_map(|line| {
+            line.trim_ascii()
+                .strip_prefix(key.as_bytes())?
+                .strip_prefix(b"=")
+        })
+        .map(unquote)
+}
+
+fn unquote(value: &[u8]) -> Vec<u8> {
+    match value {
+        [b'\'', inner @ .., b'\''] => inner.to_vec(),
+        [b'"', inner @ .., b'"'] => {
+            let mut unquoted = Vec::with_capacity(inner.len());
+            let mut escaped = false;
+            for &ch in inner {
+                if ch == b'\\' && !escaped {
+                    escaped = true;
+                } else {
+                    unquoted.push(ch);
+                    escaped = false;
+                }
+            }
+            unquoted
+        }
+        _ => value.to_vec(),
+    }
+}
+
+/// Find the entry for `id` in a passwd or group style database
+fn database_entry(database: &[u8], id: u32) -> Option<Vec<&[u8]>> {
+    let id = id.to_string();
+    database
+        .split(|&ch| ch == b'\n')
+        .map(|line| line.split(|&ch| ch == b':').collect::<Vec<_>>())
+        .find(|fields| fields.get(2).is_some_and(|field| *field == id.as_bytes()))
+}
+
+fn architecture() -> &'static [u8] {
+    // systemd's names, which differ from rust's for a few architectures
+    match env::consts::ARCH {
+        "x86_64" => b"x86-64",
+        "aarch64" => b"arm64",
+        "powerpc64" if cfg!(target_endian = "little") => b"ppc64-le",
+        "powerpc64" => b"ppc64",
+        "powerpc" => b"ppc",
+        "riscv64" => b"riscv64",
+        arch => arch.as_bytes(),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn boot_id() -> io::Result<Vec<u8>> {
+    let id = fs::read("/proc/sys/kernel/random/boot_id")?;
+    Ok(id
+        .trim_ascii()
+        .iter()
+        .copied()
+        .filter(|&ch| ch != b'-')
+        .collect())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn boot_id() -> io::Result<Vec<u8>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "boot IDs are only available on Linux",
+    ))
+}
+
+fn temp_dir(default: &str) -> Vec<u8> {
+    ["TMPDIR", "TEMP", "TMP"]
+        .into_iter()
+        .filter_map(env::var_os)
+        .find(|dir| Path::new(dir).is_absolute())
+        .map(|dir| dir.as_bytes().to_vec())
+        .unwrap_or_else(|| default.as_bytes().to_vec())
+}
+
+/// Raw bytes of the hostname, which aren't necessarily UTF-8
+fn hostname() -> io::Result<Vec<u8>> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len() - 1) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(CStr::from_bytes_until_nul(&buf)
+        .unwrap()
+        .to_bytes()
+        .to_vec())
+}
+
+/// Hostname up to the first `.`, compared as bytes so any encoding works
+fn short_hostname(hostname: &[u8]) -> &[u8] {
+    hostname.split(|&ch| ch == b'.').next().unwrap()
+}
+
+fn kernel_release() -> io::Result<Vec<u8>> {
+    let mut uts: libc::utsname = unsafe { mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { CStr::from_ptr(uts.release.as_ptr()) }
+        .to_bytes()
+        .to_vec())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{ffi::OsStr, fs, io, os::unix::ffi::OsStrExt, path::Path};
+
+    use tempfile::TempDir;
+
+    use crate::{
+        config_file::{Specifier, SpecifierString},
+        parser::{parse_line, FileSpan},
+        specifier::{
+            os_release_field, short_hostname, ExpandError, Resolve, SpecifierContext,
+            SpecifierSource,
+        },
+    };
+
+    struct Fixed;
+
+    impl Resolve for Fixed {
+        fn resolve(&self, specifier: Specifier) -> io::Result<Vec<u8>> {
+            match specifier {
+                Specifier::MachineID => Ok(b"0123456789abcdef0123456789abcdef".to_vec()),
+                Specifier::Hostname => Ok(b"h\xf6st.\xff.example".to_vec()),
+                Specifier::KernelRelease => Ok(b"6.1\0/etc".to_vec()),
+                Specifier::BootID => Err(io::ErrorKind::Unsupported.into()),
+                Specifier::UserHome => Ok(b"/home/user".to_vec()),
+                _ => Err(io::ErrorKind::NotFound.into()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_expand() {
+        let string = SpecifierString(
+            b"/run/".to_vec(),
+            [
+                (Specifier::MachineID, b"/100".to_vec()),
+                (Specifier::PercentSign, b"".to_vec()),
+            ]
+            .into(),
+        );
+        assert_eq!(
+            string.expand(&Fixed).unwrap(),
+            b"/run/0123456789abcdef0123456789abcdef/100%"
+        );
+        let error = SpecifierString(b"/".to_vec(), [(Specifier::BootID, Vec::new())].into())
+            .expand(&Fixed)
+            .unwrap_err();
+        assert!(matches!(error, ExpandError::Unsupported(Specifier::BootID)));
+    }
+
+    #[test]
+    fn test_expand_leading_home() {
+        // A leading %h makes the path absolute, and the slashes in its value separate components
+        // like any others
+        let line = parse_line(FileSpan::from_slice(
+            b"L %h/.config/app - - - - /target",
+            Path::new(""),
+        ))
+        .unwrap();
+        let expanded = line.path.data.expand(&Fixed).unwrap();
+        assert_eq!(expanded, b"/home/user/.config/app");
+        let path = Path::new(OsStr::from_bytes(&expanded));
+        assert!(path.is_absolute());
+        assert_eq!(
+            path.components().map(|c| c.as_os_str()).collect::<Vec<_>>(),
+            ["/", "home", "user", ".config", "app"]
+        );
+    }
+
+    #[test]
+    fn test_runtime_and_temp_dirs() {
+        // Like systemd, lowercase is the runtime directory and uppercase the temporary one
+        assert_eq!(Specifier::parse(b't'), Some(Specifier::RuntimeDir));
+        assert_eq!(Specifier::parse(b'T'), Some(Specifier::TempDir));
+        assert_eq!(Specifier::RuntimeDir.to_char(), b't');
+        assert_eq!(Specifier::TempDir.to_char(), b'T');
+        let context = SpecifierContext::new("/".as_ref(), SpecifierSource::Host);
+        let string = SpecifierString(Vec::new(), [(Specifier::RuntimeDir, b"/x".to_vec())].into());
+        assert_eq!(string.expand(&context).unwrap(), b"/run/x");
+    }
+
+    #[test]
+    fn test_expand_nul() {
+        let error = SpecifierString(
+            b"/lib/modules/".to_vec(),
+            [(Specifier::KernelRelease, Vec::new())].into(),
+        )
+        .expand(&Fixed)
+        .unwrap_err();
+        assert!(matches!(
+            error,
+            ExpandError::Malformed(Specifier::KernelRelease, _)
+        ));
+    }
+
+    #[test]
+    fn test_non_utf8_hostname() {
+        let string = SpecifierString(
+            b"/run/".to_vec(),
+            [(Specifier::Hostname, b"/x".to_vec())].into(),
+        );
+        assert_eq!(
+            string.expand(&Fixed).unwrap(),
+            b"/run/h\xf6st.\xff.example/x"
+        );
+        assert_eq!(short_hostname(b"h\xf6st.\xff.example"), b"h\xf6st");
+        assert_eq!(short_hostname(b"\xff"), b"\xff");
+    }
+
+    #[test]
+    fn test_os_release() {
+        let contents = b"NAME=\"NixOS\"\nID=nixos\nVERSION_ID='24.05'\nBUILD_ID=\"a\\\"b\"\n";
+        assert_eq!(os_release_field(contents, "ID"), Some(b"nixos".to_vec()));
+        assert_eq!(os_release_field(contents, "NAME"), Some(b"NixOS".to_vec()));
+        assert_eq!(
+            os_release_field(contents, "VERSION_ID"),
+            Some(b"24.05".to_vec())
+        );
+        assert_eq!(
+            os_release_field(contents, "BUILD_ID"),
+            Some(b"a\"b".to_vec())
+        );
+        assert_eq!(os_release_field(contents, "IMAGE_ID"), None);
+    }
+
+    #[test]
+    fn test_target_source() {
+        let root = TempDir::new().unwrap();
+        fs::create_dir_all(root.path().join("usr/lib")).unwrap();
+        fs::create_dir_all(root.path().join("etc")).unwrap();
+        fs::write(
+            root.path().join("etc/machine-id"),
+            b"fedcba9876543210fedcba9876543210\n",
+        )
+        .unwrap();
+        fs::write(root.path().join("usr/lib/os-release"), b"ID=target\n").unwrap();
+
+        let target = SpecifierContext::new(root.path(), SpecifierSource::Target);
+        assert_eq!(
+            target.resolve(Specifier::MachineID).unwrap(),
+            b"fedcba9876543210fedcba9876543210"
+        );
+        assert_eq!(
+            target.resolve(Specifier::OperatingSystemID).unwrap(),
+            b"target"
+        );
+        let expand = |specifier| {
+            SpecifierString(Vec::new(), [(specifier, Vec::new())].into())
+                .expand(&target)
+                .unwrap_err()
+        };
+        // The target has no passwd database
+        assert!(matches!(
+            expand(Specifier::Username),
+            ExpandError::Unavailable(Specifier::Username, _)
+        ));
+        assert!(matches!(
+            expand(Specifier::BuildID),
+            ExpandError::SpecifierSourceMissingField {
+                specifier: Specifier::BuildID,
+                field: "BUILD_ID"
+            }
+        ));
+        fs::write(root.path().join("etc/machine-id"), b"0123\0\n").unwrap();
+        assert!(matches!(
+            expand(Specifier::MachineID),
+            ExpandError::Malformed(Specifier::MachineID, _)
+        ));
+
+        // Host mode ignores the root entirely
+        let host = SpecifierContext::new(root.path(), SpecifierSource::Host);
+        assert_ne!(
+            host.resolve(Specifier::MachineID).ok(),
+            Some(b"fedcba9876543210fedcba9876543210".to_vec())
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::config_file::Line;
+
+/// How many of the slowest lines to report
+const SLOWEST_LINES: usize = 10;
+
+struct Timing {
+    pass: &'static str,
+    action: char,
+    line: String,
+    elapsed: Duration,
+}
+
+/// Time spent applying each line, printed with `--statistics`
+pub struct Statistics {
+    start: Instant,
+    timings: Vec<Timing>,
+}
+
+impl Statistics {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            timings: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, pass: &'static str, line: &Line, elapsed: Duration) {
+        self.timings.push(Timing {
+            pass,
+            action: line.line_type.data.action.type_char(),
+            line: line.to_string(),
+            elapsed,
+        });
+    }
+}
+
+impl Default for Statistics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for Statistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Applied {} lines in {:?}",
+            self.timings.len(),
+            self.start.elapsed()
+        )?;
+
+        // Keep passes in the order they ran, and actions sorted within each pass
+        let mut passes: Vec<(&str, BTreeMap<char, usize>)> = Vec::new();
+        for timing in &self.timings {
+            let index = match passes.iter().position(|(pass, _)| *pass == timing.pass) {
+                Some(index) => index,
+                None => {
+                    passes.push((timing.pass, BTreeMap::new()));
+                    passes.len() - 1
+                }
+            };
+            *passes[index].1.entry(timing.action).or_default() += 1;
+        }
+        for (pass, actions) in passes {
+            let counts = actions
+                .iter()
+                .map(|(action, count)| format!("{action}: {count}"))
+                .collect::<Vec<_>>();
+            writeln!(f, "  {pass}: {}", counts.join(", "))?;
+        }
+
+        let mut slowest = self.timings.iter().collect::<Vec<_>>();
+        slowest.sort_by_key(|timing| std::cmp::Reverse(timing.elapsed));
+        if !slowest.is_empty() {
+            writeln!(f, "Slowest lines:")?;
+        }
+        for timing in slowest.into_iter().take(SLOWEST_LINES) {
+            writeln!(
+                f,
+                "  {:>12?}  {:<6}  {}",
+                timing.elapsed, timing.pass, timing.line
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::Path, time::Duration};
+
+    use crate::{
+        parser::{parse_line, FileSpan},
+        statistics::Statistics,
+    };
+
+    #[test]
+    fn test_report() {
+        let file = Path::new("");
+        let dir = parse_line(FileSpan::from_slice(b"d /tmp/a", file)).unwrap();
+        let other = parse_line(FileSpan::from_slice(b"d /tmp/b", file)).unwrap();
+        let symlink = parse_line(FileSpan::from_slice(b"L /tmp/c - - - - /", file)).unwrap();
+
+        let mut statistics = Statistics::new();
+        statistics.record("remove", &dir, Duration::from_millis(1));
+        statistics.record("create", &dir, Duration::from_millis(2));
+        statistics.record("create", &other, Duration::from_millis(30));
+        statistics.record("create", &symlink, Duration::from_millis(4));
+
+        let report = statistics.to_string();
+        let lines = report.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("Applied 4 lines in "));
+        assert_eq!(lines[1], "  remove: d: 1");
+        assert_eq!(lines[2], "  create: L: 1, d: 2");
+        assert_eq!(lines[3], "Slowest lines:");
+        assert!(lines[4].ends_with("create  d /tmp/b"));
+        assert!(lines[7].ends_with("remove  d /tmp/a"));
+    }
+}
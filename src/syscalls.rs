@@ -0,0 +1,338 @@
+use std::{
+    ffi::{CStr, CString},
+    fs::{self, File},
+    io,
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(test)]
+use std::{cell::RefCell, collections::HashMap};
+
+use crate::{attr, xattr};
+
+/// The calls applying a line makes to change the filesystem, so tests can see what a line does
+/// without privileges. Calls that change an object we already have open take both the handle,
+/// which is what gets changed, and its path, to describe the call.
+pub trait Syscalls {
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()>;
+    /// Create a fifo with the permissions in `mode`, before the umask
+    fn mkfifo(&self, path: &Path, mode: u32) -> io::Result<()>;
+    /// Create a device node, `mode` has its type and permissions, before the umask
+    fn mknod(&self, path: &Path, mode: u32, major: u32, minor: u32) -> io::Result<()>;
+    /// Set all 12 permission bits of an open file
+    fn chmod(&self, path: &Path, file: &File, mode: u32) -> io::Result<()>;
+    /// Change the owner and group of an open file, `None` leaves that one alone
+    fn chown(&self, path: &Path, file: &File, uid: Option<u32>, gid: Option<u32>)
+        -> io::Result<()>;
+    /// The value of an extended attribute of an open file, `None` if it doesn't have it
+    fn get_xattr(&self, path: &Path, file: &File, name: &CStr) -> io::Result<Option<Vec<u8>>>;
+    fn set_xattr(&self, path: &Path, file: &File, name: &CStr, value: &[u8]) -> io::Result<()>;
+    /// Inode flags of an open file, like append only, as `FS_IOC_GETFLAGS` returns them
+    fn get_flags(&self, path: &Path, file: &File) -> io::Result<u32>;
+    fn set_flags(&self, path: &Path, file: &File, flags: u32) -> io::Result<()>;
+}
+
+impl<T: Syscalls + ?Sized> Syscalls for std::rc::Rc<T> {
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+        (**self).symlink(target, link)
+    }
+
+    fn mkfifo(&self, path: &Path, mode: u32) -> io::Result<()> {
+        (**self).mkfifo(path, mode)
+    }
+
+    fn mknod(&self, path: &Path, mode: u32, major: u32, minor: u32) -> io::Result<()> {
+        (**self).mknod(path, mode, major, minor)
+    }
+
+    fn chmod(&self, path: &Path, file: &File, mode: u32) -> io::Result<()> {
+        (**self).chmod(path, file, mode)
+    }
+
+    fn chown(
+        &self,
+        path: &Path,
+        file: &File,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> io::Result<()> {
+        (**self).chown(path, file, uid, gid)
+    }
+
+    fn get_xattr(&self, path: &Path, file: &File, name: &CStr) -> io::Result<Option<Vec<u8>>> {
+        (**self).get_xattr(path, file, name)
+    }
+
+    fn set_xattr(&self, path: &Path, file: &File, name: &CStr, value: &[u8]) -> io::Result<()> {
+        (**self).set_xattr(path, file, name, value)
+    }
+
+    fn get_flags(&self, path: &Path, file: &File) -> io::Result<u32> {
+        (**self).get_flags(path, file)
+    }
+
+    fn set_flags(&self, path: &Path, file: &File, flags: u32) -> io::Result<()> {
+        (**self).set_flags(path, file, flags)
+    }
+}
+
+/// The real system calls
+pub struct Libc;
+
+impl Syscalls for Libc {
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+        std::os::unix::fs::symlink(target, link)
+    }
+
+    fn mkfifo(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let path = c_path(path)?;
+        if unsafe { libc::mkfifo(path.as_ptr(), mode as libc::mode_t) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn mknod(&self, path: &Path, mode: u32, major: u32, minor: u32) -> io::Result<()> {
+        let path = c_path(path)?;
+        let device = libc::makedev(major, minor);
+        if unsafe { libc::mknod(path.as_ptr(), mode as libc::mode_t, device) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn chmod(&self, _path: &Path, file: &File, mode: u32) -> io::Result<()> {
+        match file.set_permissions(fs::Permissions::from_mode(mode)) {
+            Err(e) if e.raw_os_error() == Some(libc::EBADF) => {
+                fs::set_permissions(fd_path(file), fs::Permissions::from_mode(mode))
+            }
+            result => result,
+        }
+    }
+
+    fn chown(
+        &self,
+        _path: &Path,
+        file: &File,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> io::Result<()> {
+        match std::os::unix::fs::fchown(file, uid, gid) {
+            Err(e) if e.raw_os_error() == Some(libc::EBADF) => {
+                std::os::unix::fs::chown(fd_path(file), uid, gid)
+            }
+            result => result,
+        }
+    }
+
+    // Through the link in /proc, since the f*xattr calls refuse O_PATH handles
+    fn get_xattr(&self, _path: &Path, file: &File, name: &CStr) -> io::Result<Option<Vec<u8>>> {
+        xattr::get_xattr(&fd_path(file), name)
+    }
+
+    fn set_xattr(&self, _path: &Path, file: &File, name: &CStr, value: &[u8]) -> io::Result<()> {
+        xattr::set_xattr(&fd_path(file), name, value)
+    }
+
+    fn get_flags(&self, _path: &Path, file: &File) -> io::Result<u32> {
+        attr::get_flags(file)
+    }
+
+    fn set_flags(&self, _path: &Path, file: &File, flags: u32) -> io::Result<()> {
+        attr::set_flags(file, flags)
+    }
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// A path to an open file that works for handles opened with `O_PATH`, which device nodes are
+/// opened with so their driver never sees an open. `fchmod` and `fchown` refuse those handles,
+/// but going through the link in `/proc` changes the file they point to.
+pub(crate) fn fd_path(file: &File) -> PathBuf {
+    use std::os::fd::AsRawFd;
+
+    PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
+
+/// Set the access and modification times of the file at `path`, following symlinks, for handles
+/// that only have a path from `fd_path`
+pub(crate) fn set_times(path: &Path, time: SystemTime) -> io::Result<()> {
+    let since_epoch = time
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let time = libc::timespec {
+        tv_sec: since_epoch.as_secs() as libc::time_t,
+        tv_nsec: since_epoch.subsec_nanos() as _,
+    };
+    let path = c_path(path)?;
+    if unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), [time, time].as_ptr(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A call made through `Recording`
+#[cfg(test)]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Call {
+    Symlink {
+        target: PathBuf,
+        link: PathBuf,
+    },
+    Mkfifo {
+        path: PathBuf,
+        mode: u32,
+    },
+    Mknod {
+        path: PathBuf,
+        mode: u32,
+        major: u32,
+        minor: u32,
+    },
+    Chmod {
+        path: PathBuf,
+        mode: u32,
+    },
+    Chown {
+        path: PathBuf,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    },
+    SetXattr {
+        path: PathBuf,
+        name: CString,
+        value: Vec<u8>,
+    },
+    SetFlags {
+        path: PathBuf,
+        flags: u32,
+    },
+}
+
+/// Records every call in order without making it, so nothing is changed and no privileges are
+/// needed. Calls succeed unless `errors` has their path. Extended attributes and inode flags that
+/// are set are kept, so reading them back sees the change.
+#[cfg(test)]
+#[derive(Default)]
+pub struct Recording {
+    pub calls: RefCell<Vec<Call>>,
+    /// Calls on these paths fail with the given error, after being recorded
+    pub errors: RefCell<HashMap<PathBuf, io::ErrorKind>>,
+    /// Extended attributes by path and name
+    pub xattrs: RefCell<HashMap<(PathBuf, CString), Vec<u8>>>,
+    /// Inode flags by path, files that aren't in here have none
+    pub flags: RefCell<HashMap<PathBuf, u32>>,
+}
+
+#[cfg(test)]
+impl Recording {
+    fn record(&self, path: &Path, call: Call) -> io::Result<()> {
+        self.calls.borrow_mut().push(call);
+        match self.errors.borrow().get(path) {
+            Some(&kind) => Err(kind.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Syscalls for Recording {
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+        self.record(
+            link,
+            Call::Symlink {
+                target: target.to_owned(),
+                link: link.to_owned(),
+            },
+        )
+    }
+
+    fn mkfifo(&self, path: &Path, mode: u32) -> io::Result<()> {
+        self.record(
+            path,
+            Call::Mkfifo {
+                path: path.to_owned(),
+                mode,
+            },
+        )
+    }
+
+    fn mknod(&self, path: &Path, mode: u32, major: u32, minor: u32) -> io::Result<()> {
+        self.record(
+            path,
+            Call::Mknod {
+                path: path.to_owned(),
+                mode,
+                major,
+                minor,
+            },
+        )
+    }
+
+    fn chmod(&self, path: &Path, _file: &File, mode: u32) -> io::Result<()> {
+        self.record(
+            path,
+            Call::Chmod {
+                path: path.to_owned(),
+                mode,
+            },
+        )
+    }
+
+    fn chown(
+        &self,
+        path: &Path,
+        _file: &File,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> io::Result<()> {
+        self.record(
+            path,
+            Call::Chown {
+                path: path.to_owned(),
+                uid,
+                gid,
+            },
+        )
+    }
+
+    fn get_xattr(&self, path: &Path, _file: &File, name: &CStr) -> io::Result<Option<Vec<u8>>> {
+        let key = (path.to_owned(), name.to_owned());
+        Ok(self.xattrs.borrow().get(&key).cloned())
+    }
+
+    fn set_xattr(&self, path: &Path, _file: &File, name: &CStr, value: &[u8]) -> io::Result<()> {
+        self.record(
+            path,
+            Call::SetXattr {
+                path: path.to_owned(),
+                name: name.to_owned(),
+                value: value.to_vec(),
+            },
+        )?;
+        let key = (path.to_owned(), name.to_owned());
+        self.xattrs.borrow_mut().insert(key, value.to_vec());
+        Ok(())
+    }
+
+    fn get_flags(&self, path: &Path, _file: &File) -> io::Result<u32> {
+        Ok(self.flags.borrow().get(path).copied().unwrap_or(0))
+    }
+
+    fn set_flags(&self, path: &Path, _file: &File, flags: u32) -> io::Result<()> {
+        self.record(
+            path,
+            Call::SetFlags {
+                path: path.to_owned(),
+                flags,
+            },
+        )?;
+        self.flags.borrow_mut().insert(path.to_owned(), flags);
+        Ok(())
+    }
+}
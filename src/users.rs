@@ -0,0 +1,123 @@
+use std::{
+    error::Error,
+    ffi::{c_char, CStr, CString},
+    fmt, io, mem, ptr,
+};
+
+use crate::config_file::{FileOwner, SpecifierString};
+
+/// A user or group name in the configuration that the system doesn't know, carried in a
+/// `NotFound` error so `--graceful` can tell it apart
+#[derive(Debug, PartialEq, Eq)]
+pub enum UnknownOwner {
+    User(String),
+    Group(String),
+}
+
+impl fmt::Display for UnknownOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::User(name) => write!(f, "unknown user {name}"),
+            Self::Group(name) => write!(f, "unknown group {name}"),
+        }
+    }
+}
+
+impl Error for UnknownOwner {}
+
+/// Call a reentrant NSS lookup, growing the buffer until it fits
+fn nss_lookup<T, R, E: Into<Box<dyn Error + Send + Sync>>>(
+    mut lookup: impl FnMut(*mut T, *mut c_char, usize, *mut *mut T) -> libc::c_int,
+    extract: impl FnOnce(&T) -> R,
+    describe: impl FnOnce() -> E,
+) -> io::Result<R> {
+    let mut buf = vec![0 as c_char; 1024];
+    loop {
+        let mut entry: T = unsafe { mem::zeroed() };
+        let mut result = ptr::null_mut();
+        match lookup(&mut entry, buf.as_mut_ptr(), buf.len(), &mut result) {
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            0 if result.is_null() => {
+                return Err(io::Error::new(io::ErrorKind::NotFound, describe()))
+            }
+            0 => return Ok(extract(&entry)),
+            errno => return Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+}
+
+fn c_name(name: &str) -> io::Result<CString> {
+    CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Name and home directory of a user
+pub fn user_by_uid(uid: libc::uid_t) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    nss_lookup(
+        |entry, buf, len, result| unsafe { libc::getpwuid_r(uid, entry, buf, len, result) },
+        |entry: &libc::passwd| unsafe {
+            (
+                CStr::from_ptr(entry.pw_name).to_bytes().to_vec(),
+                CStr::from_ptr(entry.pw_dir).to_bytes().to_vec(),
+            )
+        },
+        || format!("no user with uid {uid}"),
+    )
+}
+
+pub fn group_name_by_gid(gid: libc::gid_t) -> io::Result<Vec<u8>> {
+    nss_lookup(
+        |entry, buf, len, result| unsafe { libc::getgrgid_r(gid, entry, buf, len, result) },
+        |entry: &libc::group| unsafe { CStr::from_ptr(entry.gr_name).to_bytes().to_vec() },
+        || format!("no group with gid {gid}"),
+    )
+}
+
+pub fn uid_by_name(name: &str) -> io::Result<libc::uid_t> {
+    let c_name = c_name(name)?;
+    nss_lookup(
+        |entry, buf, len, result| unsafe {
+            libc::getpwnam_r(c_name.as_ptr(), entry, buf, len, result)
+        },
+        |entry: &libc::passwd| entry.pw_uid,
+        || UnknownOwner::User(name.to_owned()),
+    )
+}
+
+pub fn gid_by_name(name: &str) -> io::Result<libc::gid_t> {
+    let c_name = c_name(name)?;
+    nss_lookup(
+        |entry, buf, len, result| unsafe {
+            libc::getgrnam_r(c_name.as_ptr(), entry, buf, len, result)
+        },
+        |entry: &libc::group| entry.gr_gid,
+        || UnknownOwner::Group(name.to_owned()),
+    )
+}
+
+fn unexpanded(string: &SpecifierString) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "specifiers in {} are not expanded",
+            string.to_bytes().escape_ascii()
+        ),
+    )
+}
+
+/// Numeric user ID for an owner field
+pub fn resolve_user(owner: &FileOwner) -> io::Result<libc::uid_t> {
+    match owner {
+        FileOwner::Id(id) => Ok(*id),
+        FileOwner::Name(name) => uid_by_name(name),
+        FileOwner::Specifiers(string) => Err(unexpanded(string)),
+    }
+}
+
+/// Numeric group ID for a group field
+pub fn resolve_group(group: &FileOwner) -> io::Result<libc::gid_t> {
+    match group {
+        FileOwner::Id(id) => Ok(*id),
+        FileOwner::Name(name) => gid_by_name(name),
+        FileOwner::Specifiers(string) => Err(unexpanded(string)),
+    }
+}
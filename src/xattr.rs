@@ -0,0 +1,230 @@
+use std::{
+    error::Error,
+    ffi::{CStr, CString},
+    fmt, io,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+use base64::engine::Engine;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Xattr {
+    pub name: CString,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum XattrParseError {
+    MalformedEntry(Box<[u8]>),
+    UnterminatedQuote(Box<[u8]>),
+    InvalidName(Box<[u8]>),
+    InvalidHex(Box<[u8]>),
+    InvalidBase64(Box<[u8]>),
+}
+
+impl fmt::Display for XattrParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (message, bytes) = match self {
+            Self::MalformedEntry(entry) => ("expected name=value for extended attribute", entry),
+            Self::UnterminatedQuote(entry) => ("unterminated quote in extended attribute", entry),
+            Self::InvalidName(name) => ("invalid extended attribute name", name),
+            Self::InvalidHex(value) => ("invalid hex extended attribute value", value),
+            Self::InvalidBase64(value) => ("invalid base64 extended attribute value", value),
+        };
+        write!(f, "{message} \"{}\"", bytes.escape_ascii())
+    }
+}
+
+impl Error for XattrParseError {}
+
+/// Parse an xattr argument like `user.a=1 user.b="two words"` into its attributes. Like setfattr,
+/// values starting with `0x` are hex and values starting with `0s` are base64.
+pub fn parse_xattrs(input: &[u8]) -> Result<Vec<Xattr>, XattrParseError> {
+    split_words(input)?
+        .into_iter()
+        .map(|entry| {
+            let Some(equals) = entry.iter().position(|&ch| ch == b'=') else {
+                Err(XattrParseError::MalformedEntry(entry.clone().into()))?
+            };
+            let (name, value) = (&entry[..equals], &entry[equals + 1..]);
+            if name.is_empty() {
+                Err(XattrParseError::InvalidName(name.into()))?
+            }
+            Ok(Xattr {
+                // Words never contain NUL, the parser rejects it in arguments
+                name: CString::new(name).map_err(|_| XattrParseError::InvalidName(name.into()))?,
+                value: decode_value(value)?,
+            })
+        })
+        .collect()
+}
+
+/// Split on whitespace, keeping whitespace within double quotes and dropping the quotes
+fn split_words(input: &[u8]) -> Result<Vec<Vec<u8>>, XattrParseError> {
+    let mut words = Vec::new();
+    let mut word = None::<Vec<u8>>;
+    let mut quoted = false;
+    for &ch in input {
+        match ch {
+            b'"' => {
+                quoted = !quoted;
+                word.get_or_insert_with(Vec::new);
+            }
+            b' ' | b'\t' if !quoted => words.extend(word.take()),
+            _ => word.get_or_insert_with(Vec::new).push(ch),
+        }
+    }
+    if quoted {
+        Err(XattrParseError::UnterminatedQuote(input.into()))?
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+fn decode_value(value: &[u8]) -> Result<Vec<u8>, XattrParseError> {
+    match value {
+        [b'0', b'x' | b'X', hex @ ..] => {
+            let invalid = || XattrParseError::InvalidHex(value.into());
+            if hex.len() % 2 != 0 {
+                Err(invalid())?
+            }
+            hex.chunks(2)
+                .map(|pair| {
+                    let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+                    u8::from_str_radix(pair, 16).map_err(|_| invalid())
+                })
+                .collect()
+        }
+        [b'0', b's' | b'S', encoded @ ..] => base64::prelude::BASE64_STANDARD
+            .decode(encoded)
+            .map_err(|_| XattrParseError::InvalidBase64(value.into())),
+        _ => Ok(value.to_vec()),
+    }
+}
+
+/// Set an extended attribute of `path`, following a symlink there, so that a path from
+/// `syscalls::fd_path` reaches the file it was opened for
+#[cfg(target_os = "linux")]
+pub fn set_xattr(path: &Path, name: &CStr, value: &[u8]) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let result = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_xattr(_path: &Path, _name: &CStr, _value: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "extended attributes are only supported on Linux",
+    ))
+}
+
+/// The value of an extended attribute of `path`, following a symlink there like `set_xattr`, or
+/// `None` if it doesn't have one by that name
+#[cfg(target_os = "linux")]
+pub fn get_xattr(path: &Path, name: &CStr) -> io::Result<Option<Vec<u8>>> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    loop {
+        let size = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ENODATA) {
+                return Ok(None);
+            }
+            return Err(e);
+        }
+        let mut value = vec![0; size as usize];
+        let size = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+            )
+        };
+        if size < 0 {
+            let e = io::Error::last_os_error();
+            // It grew between the calls, so ask for the size again
+            if e.raw_os_error() == Some(libc::ERANGE) {
+                continue;
+            }
+            return Err(e);
+        }
+        value.truncate(size as usize);
+        return Ok(Some(value));
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn get_xattr(_path: &Path, _name: &CStr) -> io::Result<Option<Vec<u8>>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "extended attributes are only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::xattr::{parse_xattrs, Xattr, XattrParseError};
+
+    #[test]
+    fn test_parse_xattrs() {
+        assert_eq!(
+            parse_xattrs(b"user.a=1  user.b=\"two words\" user.c="),
+            Ok(vec![
+                Xattr {
+                    name: c"user.a".into(),
+                    value: b"1".to_vec(),
+                },
+                Xattr {
+                    name: c"user.b".into(),
+                    value: b"two words".to_vec(),
+                },
+                Xattr {
+                    name: c"user.c".into(),
+                    value: Vec::new(),
+                },
+            ])
+        );
+        assert_eq!(
+            parse_xattrs(b"user.a"),
+            Err(XattrParseError::MalformedEntry(b"user.a".as_slice().into()))
+        );
+        assert_eq!(
+            parse_xattrs(b"user.a=\"open"),
+            Err(XattrParseError::UnterminatedQuote(
+                b"user.a=\"open".as_slice().into()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_encoded_values() {
+        let hex = parse_xattrs(b"user.k=0x0102").unwrap();
+        let base64 = parse_xattrs(b"user.k=0sAQI=").unwrap();
+        assert_eq!(hex[0].value, [1, 2]);
+        assert_eq!(hex, base64);
+        assert_eq!(
+            parse_xattrs(b"user.k=0x012"),
+            Err(XattrParseError::InvalidHex(b"0x012".as_slice().into()))
+        );
+        assert_eq!(
+            parse_xattrs(b"user.k=0s!!"),
+            Err(XattrParseError::InvalidBase64(b"0s!!".as_slice().into()))
+        );
+    }
+}
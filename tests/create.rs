@@ -0,0 +1,94 @@
+//! Apply whole configuration files to a temporary root and check the tree they leave behind
+
+use std::{fs, os::unix::fs::PermissionsExt, path::Path};
+
+use mini_tmpfiles::{
+    apply::{create, Context},
+    config_file::{FileOwner, Line},
+    parser::{parse_line, FileSpan},
+    specifier::SpecifierSource,
+};
+use tempfile::TempDir;
+
+/// Write `config` to a file outside the root, then parse and create every line below the root
+fn apply_config(root: &Path, config: &str) {
+    let config_dir = TempDir::new().unwrap();
+    let config_path = config_dir.path().join("test.conf");
+    fs::write(&config_path, config).unwrap();
+    let contents = fs::read(&config_path).unwrap();
+    let lines = FileSpan::from_slice(&contents, &config_path)
+        .lines()
+        .map(|(_, line)| line)
+        .filter(|line| line.is_data_line())
+        .map(|line| parse_line(line).unwrap())
+        .collect::<Vec<_>>();
+    create(
+        &lines,
+        &Context::new(root.to_owned(), SpecifierSource::Target),
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_symlinks() {
+    let root = TempDir::new().unwrap();
+    let config = "# Links are created with their parents, and the target is written as is\n\
+                  \t# Comments may be indented\n\
+                  L /etc/resolv.conf - - - - /run/systemd/resolve/stub-resolv.conf\n\
+                  L /var/run - - - - ../run\n";
+    apply_config(root.path(), config);
+    // Applying again leaves the existing links alone
+    apply_config(root.path(), config);
+
+    let link = root.path().join("etc/resolv.conf");
+    assert!(fs::symlink_metadata(&link).unwrap().is_symlink());
+    assert_eq!(
+        fs::read_link(&link).unwrap(),
+        Path::new("/run/systemd/resolve/stub-resolv.conf")
+    );
+    assert_eq!(
+        fs::read_link(root.path().join("var/run")).unwrap(),
+        Path::new("../run")
+    );
+}
+
+#[test]
+fn test_generated_lines() {
+    let root = TempDir::new().unwrap();
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let lines = [
+        Line::directory("/run/x").mode(0o750),
+        Line::file("/run/x/%literal")
+            .mode(0o600)
+            .owner(FileOwner::Id(uid))
+            .group(FileOwner::Id(gid))
+            .argument("contents"),
+        Line::symlink("/run/link", "x"),
+    ];
+    assert_eq!(
+        lines[1].to_string(),
+        format!("f /run/x/%%literal 0600 {uid} {gid} - contents")
+    );
+    create(
+        &lines,
+        &Context::new(root.path().to_owned(), SpecifierSource::Target),
+    )
+    .unwrap();
+
+    let mode = |path: &str| {
+        fs::metadata(root.path().join(path))
+            .unwrap()
+            .permissions()
+            .mode()
+    };
+    assert_eq!(mode("run/x") & 0o7777, 0o750);
+    assert_eq!(mode("run/x/%literal") & 0o7777, 0o600);
+    assert_eq!(
+        fs::read(root.path().join("run/x/%literal")).unwrap(),
+        b"contents"
+    );
+    assert_eq!(
+        fs::read_link(root.path().join("run/link")).unwrap(),
+        Path::new("x")
+    );
+}